@@ -2,87 +2,593 @@
 layout(location = 0) out vec4 o_Target;
 layout(location = 1) in vec3 world_space_position;
 layout(location = 2) flat in int lod;
+layout(location = 3) in float morph;
 
-layout(set = 2, binding = 0) uniform texture2D MapMaterial_forest;
-layout(set = 2, binding = 1) uniform sampler MapMaterial_forest_sampler;
-layout(set = 2, binding = 2) uniform texture2D MapMaterial_sand;
-layout(set = 2, binding = 3) uniform sampler MapMaterial_sand_sampler;
+layout(set = 2, binding = 0) uniform texture2D MapMaterial_layer_atlas;
+layout(set = 2, binding = 1) uniform sampler MapMaterial_layer_atlas_sampler;
+
+// See `splat::TerrainLayerUniform`. Each rule is (min, max, blend, unused).
+struct TerrainLayer {
+    vec4 height;
+    vec4 slope;
+    vec4 coast_distance;
+    vec4 latitude;
+    // Atlas tile, texture scale, strength, unused
+    vec4 texture;
+};
+
+layout(set = 2, binding = 2) readonly buffer MapMaterial_terrain_layers {
+    TerrainLayer terrain_layers[];
+};
+layout(set = 2, binding = 3) uniform MapMaterial_metres_per_level {
+    float metres_per_level;
+};
 layout(set = 2, binding = 4) uniform utexture2D MapMaterial_heightmap;
 layout(set = 2, binding = 5) uniform sampler MapMaterial_heightmap_sampler;
+layout(set = 2, binding = 6) uniform utexture2D MapMaterial_mipmap;
+layout(set = 2, binding = 7) uniform sampler MapMaterial_mipmap_sampler;
+layout(set = 2, binding = 8) uniform MapMaterial_lod_colours {
+    uint lod_colours;
+};
+layout(set = 2, binding = 9) uniform MapMaterial_clipmap_half_extent {
+    float clipmap_half_extent;
+};
+layout(set = 2, binding = 26) uniform MapMaterial_clipmap_lod_levels {
+    int clipmap_lod_levels;
+};
+layout(set = 2, binding = 25) uniform MapMaterial_wireframe {
+    uint wireframe;
+};
+layout(set = 2, binding = 10) uniform MapMaterial_sun_direction {
+    vec3 sun_direction;
+};
+layout(set = 2, binding = 11) uniform MapMaterial_ambient_strength {
+    float ambient_strength;
+};
+layout(set = 2, binding = 12) uniform MapMaterial_sun_colour {
+    vec4 sun_colour;
+};
+layout(set = 2, binding = 13) uniform utexture2D MapMaterial_water;
+layout(set = 2, binding = 14) uniform sampler MapMaterial_water_sampler;
+layout(set = 2, binding = 15) uniform MapMaterial_sea_level {
+    float sea_level;
+};
+// Latitude of the first row of texels, and change in latitude per row
+layout(set = 2, binding = 16) uniform MapMaterial_latitude {
+    vec2 latitude_mapping;
+};
+layout(set = 2, binding = 17) uniform MapMaterial_fog_colour {
+    vec4 fog_colour;
+};
+layout(set = 2, binding = 18) uniform MapMaterial_fog_distance {
+    float fog_distance;
+};
+// How far the terrain has faded into the paper map, from 0 to 1
+layout(set = 2, binding = 19) uniform MapMaterial_paper_map {
+    float paper_map;
+};
+// See `overlay::MapMode::shader_id`
+layout(set = 2, binding = 20) uniform MapMaterial_map_mode {
+    uint map_mode;
+};
+// Id of the realm owning each pixel - see `overlay::Ownership`
+layout(set = 2, binding = 21) uniform utexture2D MapMaterial_overlay;
+layout(set = 2, binding = 22) uniform sampler MapMaterial_overlay_sampler;
+// Heightmap texels per overlay pixel
+layout(set = 2, binding = 23) uniform MapMaterial_overlay_scale {
+    float overlay_scale;
+};
+
+// See `overlay::RealmUniform`
+struct Realm {
+    vec4 colour;
+    // Relation, wealth, unused, unused
+    vec4 values;
+};
+
+layout(set = 2, binding = 24) readonly buffer MapMaterial_realms {
+    Realm realms[];
+};
 
 layout(set = 1, binding = 0) uniform Transform {
     mat4 Model;
 };
+layout(set = 1, binding = 1) uniform TimeNode_time {
+    float time;
+};
+layout(set = 1, binding = 2) uniform ViewNode_camera_position {
+    vec3 camera_position;
+};
+
+const float Y_SCALE = 0.2;
+const float XYZ_SCALE = 1.0 / 8.0;
+const uint NORMAL_BITS = 8;
+const float MAX_NORMAL_LEVEL = float((1 << NORMAL_BITS) - 1);
+// Metres of depth per level of the water texture (see `water::DEPTH_SCALE`)
+const float DEPTH_SCALE = 20.0;
+// Higher values narrow the band where triplanar projections blend into each other
+const float TRIPLANAR_SHARPNESS = 4.0;
+// How far from the coast, in heightmap texels, shore foam reaches
+const float FOAM_WIDTH = 2.5;
+
+// Density of the fog at sea level, and how quickly it thins out with height (both per world unit)
+const float FOG_DENSITY = 0.004;
+const float FOG_FALLOFF = 0.15;
+const float SUN_GLOW = 0.35;
+
+const vec3 PAPER_LOWLAND = vec3(0.87, 0.78, 0.58);
+const vec3 PAPER_HIGHLAND = vec3(0.62, 0.5, 0.33);
+const vec3 PAPER_SEA = vec3(0.6, 0.68, 0.62);
+const vec3 INK = vec3(0.2, 0.14, 0.08);
+// Direction that the paper map's hill shading is lit from (the north west, as is traditional)
+const vec3 PAPER_LIGHT = vec3(-0.6, 0.6, -0.6);
+
+const uint MAP_MODE_TERRAIN = 0;
+const uint MAP_MODE_DIPLOMATIC = 2;
+const uint MAP_MODE_ECONOMIC = 3;
+// How much realms' colours show over the map
+const float OVERLAY_OPACITY = 0.55;
+// Half the width of borders, in screen pixels (they are drawn on both sides of the edge)
+const float BORDER_WIDTH = 1.0;
+const vec3 BORDER_COLOUR = vec3(0.1, 0.07, 0.05);
+const vec3 HOSTILE = vec3(0.8, 0.1, 0.1);
+const vec3 NEUTRAL = vec3(0.7, 0.7, 0.65);
+const vec3 FRIENDLY = vec3(0.1, 0.7, 0.2);
+const vec3 POOR = vec3(0.25, 0.2, 0.15);
+const vec3 RICH = vec3(1.0, 0.8, 0.2);
+
+const vec3 SHALLOW_WATER = vec3(0.05, 0.45, 0.5);
+const vec3 DEEP_WATER = vec3(0.0, 0.04, 0.15);
+const float DEEP_WATER_DEPTH = 3000.0;
+const vec3 FOAM = vec3(0.9, 0.95, 1.0);
+// Heightmap texels over which the map fades out past its edges
+const float MAP_EDGE_FADE = 64.0;
+
+// The map doesn't wrap around - beyond its edges, the edge texels are stretched out
+ivec2 clamp_to_map(ivec2 heightmap_coord) {
+    ivec2 size = textureSize(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), 0);
+    return clamp(heightmap_coord, ivec2(0), size - 1);
+}
+
+// How far a point is beyond the edge of the map, in heightmap texels
+float distance_off_map(vec2 pos) {
+    vec2 size = vec2(textureSize(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), 0));
+    vec2 outside = max(-pos, pos - size);
+    return max(max(outside.x, outside.y), 0.0);
+}
+
+vec3 sample_raw_normal(ivec2 heightmap_coord) {
+    heightmap_coord = clamp_to_map(heightmap_coord);
+    uvec4 packed = texelFetch(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), heightmap_coord, 0);
+    vec2 xz = vec2(packed.ga) / MAX_NORMAL_LEVEL * 2.0 - 1.0;
+    // The normal always points upwards, so y is the positive solution
+    return vec3(xz.x, sqrt(max(0.0, 1.0 - dot(xz, xz))), xz.y);
+}
+
+vec3 sample_billinear_normal(vec2 dest_coord) {
+    vec2 fxy = fract(dest_coord);
+    ivec2 ipos = ivec2(floor(dest_coord));
+
+    vec3 centre = sample_raw_normal(ipos);
+    vec3 bottom = sample_raw_normal(ivec2(ipos.x, ipos.y + 1));
+    vec3 right = sample_raw_normal(ivec2(ipos.x + 1, ipos.y));
+    vec3 bottom_right = sample_raw_normal(ivec2(ipos.x + 1, ipos.y + 1));
 
-const uint LIGHT_BITS = 8;
-const float MAX_LIGHT_LEVEL = float((1 << LIGHT_BITS) - 1);
-const float SQRT_2 = sqrt(2.0);
+    return normalize(mix(mix(centre, right, fxy.x), mix(bottom, bottom_right, fxy.x), fxy.y));
+}
 
-struct HeightmapTexel {
-    bool is_water;
-    float brightness;
+struct WaterTexel {
+    // Metres below sea level
+    float depth;
+    // Heightmap texels to the coast
+    float coast_distance;
 };
 
-HeightmapTexel sample_heightmap(ivec2 pos) {
-    uvec4 packed = texelFetch(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), pos, 0);
-    return HeightmapTexel(packed.b == 1, float(packed.g) / MAX_LIGHT_LEVEL);
+WaterTexel sample_raw_water(ivec2 heightmap_coord) {
+    heightmap_coord = clamp_to_map(heightmap_coord);
+    uvec4 packed = texelFetch(usampler2D(MapMaterial_water, MapMaterial_water_sampler), heightmap_coord, 0);
+    return WaterTexel(float(packed.r) * DEPTH_SCALE, float(packed.g));
 }
 
-float w(HeightmapTexel t) {
-    return float(t.is_water);
+WaterTexel sample_billinear_water(vec2 dest_coord) {
+    vec2 fxy = fract(dest_coord);
+    ivec2 ipos = ivec2(floor(dest_coord));
+
+    WaterTexel centre = sample_raw_water(ipos);
+    WaterTexel bottom = sample_raw_water(ivec2(ipos.x, ipos.y + 1));
+    WaterTexel right = sample_raw_water(ivec2(ipos.x + 1, ipos.y));
+    WaterTexel bottom_right = sample_raw_water(ivec2(ipos.x + 1, ipos.y + 1));
+
+    return WaterTexel(
+        mix(mix(centre.depth, right.depth, fxy.x), mix(bottom.depth, bottom_right.depth, fxy.x), fxy.y),
+        mix(mix(centre.coast_distance, right.coast_distance, fxy.x), mix(bottom.coast_distance, bottom_right.coast_distance, fxy.x), fxy.y)
+    );
 }
 
-vec4 sample_raw_terrain_color(ivec2 heightmap_coord, vec2 texture_coord) {
+float sample_raw_is_water(ivec2 heightmap_coord) {
+    heightmap_coord = clamp_to_map(heightmap_coord);
     uvec4 packed = texelFetch(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), heightmap_coord, 0);
-    uint terrain = packed.b;
-    float brightness = float(packed.g) / MAX_LIGHT_LEVEL;
+    return float(packed.b == 1);
+}
 
-    vec4 color;
+// How much of the given point is covered by water, from 0 to 1
+float sample_billinear_is_water(vec2 dest_coord) {
+    vec2 fxy = fract(dest_coord);
+    ivec2 ipos = ivec2(floor(dest_coord));
 
-    if (terrain == 0) {
-        color = texture(sampler2D(MapMaterial_forest, MapMaterial_forest_sampler), texture_coord * 0.005);
-    } else if (terrain == 1) {
-        color = vec4(0.0, 0.0, 1.0, 1.0);
-    } else if (terrain == 2) {
-        color = vec4(1.0, 1.0, 0.0, 1.0);
-    }
+    float centre = sample_raw_is_water(ipos);
+    float bottom = sample_raw_is_water(ivec2(ipos.x, ipos.y + 1));
+    float right = sample_raw_is_water(ivec2(ipos.x + 1, ipos.y));
+    float bottom_right = sample_raw_is_water(ivec2(ipos.x + 1, ipos.y + 1));
 
-    color.rgb *= brightness;
-    return color;
+    return mix(mix(centre, right, fxy.x), mix(bottom, bottom_right, fxy.x), fxy.y);
 }
 
-vec4 f(vec2 dest, vec2 ipos) {
-    return sample_raw_terrain_color(ivec2(ipos), dest);
+// Height of the surface above sea level, in metres
+float sample_raw_height(ivec2 heightmap_coord) {
+    heightmap_coord = clamp_to_map(heightmap_coord);
+    uvec4 packed = texelFetch(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), heightmap_coord, 0);
+    return (float(packed.r) - sea_level) * metres_per_level;
 }
 
-vec4 sample_billinear_terrain_color(vec2 dest_coord) {
+float sample_billinear_height(vec2 dest_coord) {
     vec2 fxy = fract(dest_coord);
     ivec2 ipos = ivec2(floor(dest_coord));
 
-    vec4 centre = sample_raw_terrain_color(ipos, dest_coord);
-    vec4 bottom = sample_raw_terrain_color(ivec2(ipos.x, ipos.y + 1), dest_coord);
-    vec4 right = sample_raw_terrain_color(ivec2(ipos.x + 1, ipos.y), dest_coord);
-    vec4 bottom_right = sample_raw_terrain_color(ivec2(ipos.x + 1, ipos.y + 1), dest_coord);
+    float centre = sample_raw_height(ipos);
+    float bottom = sample_raw_height(ivec2(ipos.x, ipos.y + 1));
+    float right = sample_raw_height(ivec2(ipos.x + 1, ipos.y));
+    float bottom_right = sample_raw_height(ivec2(ipos.x + 1, ipos.y + 1));
 
     return mix(mix(centre, right, fxy.x), mix(bottom, bottom_right, fxy.x), fxy.y);
 }
 
+float fetch_mipmap_height(ivec2 pos) {
+    ivec2 size = textureSize(usampler2D(MapMaterial_mipmap, MapMaterial_mipmap_sampler), 0);
+    pos = clamp(pos, ivec2(0), size - 1);
+    float level = float(texelFetch(usampler2D(MapMaterial_mipmap, MapMaterial_mipmap_sampler), pos, 0).r);
+    return (level - sea_level) * metres_per_level;
+}
+
+// Bilinearly samples the (half resolution) mipmap at a position in full resolution texels, in metres
+float sample_mipmap_height(vec2 pos) {
+    vec2 mip_pos = pos / 2.0;
+    ivec2 ipos = ivec2(floor(mip_pos));
+    vec2 fxy = fract(mip_pos);
+
+    float top = mix(fetch_mipmap_height(ipos), fetch_mipmap_height(ipos + ivec2(1, 0)), fxy.x);
+    float bottom = mix(fetch_mipmap_height(ipos + ivec2(0, 1)), fetch_mipmap_height(ipos + ivec2(1, 1)), fxy.x);
+    return mix(top, bottom, fxy.y);
+}
+
+// How well a value meets a layer's rule, from 0 to 1
+float rule_weight(vec4 rule, float value) {
+    return smoothstep(rule.x - rule.z, rule.x + rule.z, value) * (1.0 - smoothstep(rule.y - rule.z, rule.y + rule.z, value));
+}
+
+vec4 sample_layer_planar(TerrainLayer layer, vec2 texture_coord) {
+    float tiles = float(terrain_layers.length());
+    float tile_height = float(textureSize(sampler2D(MapMaterial_layer_atlas, MapMaterial_layer_atlas_sampler), 0).y) / tiles;
+    vec2 uv = fract(texture_coord * layer.texture.y);
+
+    // Tiles are stacked vertically, so rows are kept half a texel inside the tile, or filtering would
+    // blend in the neighbouring tiles at its top and bottom edges. Each tile is the full width of the
+    // atlas, so columns can wrap around with the sampler.
+    float row = clamp(uv.y * tile_height, 0.5, tile_height - 0.5);
+    uv.y = (row + layer.texture.x * tile_height) / (tile_height * tiles);
+    return texture(sampler2D(MapMaterial_layer_atlas, MapMaterial_layer_atlas_sampler), uv);
+}
+
+// Samples a layer projected along each axis, blended by the given triplanar weights, so that steep
+// terrain isn't stretched by a top down projection. Position is in heightmap texels.
+vec4 sample_layer(TerrainLayer layer, vec3 position, vec3 triplanar) {
+    vec4 color = sample_layer_planar(layer, position.xz) * triplanar.y;
+
+    // The sides are only sampled where they show, since most of the map is fairly flat
+    if (triplanar.x > 0.01) {
+        color += sample_layer_planar(layer, position.zy) * triplanar.x;
+    }
+    if (triplanar.z > 0.01) {
+        color += sample_layer_planar(layer, position.xy) * triplanar.z;
+    }
+
+    return color;
+}
+
+// How much each axis' projection contributes to a surface with the given normal
+vec3 triplanar_weights(vec3 normal) {
+    vec3 weights = pow(abs(normal), vec3(TRIPLANAR_SHARPNESS));
+    return weights / (weights.x + weights.y + weights.z);
+}
+
+// Blends the terrain layers together by how well the terrain here meets each of their rules
+vec4 splat_terrain_color(vec2 pos, vec3 normal) {
+    float height = sample_billinear_height(pos);
+    // Position in heightmap texels, with height scaled the same way as the mesh and its normals
+    vec3 position = vec3(pos.x, world_space_position.y * Y_SCALE, pos.y);
+    vec3 triplanar = triplanar_weights(normal);
+    float slope = 1.0 - normal.y;
+    float coast_distance = sample_billinear_water(pos).coast_distance;
+    float latitude = latitude_mapping.x + pos.y * latitude_mapping.y;
+
+    vec4 color = vec4(0.0);
+    float total_weight = 0.0;
+
+    for (int i = 0; i < terrain_layers.length(); i++) {
+        TerrainLayer layer = terrain_layers[i];
+        float weight = layer.texture.z
+            * rule_weight(layer.height, height)
+            * rule_weight(layer.slope, slope)
+            * rule_weight(layer.coast_distance, coast_distance)
+            * rule_weight(layer.latitude, latitude);
+
+        if (weight > 0.001) {
+            color += sample_layer(layer, position, triplanar) * weight;
+            total_weight += weight;
+        }
+    }
+
+    // Fall back to the first layer where no layer's rules are met
+    if (total_weight < 0.001) {
+        return sample_layer(terrain_layers[0], position, triplanar);
+    }
+
+    return color / total_weight;
+}
+
+// Gradient of the height of a sine wave travelling across the water
+vec2 wave_gradient(vec2 pos, vec2 direction, float wavelength, float amplitude, float speed) {
+    float k = 2.0 * 3.14159265 / wavelength;
+    float phase = k * (dot(normalize(direction), pos) - speed * time);
+    return normalize(direction) * amplitude * k * cos(phase);
+}
+
+vec3 water_normal(vec2 pos) {
+    vec2 gradient = wave_gradient(pos, vec2(1.0, 0.3), 9.0, 0.08, 1.2)
+        + wave_gradient(pos, vec2(-0.4, 1.0), 5.3, 0.05, 0.9)
+        + wave_gradient(pos, vec2(0.7, -0.8), 3.1, 0.03, 0.7)
+        + wave_gradient(pos, vec2(-1.0, -0.2), 1.7, 0.015, 0.5);
+    return normalize(vec3(-gradient.x, 1.0, -gradient.y));
+}
+
+vec3 shade_water(vec2 pos, vec3 sea_floor) {
+    WaterTexel water = sample_billinear_water(pos);
+    vec3 normal = water_normal(pos);
+    vec3 sun = normalize(sun_direction);
+
+    // Deeper water is darker, and the sea floor shows through in the shallows
+    float deepness = pow(clamp(water.depth / DEEP_WATER_DEPTH, 0.0, 1.0), 0.35);
+    vec3 color = mix(SHALLOW_WATER, DEEP_WATER, deepness);
+    color = mix(sea_floor, color, smoothstep(0.0, 0.8, water.coast_distance));
+
+    float diffuse = max(dot(normal, sun), 0.0);
+    color *= min(vec3(1.0), sun_colour.rgb * diffuse + ambient_strength);
+
+    // Foam laps at the shore
+    float foam = 1.0 - smoothstep(0.0, FOAM_WIDTH, water.coast_distance);
+    foam *= 0.6 + 0.4 * sin(water.coast_distance * 4.0 - time * 1.5);
+    color = mix(color, FOAM * min(vec3(1.0), sun_colour.rgb * diffuse + ambient_strength), clamp(foam, 0.0, 1.0));
+
+    // Sun glint
+    vec3 view = normalize(camera_position - vec3(pos.x * XYZ_SCALE, 0.0, pos.y * XYZ_SCALE));
+    vec3 half_vector = normalize(sun + view);
+    float specular = pow(max(dot(normal, half_vector), 0.0), 120.0) * step(0.0, sun.y);
+    color += sun_colour.rgb * specular * 0.8;
+
+    return color;
+}
+
+// Cheap hash based noise, for the grain of the paper
+float paper_grain(vec2 pos) {
+    return fract(sin(dot(floor(pos), vec2(12.9898, 78.233))) * 43758.5453);
+}
+
+// The strategic map: parchment tinted by height and hill shaded from the mipmap, with inked coasts
+vec3 paper_map_color(vec2 pos, float is_water) {
+    float height = sample_mipmap_height(pos);
+    vec2 gradient = vec2(
+        sample_mipmap_height(pos + vec2(2.0, 0.0)) - sample_mipmap_height(pos - vec2(2.0, 0.0)),
+        sample_mipmap_height(pos + vec2(0.0, 2.0)) - sample_mipmap_height(pos - vec2(0.0, 2.0))
+    ) * 0.25 * metres_per_level * Y_SCALE;
+    vec3 normal = normalize(vec3(-gradient.x, 1.0, -gradient.y));
+    float shade = mix(0.75, 1.1, max(dot(normal, normalize(PAPER_LIGHT)), 0.0));
+
+    vec3 land = mix(PAPER_LOWLAND, PAPER_HIGHLAND, smoothstep(0.0, 2500.0, height)) * shade;
+
+    // The sea darkens towards the coast, as on old charts
+    float coast_distance = sample_billinear_water(pos).coast_distance;
+    vec3 sea = PAPER_SEA * mix(0.85, 1.0, smoothstep(0.0, 8.0, coast_distance));
+
+    vec3 color = mix(land, sea, smoothstep(0.4, 0.6, is_water));
+
+    // Ink the coastline where the water fraction crosses a half, with a constant width on screen
+    float from_coast = abs(is_water - 0.5) / max(fwidth(is_water), 0.0001);
+    color = mix(INK, color, smoothstep(0.5, 1.5, from_coast));
+
+    return color * mix(0.94, 1.0, paper_grain(pos * 4.0));
+}
+
+// Colour of the atmosphere around the sun, which tints the sky and the fog. This must match
+// `atmosphere_glow` in sky.frag.
+vec3 atmosphere_glow(vec3 view_direction) {
+    float sun_amount = max(dot(view_direction, normalize(sun_direction)), 0.0);
+    return sun_colour.rgb * pow(sun_amount, 8.0) * SUN_GLOW;
+}
+
+// How much fog there is along a ray from the camera, with the fog thinning exponentially with height.
+// See https://iquilezles.org/articles/fog/
+float height_fog(vec3 origin, vec3 direction, float distance) {
+    float optical_depth;
+
+    if (abs(direction.y) < 0.0001) {
+        optical_depth = FOG_DENSITY * exp(-origin.y * FOG_FALLOFF) * distance;
+    } else {
+        optical_depth = FOG_DENSITY / FOG_FALLOFF * exp(-origin.y * FOG_FALLOFF)
+            * (1.0 - exp(-distance * direction.y * FOG_FALLOFF)) / direction.y;
+    }
+
+    return 1.0 - exp(-max(optical_depth, 0.0));
+}
+
+vec3 apply_fog(vec3 color, vec2 pos) {
+    vec3 world = vec3(pos.x, world_space_position.y * Y_SCALE, pos.y) * XYZ_SCALE;
+    vec3 ray = world - camera_position;
+    float distance = length(ray);
+    vec3 view_direction = ray / distance;
+
+    float fog = height_fog(camera_position, view_direction, distance);
+
+    // Thicken the fog into the sky towards the edge of the clipmap, so that it is never seen
+    vec2 from_centre = abs(world.xz - Model[3].xz);
+    fog = max(fog, smoothstep(fog_distance * 0.7, fog_distance, max(from_centre.x, from_centre.y)));
+
+    return mix(color, fog_colour.rgb + atmosphere_glow(view_direction), fog);
+}
+
+uint fetch_owner(ivec2 pixel) {
+    ivec2 size = textureSize(usampler2D(MapMaterial_overlay, MapMaterial_overlay_sampler), 0);
+    pixel = clamp(pixel, ivec2(0), size - 1);
+    return texelFetch(usampler2D(MapMaterial_overlay, MapMaterial_overlay_sampler), pixel, 0).r;
+}
+
+// Colour that a realm is tinted in the current map mode
+vec3 realm_overlay_color(Realm realm) {
+    if (map_mode == MAP_MODE_DIPLOMATIC) {
+        float relation = realm.values.x;
+        return relation < 0.0 ? mix(NEUTRAL, HOSTILE, -relation) : mix(NEUTRAL, FRIENDLY, relation);
+    } else if (map_mode == MAP_MODE_ECONOMIC) {
+        return mix(POOR, RICH, realm.values.y);
+    } else {
+        return realm.colour.rgb;
+    }
+}
+
+// Tints the map by owner in every map mode but the terrain one, and draws borders between owners in
+// those modes and on the paper map
+vec3 apply_overlay(vec3 color, vec2 pos) {
+    vec2 overlay_pos = pos / overlay_scale;
+    ivec2 pixel = ivec2(floor(overlay_pos));
+    vec2 fxy = fract(overlay_pos);
+    uint owner = fetch_owner(pixel);
+
+    // Distance to the nearest side of this overlay pixel which borders another owner
+    float edge_distance = 1.0e6;
+    if (fetch_owner(pixel + ivec2(-1, 0)) != owner) edge_distance = min(edge_distance, fxy.x);
+    if (fetch_owner(pixel + ivec2(1, 0)) != owner) edge_distance = min(edge_distance, 1.0 - fxy.x);
+    if (fetch_owner(pixel + ivec2(0, -1)) != owner) edge_distance = min(edge_distance, fxy.y);
+    if (fetch_owner(pixel + ivec2(0, 1)) != owner) edge_distance = min(edge_distance, 1.0 - fxy.y);
+
+    // Measured in screen pixels, so that borders are the same width at every zoom level and are
+    // anti-aliased
+    float pixels_per_screen_pixel = max(length(fwidth(overlay_pos)), 0.00001);
+    float border = 1.0 - smoothstep(BORDER_WIDTH - 0.75, BORDER_WIDTH + 0.75, edge_distance / pixels_per_screen_pixel);
+
+    if (map_mode != MAP_MODE_TERRAIN && owner != 0 && owner < realms.length()) {
+        Realm realm = realms[owner];
+        color = mix(color, realm_overlay_color(realm), OVERLAY_OPACITY * realm.colour.a);
+    }
+
+    float border_strength = max(float(map_mode != MAP_MODE_TERRAIN), paper_map);
+    return mix(color, BORDER_COLOUR, border * border_strength);
+}
+
+vec4 lod_color(int lod) {
+    if (lod == 0) {
+        return vec4(1.0, 0.0, 0.0, 1.0);
+    } else if (lod == 1) {
+        return vec4(0.0, 1.0, 0.0, 1.0);
+    } else if (lod == 2) {
+        return vec4(0.0, 0.0, 1.0, 1.0);
+    } else if (lod == 3) {
+        return vec4(1.0, 0.0, 1.0, 1.0);
+    } else if (lod == 4) {
+        return vec4(1.0, 0.0, 0.5, 1.0);
+    } else {
+        return vec4(0.5, 0.0, 0.5, 1.0);
+    }
+}
+
+// Distance in pixels to the nearest of a family of parallel lines, one every `period` along a value
+float line_distance(float along, float period) {
+    return abs(fract(along / period + 0.5) - 0.5) * period / fwidth(along);
+}
+
+// How much a fragment is covered by an edge of the clipmap mesh's triangles (see `mesh::Ring`). The
+// mesh is a lattice of vertices, with one every texel in LOD 0 and one every half cell in the
+// others. LOD 0's cells are split along one diagonal, and the other LODs' along both diagonals of
+// each cell, which cross at the centre vertex.
+float wireframe_edge(vec2 pos) {
+    vec2 lattice = pos / (lod == 0 ? 1.0 : float(1 << (lod - 1)));
+    float diagonal_period = lod == 0 ? 1.0 : 2.0;
+
+    float nearest = min(line_distance(lattice.x, 1.0), line_distance(lattice.y, 1.0));
+    nearest = min(nearest, line_distance(lattice.x - lattice.y, diagonal_period));
+    if (lod != 0) {
+        nearest = min(nearest, line_distance(lattice.x + lattice.y, diagonal_period));
+    }
+
+    return 1.0 - smoothstep(0.0, 1.0, nearest);
+}
+
+// Whether the LOD coarser than this fragment's is drawn here too. Each LOD overlaps the next one, as
+// they are snapped to different grids, but only the coarser one is drawn where they overlap, so that
+// they can't fight over depth. By the coarser LOD's inner edge the finer one has been geomorphed to
+// its triangles, so there is no seam.
+bool covered_by_coarser_lod(vec2 pos) {
+    if (lod >= clipmap_lod_levels - 1) {
+        return false;
+    }
+
+    float cell = float(1 << lod);
+    vec2 camera_pos = Model[3].xz / XYZ_SCALE;
+    vec2 coarser_centre = round(camera_pos / (cell * 2.0)) * cell * 2.0;
+    vec2 from_centre = abs(pos - coarser_centre);
+    return max(from_centre.x, from_centre.y) > clipmap_half_extent * cell;
+}
+
 void main() {
-    o_Target = sample_billinear_terrain_color(world_space_position.xz);
-
-//    vec4 lod_color;
-//
-//    if (lod < 2) {
-//        lod_color = color;
-//    } else if (lod == 2) {
-//        lod_color = vec4(0.0, 0.0, 1.0, 1.0);
-//    } else if (lod == 3) {
-//        lod_color = vec4(1.0, 0.0, 1.0, 1.0);
-//    } else if (lod == 4) {
-//        lod_color = vec4(1.0, 0.0, 0.5, 1.0);
-//    } else {
-//        lod_color = vec4(0.5, 0.0, 0.5, 1.0);
-//    }
-//
-//    o_Target = mix(color, lod_color, 0.05);
+    vec2 pos = world_space_position.xz;
+    if (covered_by_coarser_lod(pos)) {
+        discard;
+    }
+
+    float is_water = sample_billinear_is_water(pos);
+    vec4 color = vec4(0.0, 0.0, 0.0, 1.0);
+
+    // The terrain is only drawn until it has completely faded into the paper map
+    if (paper_map < 1.0) {
+        vec3 normal = sample_billinear_normal(pos);
+        color = splat_terrain_color(pos, normal);
+
+        vec3 sea_floor = color.rgb;
+        float diffuse = max(dot(normal, normalize(sun_direction)), 0.0);
+        color.rgb *= min(vec3(1.0), sun_colour.rgb * diffuse + ambient_strength);
+
+        if (is_water > 0.0) {
+            color.rgb = mix(color.rgb, shade_water(pos, sea_floor), is_water);
+        }
+
+        color.rgb = apply_fog(color.rgb, pos);
+    }
+
+    if (paper_map > 0.0) {
+        color.rgb = mix(color.rgb, paper_map_color(pos, is_water), paper_map);
+    }
+
+    color.rgb = apply_overlay(color.rgb, pos);
+
+    // Fade out into the haze past the edge of the map
+    color.rgb = mix(color.rgb, fog_colour.rgb, smoothstep(0.0, MAP_EDGE_FADE, distance_off_map(pos)));
+
+    if (lod_colours != 0) {
+        // Blend into the next LOD's colour as the vertices morph towards it
+        vec4 ring_color = mix(lod_color(lod), lod_color(lod + 1), morph);
+        color = mix(color, ring_color, 0.3);
+    }
+
+    if (wireframe != 0) {
+        color.rgb = mix(color.rgb, vec3(0.0), 0.8 * wireframe_edge(pos));
+    }
+
+    o_Target = color;
 }
@@ -3,6 +3,7 @@
 layout(location = 0) in vec3 Vertex_Position;
 layout(location = 1) out vec3 world_space_position;
 layout(location = 2) flat out int lod;
+layout(location = 3) out float morph;
 
 layout(set = 2, binding = 4) uniform utexture2D MapMaterial_heightmap;
 layout(set = 2, binding = 5) uniform sampler MapMaterial_heightmap_sampler;
@@ -10,6 +11,18 @@ layout(set = 2, binding = 5) uniform sampler MapMaterial_heightmap_sampler;
 layout(set = 2, binding = 6) uniform utexture2D MapMaterial_mipmap;
 layout(set = 2, binding = 7) uniform sampler MapMaterial_mipmap_sampler;
 
+layout(set = 2, binding = 9) uniform MapMaterial_clipmap_half_extent {
+    float clipmap_half_extent;
+};
+// Heights are stored offset so that they are never negative - this is the stored level of sea level
+layout(set = 2, binding = 15) uniform MapMaterial_sea_level {
+    float sea_level;
+};
+// How far the terrain has faded into the flat paper map, from 0 to 1
+layout(set = 2, binding = 19) uniform MapMaterial_paper_map {
+    float paper_map;
+};
+
 layout(set = 0, binding = 0) uniform Camera {
     mat4 ViewProj;
 };
@@ -20,33 +33,100 @@ layout(set = 1, binding = 0) uniform Transform {
 
 const float Y_SCALE = 0.2;
 const float XYZ_SCALE = 1.0 / 8.0;
-const uint HEIGHT_BITS = 8;
-const uint LIGHT_BITS = 8;
-const uint LOD_0_EXTENT = 512 / 2;
 
 vec2 round_to_increment(vec2 value, float increment) {
     return round(value * (1.0 / increment)) * increment;
 }
 
+// The map doesn't wrap around - beyond its edges, the edge texels are stretched out
+float fetch_fullres_height(ivec2 pos) {
+    ivec2 size = textureSize(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), 0);
+    pos = clamp(pos, ivec2(0), size - 1);
+    return float(texelFetch(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), pos, 0).r) - sea_level;
+}
+
+float fetch_mipmap_height(ivec2 pos) {
+    ivec2 size = textureSize(usampler2D(MapMaterial_mipmap, MapMaterial_mipmap_sampler), 0);
+    pos = clamp(pos, ivec2(0), size - 1);
+    return float(texelFetch(usampler2D(MapMaterial_mipmap, MapMaterial_mipmap_sampler), pos, 0).r) - sea_level;
+}
+
+// Bilinearly samples the (half resolution) mipmap at a position in full resolution texels
+float sample_mipmap_height(vec2 pos) {
+    vec2 mip_pos = pos / 2.0;
+    ivec2 ipos = ivec2(floor(mip_pos));
+    vec2 fxy = fract(mip_pos);
+
+    float top = mix(fetch_mipmap_height(ipos), fetch_mipmap_height(ipos + ivec2(1, 0)), fxy.x);
+    float bottom = mix(fetch_mipmap_height(ipos + ivec2(0, 1)), fetch_mipmap_height(ipos + ivec2(1, 1)), fxy.x);
+    return mix(top, bottom, fxy.y);
+}
+
+// Height of the terrain as seen by the given LOD
+float sample_lod_height(int lod, vec2 pos) {
+    if (lod == 0) {
+        return fetch_fullres_height(ivec2(pos));
+    } else {
+        return sample_mipmap_height(pos);
+    }
+}
+
+// Height of the terrain as drawn by the LOD coarser than the given one, so a vertex morphed fully to
+// this height lies exactly on its triangles (see `mesh::Ring`). Its cells are aligned to its grid,
+// and each quarter of a cell is split along the diagonal through the cell's centre.
+float sample_coarser_lod_height(int lod, vec2 pos) {
+    // Vertex spacing of the coarser LOD, which is half of its cell size
+    float spacing = float(1 << lod);
+    vec2 quarter = floor(pos / spacing);
+    vec2 fxy = pos / spacing - quarter;
+
+    float tl = sample_lod_height(lod + 1, quarter * spacing);
+    float tr = sample_lod_height(lod + 1, (quarter + vec2(1.0, 0.0)) * spacing);
+    float bl = sample_lod_height(lod + 1, (quarter + vec2(0.0, 1.0)) * spacing);
+    float br = sample_lod_height(lod + 1, (quarter + vec2(1.0, 1.0)) * spacing);
+
+    // The centre is at the top left of the bottom right quarter and vice versa, which are split
+    // along the leading diagonal, while the other two quarters are split along the other one
+    vec2 odd = mod(quarter, 2.0);
+    if (odd.x == odd.y) {
+        if (fxy.x >= fxy.y) {
+            return tl + (tr - tl) * fxy.x + (br - tr) * fxy.y;
+        } else {
+            return tl + (bl - tl) * fxy.y + (br - bl) * fxy.x;
+        }
+    } else if (fxy.x + fxy.y <= 1.0) {
+        return tl + (tr - tl) * fxy.x + (bl - tl) * fxy.y;
+    } else {
+        return br + (bl - br) * (1.0 - fxy.x) + (tr - br) * (1.0 - fxy.y);
+    }
+}
+
 void main() {
     lod = int(Vertex_Position.y);
     float grid_size = float(1 << lod);
 
+    // Only the offset is snapped, so that vertices between grid lines (i.e the midpoints of LODs
+    // above 0) keep their place
     vec3 camera_pos = Model[3].xyz / XYZ_SCALE;
-    world_space_position.xz = round_to_increment(Vertex_Position.xz + camera_pos.xz, grid_size);
+    world_space_position.xz = Vertex_Position.xz + round_to_increment(camera_pos.xz, grid_size);
 
-    float height;
+    // Blend towards the coarser LOD over the last quarter of the cells before this LOD's border with
+    // it. The morph is complete one cell before the border, since that is the closest that the
+    // coarser LOD's inner edge can be once both LODs are snapped to their grids.
+    float morph_cells = clipmap_half_extent / 4.0;
+    vec2 camera_distance = abs(world_space_position.xz - camera_pos.xz) / grid_size;
+    float lod_distance = max(camera_distance.x, camera_distance.y);
+    morph = clamp((lod_distance - (clipmap_half_extent - 1.0 - morph_cells)) / morph_cells, 0.0, 1.0);
 
-    if (lod == 0) {
-        uint packed = texelFetch(usampler2D(MapMaterial_heightmap, MapMaterial_heightmap_sampler), ivec2(world_space_position.xz), 0).r;
-        float mipHeight = float(texelFetch(usampler2D(MapMaterial_mipmap, MapMaterial_mipmap_sampler), ivec2(world_space_position.xz) / 2, 0).r);
-        float fullresHeight = float((packed >> LIGHT_BITS) & uint((1 << HEIGHT_BITS) - 1));
-        vec2 normalizedPos = ivec2(world_space_position.xz) / LOD_0_EXTENT;
-        height = mix(fullresHeight, mipHeight, min(1.0, (normalizedPos.x + normalizedPos.y) / 2));
-    } else {
-        height = float(texelFetch(usampler2D(MapMaterial_mipmap, MapMaterial_mipmap_sampler), ivec2(world_space_position.xz) / 2, 0).r);
+    float height = sample_lod_height(lod, world_space_position.xz);
+
+    if (morph > 0.0) {
+        height = mix(height, sample_coarser_lod_height(lod, world_space_position.xz), morph);
     }
 
+    // The paper map is flat
+    height *= 1.0 - paper_map;
+
     vec2 transformed_pos = world_space_position.xz * XYZ_SCALE;
     float y = height * Y_SCALE * XYZ_SCALE;
     world_space_position.y = height;
@@ -0,0 +1,40 @@
+#version 450
+layout(location = 0) out vec4 o_Target;
+layout(location = 1) in vec3 direction;
+
+layout(set = 2, binding = 0) uniform SkyMaterial_sun_direction {
+    vec3 sun_direction;
+};
+layout(set = 2, binding = 1) uniform SkyMaterial_sun_colour {
+    vec4 sun_colour;
+};
+layout(set = 2, binding = 2) uniform SkyMaterial_horizon_colour {
+    vec4 horizon_colour;
+};
+layout(set = 2, binding = 3) uniform SkyMaterial_zenith_colour {
+    vec4 zenith_colour;
+};
+
+const float SUN_GLOW = 0.35;
+// Cosine of the angular radius of the sun's disk
+const float SUN_DISK = 0.9997;
+
+// Colour of the atmosphere around the sun, which tints the sky and the map's fog. This must match
+// `atmosphere_glow` in map.frag.
+vec3 atmosphere_glow(vec3 view_direction) {
+    float sun_amount = max(dot(view_direction, normalize(sun_direction)), 0.0);
+    return sun_colour.rgb * pow(sun_amount, 8.0) * SUN_GLOW;
+}
+
+void main() {
+    vec3 view_direction = normalize(direction);
+
+    // Below the horizon is the same colour as the horizon, so that it blends into the map's fog
+    vec3 color = mix(horizon_colour.rgb, zenith_colour.rgb, smoothstep(0.0, 0.6, view_direction.y));
+    color += atmosphere_glow(view_direction);
+
+    float sun_amount = dot(view_direction, normalize(sun_direction));
+    color += sun_colour.rgb * smoothstep(SUN_DISK - 0.0002, SUN_DISK, sun_amount);
+
+    o_Target = vec4(color, 1.0);
+}
@@ -0,0 +1,19 @@
+#version 450
+
+layout(location = 0) in vec3 Vertex_Position;
+layout(location = 1) out vec3 direction;
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 ViewProj;
+};
+
+layout(set = 1, binding = 0) uniform Transform {
+    mat4 Model;
+};
+
+void main() {
+    direction = Vertex_Position;
+    vec4 position = ViewProj * Model * vec4(Vertex_Position, 1.0);
+    // Put the dome on the far plane, so that it is always behind everything else
+    gl_Position = position.xyww;
+}
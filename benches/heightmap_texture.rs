@@ -0,0 +1,32 @@
+//! Benchmarks building the heightmap texture from a synthetic map of rolling hills and coastline,
+//! which is about the size of a quarter of the real map
+use bevy::prelude::*;
+use bitvec::vec::BitVec;
+use criterion::{criterion_group, criterion_main, Criterion};
+use rome::map::{HeightEncoding, HeightMap};
+use rome_map::{Height, Map};
+
+const WIDTH: usize = 4096;
+const HEIGHT: usize = 2048;
+
+fn synthetic_map() -> HeightMap {
+    let heights: Vec<Height> = (0..HEIGHT)
+        .flat_map(|y| (0..WIDTH).map(move |x| (x as f32, y as f32)))
+        .map(|(x, y)| Height(((x / 97.0).sin() * (y / 61.0).cos() * 1500.0 + (x / 13.0).cos() * 80.0) as i16))
+        .collect();
+    let is_water: BitVec = heights.iter().map(|height| height.0 < 0).collect();
+
+    HeightMap::from_map(&Map { width: WIDTH, height: HEIGHT, height_map: heights, is_water })
+}
+
+fn heightmap_texture(c: &mut Criterion) {
+    let map = synthetic_map();
+
+    let mut group = c.benchmark_group("heightmap");
+    group.sample_size(10);
+    group.bench_function("to_texture", |b| b.iter(|| <(Texture, HeightEncoding)>::from(&map)));
+    group.finish();
+}
+
+criterion_group!(benches, heightmap_texture);
+criterion_main!(benches);
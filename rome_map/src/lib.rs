@@ -2,8 +2,11 @@ use serde::{Serialize, Deserialize};
 use bitvec::vec::BitVec;
 use std::ops::{Add, Div};
 
+pub mod raw;
+
 /// Height of a point (metres)
 #[derive(Serialize, Deserialize, Copy, Clone)]
+#[repr(transparent)]
 pub struct Height(pub i16);
 
 impl Add<Height> for Height {
@@ -42,6 +45,15 @@ impl Map {
     }
 }
 
+#[cfg(feature = "preprocess")]
+impl Map {
+    /// Reads a map in the old `.mapdat` format, which was compressed with zstd
+    pub fn read_mapdat(reader: impl std::io::Read) -> std::io::Result<Map> {
+        bincode::deserialize_from(zstd::Decoder::new(reader)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+}
+
 pub struct Pixel {
     pub height: Height,
     pub is_water: bool,
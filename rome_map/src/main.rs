@@ -21,21 +21,25 @@ mod osm_water_polygons;
 
 fn main() {
     let now = Instant::now();
+    let args: Vec<String> = std::env::args().collect();
+
+    // Maps made before the raw layout can be converted without preprocessing everything again
+    if let [_, command, from, to] = args.as_slice() {
+        if command == "convert" {
+            println!("Converting {} to the raw layout", from);
+            let map = Map::read_mapdat(BufReader::new(File::open(from).unwrap())).unwrap();
+            map.save_raw(to).unwrap();
+            println!("Done in {:.2}s. Saved to {}", now.elapsed().as_secs_f32(), to);
+            return;
+        }
+    }
+
     println!("Reading heightmaps");
     let heightmaps = terrarium_raster::read_all();
     let map = combine(heightmaps);
-    println!("Compressing and saving map data");
-    write_ser(&map, "output/map.mapdat", compress_zstd);
-    println!("Done in {:.2}s. Saved to output/map.mapdat", now.elapsed().as_secs_f32());
-}
-
-fn compress_zstd(bytes: &[u8]) -> Vec<u8> {
-    let mut compressed_bytes = Vec::new();
-    let mut zstd_writer = zstd::Encoder::new(&mut compressed_bytes, 6).unwrap();
-    println!(" -> Compressing");
-    zstd_writer.write_all(bytes).unwrap();
-    zstd_writer.finish().unwrap();
-    compressed_bytes
+    println!("Saving map data");
+    map.save_raw("output/map.heightmap").unwrap();
+    println!("Done in {:.2}s. Saved to output/map.heightmap", now.elapsed().as_secs_f32());
 }
 
 fn compress_xz(bytes: &[u8]) -> Vec<u8> {
@@ -0,0 +1,183 @@
+//! An uncompressed layout of the map that can be memory mapped and read in place, without being
+//! decompressed or deserialised first.
+//!
+//! The layout is, with all integers little endian:
+//!
+//! | Bytes                | Contents                                              |
+//! |----------------------|-------------------------------------------------------|
+//! | 8                    | [`MAGIC`]                                             |
+//! | 4                    | Width                                                 |
+//! | 4                    | Height                                                |
+//! | 2 × width × height   | Heights (`i16`), in row-major order                   |
+//! | ⌈width × height / 8⌉ | Whether each pixel is water, one bit each, LSB first  |
+use crate::{Height, Map, Pixel};
+use bitvec::order::Lsb0;
+use bitvec::slice::BitSlice;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Start of every raw map file, so that other files aren't mistaken for maps
+pub const MAGIC: [u8; 8] = *b"ROMEMAP1";
+const HEADER_LEN: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum RawMapError {
+    NotAMap,
+    /// The file is shorter than its header says
+    Truncated { expected: usize, actual: usize },
+    /// The heights don't start on a two byte boundary, so they can't be read in place
+    Misaligned,
+    /// Heights are stored little endian, so they can only be read in place on little endian
+    /// machines
+    BigEndian,
+}
+
+impl fmt::Display for RawMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RawMapError::NotAMap => write!(f, "not a raw map file"),
+            RawMapError::Truncated { expected, actual } => {
+                write!(f, "map file is truncated: expected {} bytes, but it is {} bytes", expected, actual)
+            }
+            RawMapError::Misaligned => write!(f, "map data is not aligned to two bytes"),
+            RawMapError::BigEndian => write!(f, "maps can only be read on little endian machines"),
+        }
+    }
+}
+
+impl std::error::Error for RawMapError {}
+
+/// A map read in place from bytes in the raw layout
+#[derive(Copy, Clone)]
+pub struct MapView<'a> {
+    pub width: usize,
+    pub height: usize,
+    heights: &'a [Height],
+    is_water: &'a BitSlice<Lsb0, u8>,
+}
+
+impl<'a> MapView<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<MapView<'a>, RawMapError> {
+        if cfg!(target_endian = "big") {
+            return Err(RawMapError::BigEndian);
+        }
+
+        if bytes.len() < HEADER_LEN || bytes[..8] != MAGIC {
+            return Err(RawMapError::NotAMap);
+        }
+
+        let read_u32 = |at: usize| {
+            let mut le = [0; 4];
+            le.copy_from_slice(&bytes[at..at + 4]);
+            u32::from_le_bytes(le) as usize
+        };
+        let (width, height) = (read_u32(8), read_u32(12));
+        let pixels = width * height;
+        let water_start = HEADER_LEN + pixels * 2;
+        let expected = water_start + (pixels + 7) / 8;
+
+        if bytes.len() < expected {
+            return Err(RawMapError::Truncated { expected, actual: bytes.len() });
+        }
+
+        let heights = &bytes[HEADER_LEN..water_start];
+        if heights.as_ptr().align_offset(std::mem::align_of::<Height>()) != 0 {
+            return Err(RawMapError::Misaligned);
+        }
+
+        // SAFETY: `Height` is a transparent `i16`, which any two bytes are a valid value of, and the
+        // bytes are aligned and in the machine's byte order, which were both checked above
+        let heights = unsafe { std::slice::from_raw_parts(heights.as_ptr() as *const Height, pixels) };
+        let is_water = BitSlice::from_slice(&bytes[water_start..expected])
+            .ok_or(RawMapError::NotAMap)?;
+
+        Ok(MapView { width, height, heights, is_water: &is_water[..pixels] })
+    }
+
+    /// Heights of every pixel, in row-major order
+    pub fn heights(&self) -> &'a [Height] {
+        self.heights
+    }
+
+    /// Whether each pixel is water, in row-major order
+    pub fn is_water(&self) -> &'a BitSlice<Lsb0, u8> {
+        self.is_water
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Pixel {
+        let x = usize::min(x as usize, self.width - 1);
+        let y = usize::min(y as usize, self.height - 1);
+
+        Pixel {
+            height: self.heights[x + y * self.width],
+            is_water: self.is_water[x + y * self.width],
+        }
+    }
+}
+
+impl Map {
+    /// Writes the map in the raw layout, so that it can be read with [`MapView::parse`]
+    pub fn write_raw(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&(self.width as u32).to_le_bytes())?;
+        writer.write_all(&(self.height as u32).to_le_bytes())?;
+
+        for height in &self.height_map {
+            writer.write_all(&height.0.to_le_bytes())?;
+        }
+
+        let mut water = vec![0u8; (self.width * self.height + 7) / 8];
+        for (i, is_water) in self.is_water.iter().enumerate() {
+            if *is_water {
+                water[i / 8] |= 1 << (i % 8);
+            }
+        }
+
+        writer.write_all(&water)
+    }
+
+    /// Saves the map in the raw layout. It is written to a temporary file which then replaces the
+    /// map, so that a running game that reloads it never sees it half written.
+    pub fn save_raw(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        self.write_raw(&mut writer)?;
+        writer.flush()?;
+        std::fs::rename(temporary, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitvec::vec::BitVec;
+
+    #[test]
+    fn raw_maps_read_back_in_place() {
+        let heights: Vec<Height> = (0..35).map(|i| Height(i * 100 - 1000)).collect();
+        let is_water: BitVec = heights.iter().map(|height| height.0 < 0).collect();
+        let map = Map { width: 7, height: 5, height_map: heights, is_water };
+
+        // A Vec of u16 so that the bytes are aligned like a memory mapped file's are
+        let mut bytes = Vec::new();
+        map.write_raw(&mut bytes).unwrap();
+        let mut aligned = vec![0u16; (bytes.len() + 1) / 2];
+        let aligned_bytes = unsafe { std::slice::from_raw_parts_mut(aligned.as_mut_ptr() as *mut u8, bytes.len()) };
+        aligned_bytes.copy_from_slice(&bytes);
+
+        let view = MapView::parse(aligned_bytes).unwrap();
+        assert_eq!((view.width, view.height), (7, 5));
+        for (x, y) in (0..7).flat_map(|x| (0..5).map(move |y| (x, y))) {
+            let (expected, actual) = (map.get(x, y), view.get(x, y));
+            assert_eq!((expected.height.0, expected.is_water), (actual.height.0, actual.is_water));
+        }
+
+        assert_eq!(
+            MapView::parse(&aligned_bytes[..aligned_bytes.len() - 1]).err(),
+            Some(RawMapError::Truncated { expected: bytes.len(), actual: bytes.len() - 1 }),
+        );
+    }
+}
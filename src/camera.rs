@@ -0,0 +1,79 @@
+//! The RTS camera, kept within the map and above its terrain
+use bevy::prelude::*;
+use bookmarks::{Bookmarks, FlyTo};
+use goshawk::{RtsCamera, ZoomSettings};
+use crate::map::paper::PaperMap;
+use crate::map::terrain::Terrain;
+use crate::console::AddConsoleCommand;
+use crate::{AppState, STATE_STAGE};
+
+pub mod bookmarks;
+pub mod movement;
+
+/// How far the camera is always kept above the terrain, in world units
+const MIN_CLEARANCE: f32 = 10.0;
+
+pub struct CameraPlugin;
+
+impl Plugin for CameraPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        // Flights and constraints are applied after the camera has been moved, so that they
+        // override it and the camera is never drawn out of bounds
+        let bookmarks = Bookmarks::load(&crate::asset_folder(app.resources()));
+
+        app.add_resource(bookmarks)
+            .add_event::<FlyTo>()
+            .add_console_command(
+                "goto",
+                "goto <latitude> <longitude> | goto <settlement or bookmark>",
+                "Flies the camera to a place",
+            )
+            .on_state_update(STATE_STAGE, AppState::InGame, movement::move_cameras.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, bookmarks::bookmark_keys.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, bookmarks::goto_command.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, bookmarks::start_flights.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, bookmarks::fly_cameras.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, constrain_camera.system());
+    }
+}
+
+/// Keeps the point that the camera looks at on the map and on the ground, and zooms out over high
+/// terrain so that the camera doesn't go into it, though never beyond the furthest zoom
+fn constrain_camera(
+    terrain: Res<Terrain>,
+    paper_map: Res<PaperMap>,
+    mut cameras: Query<(&mut RtsCamera, &ZoomSettings, &mut Transform)>,
+) {
+    let map_size = terrain.world_size();
+
+    for (mut camera, zoom_settings, mut transform) in cameras.iter_mut() {
+        let centre = &mut camera.looking_at;
+        centre.x = centre.x.clamp(0.0, map_size.x);
+        centre.z = centre.z.clamp(0.0, map_size.y);
+        centre.y = terrain.height_at(Vec2::new(centre.x, centre.z), *centre, &paper_map);
+
+        // Moving the camera out changes the ground under it, so this converges over a few steps
+        let direction = camera.rotation * Vec3::unit_z();
+        for _ in 0..3 {
+            let eye = camera.looking_at + direction * camera.zoom_distance;
+            let ground = terrain.height_at(Vec2::new(eye.x, eye.z), camera.looking_at, &paper_map);
+            let min_zoom = (ground + MIN_CLEARANCE - camera.looking_at.y) / direction.y.max(f32::EPSILON);
+            let min_zoom = min_zoom.min(*zoom_settings.distance_range.end());
+
+            if camera.zoom_distance >= min_zoom {
+                break;
+            }
+
+            camera.zoom_distance = min_zoom;
+            camera.zoom_velocity = camera.zoom_velocity.max(0.0);
+        }
+
+        *transform = camera_transform(&camera);
+    }
+}
+
+/// Transform of the camera entity for an [`RtsCamera`], as goshawk sets it
+pub fn camera_transform(camera: &RtsCamera) -> Transform {
+    let translation = camera.looking_at + camera.rotation * Vec3::new(0.0, 0.0, camera.zoom_distance);
+    Transform::from_matrix(Mat4::from_rotation_translation(camera.rotation, translation))
+}
@@ -0,0 +1,300 @@
+//! Camera bookmarks: named places that the camera can fly back to. Ctrl and a number key (by
+//! default) saves the current view into that slot, and the number key on its own flies back to it.
+//! Saved bookmarks are kept in the player's config directory, next to the settings file, and until
+//! the player saves any, the defaults from `assets/config/camera.bookmarks` are used.
+use bevy::prelude::*;
+use goshawk::{RtsCamera, ZoomSettings};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use crate::RomeAssets;
+use crate::console::{Console, ConsoleCommand};
+use crate::input::{Action, Actions, BOOKMARK_SLOTS};
+use crate::map::{LatLong, TileCoord};
+use crate::settings::Settings;
+use crate::settlements::{Gazetteer, Settlement};
+
+/// Path of the default bookmarks within the asset folder
+const DEFAULT_BOOKMARKS: &str = "config/camera.bookmarks";
+
+/// How much the camera zooms out partway through a flight, per world unit flown
+const FLIGHT_ARC: f32 = 0.4;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Bookmark {
+    pub name: String,
+    /// Number key that the bookmark is saved to and recalled with
+    #[serde(default)]
+    pub slot: Option<u8>,
+    pub location: LatLong,
+    pub zoom_distance: f32,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Bookmarks {
+    pub bookmarks: Vec<Bookmark>,
+}
+
+impl Bookmarks {
+    /// Where the player's bookmarks are saved
+    pub fn path() -> Option<PathBuf> {
+        Settings::config_dir().map(|dir| dir.join("camera.bookmarks"))
+    }
+
+    /// The player's bookmarks, or the default ones from the asset folder if they haven't saved any.
+    /// There are no bookmarks if they can't be read.
+    pub fn load(asset_folder: &Path) -> Bookmarks {
+        let path = match Bookmarks::path() {
+            Some(path) if path.exists() => path,
+            _ => asset_folder.join(DEFAULT_BOOKMARKS),
+        };
+
+        let bookmarks = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| ron::de::from_str(&file).map_err(anyhow::Error::from));
+
+        bookmarks.unwrap_or_else(|err| {
+            eprintln!("Couldn't load camera bookmarks from {}: {}", path.display(), err);
+            Bookmarks::default()
+        })
+    }
+
+    /// Saves the bookmarks to the player's config directory
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        let path = Bookmarks::path().ok_or_else(|| anyhow::anyhow!("no config directory"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let pretty = ron::ser::PrettyConfig::new();
+        std::fs::write(path, ron::ser::to_string_pretty(self, pretty)?)?;
+        Ok(())
+    }
+
+    /// Bookmark with the given name, ignoring case
+    pub fn by_name(&self, name: &str) -> Option<&Bookmark> {
+        self.bookmarks.iter().find(|b| b.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn in_slot(&self, slot: u8) -> Option<&Bookmark> {
+        self.bookmarks.iter().find(|b| b.slot == Some(slot))
+    }
+
+    /// Saves a view into a slot, replacing the view of the bookmark already there
+    pub fn set_slot(&mut self, slot: u8, location: LatLong, zoom_distance: f32) {
+        match self.bookmarks.iter_mut().find(|b| b.slot == Some(slot)) {
+            Some(bookmark) => {
+                bookmark.location = location;
+                bookmark.zoom_distance = zoom_distance;
+            }
+            None => self.bookmarks.push(Bookmark {
+                name: format!("Bookmark {}", slot),
+                slot: Some(slot),
+                location,
+                zoom_distance,
+            }),
+        }
+    }
+}
+
+/// Event that flies the camera to look at a place. Send it to focus on something from code, e.g.
+/// `fly_to.send(settlement.into())`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FlyTo {
+    pub location: LatLong,
+    /// Zoom distance to end up at, or `None` to keep the current one
+    pub zoom_distance: Option<f32>,
+}
+
+impl From<&Bookmark> for FlyTo {
+    fn from(bookmark: &Bookmark) -> FlyTo {
+        FlyTo { location: bookmark.location, zoom_distance: Some(bookmark.zoom_distance) }
+    }
+}
+
+impl From<&Settlement> for FlyTo {
+    fn from(settlement: &Settlement) -> FlyTo {
+        FlyTo { location: settlement.location, zoom_distance: None }
+    }
+}
+
+/// A camera's flight from one view to another
+pub struct Flight {
+    from: (Vec2, f32),
+    to: (Vec2, f32),
+    elapsed: f32,
+    duration: f32,
+}
+
+impl Flight {
+    fn new(camera: &RtsCamera, fly_to: FlyTo) -> Flight {
+        let from = Vec2::new(camera.looking_at.x, camera.looking_at.z);
+        let to = fly_to.location.to_tile_coord().to_world_space();
+
+        Flight {
+            from: (from, camera.zoom_distance),
+            to: (to, fly_to.zoom_distance.unwrap_or(camera.zoom_distance)),
+            elapsed: 0.0,
+            // Longer flights take longer, but not in proportion, so that crossing the map isn't slow
+            duration: (from.distance(to) / 400.0).sqrt().clamp(0.5, 2.0),
+        }
+    }
+
+    /// Where the camera looks and its zoom distance a fraction `t` of the way through the flight
+    fn view_at(&self, t: f32) -> (Vec2, f32) {
+        let eased = ease_in_out(t);
+        let (from, to) = (self.from, self.to);
+        let looking_at = from.0 + (to.0 - from.0) * eased;
+
+        // Zoom out in the middle of long flights, so that the land flown over can be seen
+        let arc = from.0.distance(to.0) * FLIGHT_ARC * (PI * eased).sin();
+        (looking_at, from.1 + (to.1 - from.1) * eased + arc)
+    }
+}
+
+fn ease_in_out(t: f32) -> f32 {
+    let t = t.clamp(0.0, 1.0);
+    if t < 0.5 {
+        4.0 * t * t * t
+    } else {
+        1.0 - (-2.0 * t + 2.0).powi(3) / 2.0
+    }
+}
+
+pub fn bookmark_keys(
+    actions: Res<Actions>,
+    mut bookmarks: ResMut<Bookmarks>,
+    mut fly_to: ResMut<Events<FlyTo>>,
+    cameras: Query<&RtsCamera>,
+) {
+    let saving = actions.pressed(Action::SaveBookmark);
+
+    for slot in 1..=BOOKMARK_SLOTS {
+        if !actions.just_pressed(Action::Bookmark(slot)) {
+            continue;
+        }
+
+        if saving {
+            if let Some(camera) = cameras.iter().next() {
+                let position = Vec2::new(camera.looking_at.x, camera.looking_at.z);
+                let location = TileCoord::from_world_space(position).to_lat_long();
+                bookmarks.set_slot(slot, location, camera.zoom_distance);
+
+                if let Err(err) = bookmarks.save() {
+                    eprintln!("Couldn't save camera bookmarks: {}", err);
+                }
+            }
+        } else if let Some(bookmark) = bookmarks.in_slot(slot) {
+            fly_to.send(bookmark.into());
+        }
+    }
+}
+
+/// Runs the `goto` console command, which flies to a latitude and longitude, or to a settlement or
+/// bookmark by name
+pub fn goto_command(
+    mut reader: Local<EventReader<ConsoleCommand>>,
+    commands: Res<Events<ConsoleCommand>>,
+    mut console: ResMut<Console>,
+    mut fly_to: ResMut<Events<FlyTo>>,
+    bookmarks: Res<Bookmarks>,
+    assets: Res<RomeAssets>,
+    gazetteers: Res<Assets<Gazetteer>>,
+) {
+    for command in reader.iter(&commands).filter(|command| command.name == "goto") {
+        let coordinates: Vec<f32> = command.args.iter().filter_map(|arg| arg.parse().ok()).collect();
+        let name = command.args.join(" ");
+
+        let target = match *coordinates.as_slice() {
+            [latitude, longitude] if command.args.len() == 2 => {
+                if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+                    console.print(format!("{}, {} isn't a latitude and longitude", latitude, longitude));
+                    continue;
+                }
+
+                FlyTo { location: LatLong { latitude, longitude }, zoom_distance: None }
+            }
+            _ if name.is_empty() => {
+                console.print("Usage: goto <latitude> <longitude> | goto <settlement or bookmark>");
+                continue;
+            }
+            _ => {
+                let settlement = gazetteers.get(&assets.gazetteer).and_then(|gazetteer| gazetteer.by_name(&name));
+
+                match bookmarks.by_name(&name).map(FlyTo::from).or_else(|| settlement.map(FlyTo::from)) {
+                    Some(target) => target,
+                    None => {
+                        console.print(format!("There is no settlement or bookmark called {}", name));
+                        continue;
+                    }
+                }
+            }
+        };
+
+        fly_to.send(target);
+    }
+}
+
+pub fn start_flights(
+    commands: &mut Commands,
+    mut reader: Local<EventReader<FlyTo>>,
+    events: Res<Events<FlyTo>>,
+    cameras: Query<(Entity, &RtsCamera)>,
+) {
+    if let Some(&fly_to) = reader.latest(&events) {
+        for (entity, camera) in cameras.iter() {
+            commands.insert_one(entity, Flight::new(camera, fly_to));
+        }
+    }
+}
+
+pub fn fly_cameras(
+    commands: &mut Commands,
+    time: Res<Time>,
+    mut cameras: Query<(Entity, &mut RtsCamera, &mut Flight, Option<&ZoomSettings>)>,
+) {
+    for (entity, mut camera, mut flight, zoom) in cameras.iter_mut() {
+        flight.elapsed += time.delta_seconds();
+        let t = flight.elapsed / flight.duration;
+        let (looking_at, zoom_distance) = flight.view_at(t);
+
+        camera.looking_at.x = looking_at.x;
+        camera.looking_at.z = looking_at.y;
+        camera.zoom_distance = match zoom {
+            Some(zoom) => zoom_distance.clamp(*zoom.distance_range.start(), *zoom.distance_range.end()),
+            None => zoom_distance,
+        };
+        camera.pan_velocity = Vec2::zero();
+        camera.zoom_velocity = 0.0;
+
+        if t >= 1.0 {
+            commands.remove_one::<Flight>(entity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_bookmarks_parse() {
+        let bookmarks: Bookmarks = ron::de::from_str(include_str!("../../assets/config/camera.bookmarks")).unwrap();
+        assert!(bookmarks.by_name("roma").is_some());
+    }
+
+    #[test]
+    fn flights_start_and_end_at_their_views() {
+        let flight = Flight {
+            from: (Vec2::new(0.0, 0.0), 100.0),
+            to: (Vec2::new(300.0, 400.0), 200.0),
+            elapsed: 0.0,
+            duration: 1.0,
+        };
+
+        assert_eq!(flight.view_at(0.0), (Vec2::new(0.0, 0.0), 100.0));
+        let (looking_at, zoom_distance) = flight.view_at(1.0);
+        assert!(looking_at.distance(Vec2::new(300.0, 400.0)) < 1.0e-3);
+        assert!((zoom_distance - 200.0).abs() < 1.0e-3);
+    }
+}
@@ -0,0 +1,247 @@
+//! Moves the RTS camera with the camera [`Action`]s. This works like goshawk's `rts_camera_system`,
+//! with the same settings and feel, but reads actions instead of goshawk's fixed keys, so that the
+//! controls can be rebound and the camera can be moved with a gamepad.
+use bevy::prelude::*;
+use goshawk::{PanSettings, RtsCamera, TurnSettings, ZoomSettings};
+use std::f32::consts::TAU;
+use std::ops::RangeInclusive;
+use crate::camera::camera_transform;
+use crate::input::{Action, Actions};
+
+/// Scrolling is treated as still going on for this long after the last scroll, as otherwise the
+/// zoom decelerates between scroll ticks and is too slow
+const SCROLL_TICK_GRACE_SECS: f64 = 0.05;
+
+pub fn move_cameras(
+    time: Res<Time>,
+    windows: Res<Windows>,
+    actions: Res<Actions>,
+    mut cameras: Query<(&mut RtsCamera, &mut Transform, &ZoomSettings, &PanSettings, &TurnSettings)>,
+) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+
+    for (mut camera, mut transform, zoom, pan, turn) in cameras.iter_mut() {
+        tick(&mut camera, window, &actions, zoom, pan, turn, &time);
+        *transform = camera_transform(&camera);
+    }
+}
+
+fn tick(
+    camera: &mut RtsCamera,
+    window: &Window,
+    actions: &Actions,
+    zoom: &ZoomSettings,
+    pan: &PanSettings,
+    turn: &TurnSettings,
+    time: &Time,
+) {
+    let (delta, now) = (time.delta_seconds(), time.seconds_since_startup());
+    let [mut x_decel, mut y_decel, mut turn_decel]: [Deceleration; 3] = Default::default();
+    let mut zoom_decel = if now - camera.last_scroll_sec < SCROLL_TICK_GRACE_SECS {
+        Deceleration { pos: false, neg: false }
+    } else {
+        Deceleration::default()
+    };
+
+    // Panning or turning with the mouse at the edges of the window
+    if let Some(cursor) = window.cursor_position() {
+        let turning = cursor.y > window.height() * (1.0 - turn.mouse_turn_margin);
+
+        if cursor.x < pan.mouse_accel_margin {
+            if turning {
+                accelerate(&mut camera.turn_velocity, turn.mouse_accel, 1.0, delta, &mut turn_decel.pos);
+            } else {
+                accelerate(&mut camera.pan_velocity.x, -pan.mouse_accel, 1.0, delta, &mut x_decel.neg);
+            }
+        } else if cursor.x > window.width() - pan.mouse_accel_margin {
+            if turning {
+                accelerate(&mut camera.turn_velocity, -turn.mouse_accel, 1.0, delta, &mut turn_decel.neg);
+            } else {
+                accelerate(&mut camera.pan_velocity.x, pan.mouse_accel, 1.0, delta, &mut x_decel.pos);
+            }
+        }
+
+        if cursor.y < pan.mouse_accel_margin {
+            accelerate(&mut camera.pan_velocity.y, -pan.mouse_accel, 1.0, delta, &mut y_decel.neg);
+        } else if cursor.y > window.height() - pan.mouse_accel_margin {
+            accelerate(&mut camera.pan_velocity.y, pan.mouse_accel, 1.0, delta, &mut y_decel.pos);
+        }
+    }
+
+    let held = |action| actions.value(action);
+    accelerate(&mut camera.pan_velocity.x, pan.keyboard_accel, held(Action::PanRight), delta, &mut x_decel.pos);
+    accelerate(&mut camera.pan_velocity.x, -pan.keyboard_accel, held(Action::PanLeft), delta, &mut x_decel.neg);
+    accelerate(&mut camera.pan_velocity.y, pan.keyboard_accel, held(Action::PanUp), delta, &mut y_decel.pos);
+    accelerate(&mut camera.pan_velocity.y, -pan.keyboard_accel, held(Action::PanDown), delta, &mut y_decel.neg);
+    accelerate(&mut camera.turn_velocity, -turn.keyboard_accel, held(Action::TurnRight), delta, &mut turn_decel.neg);
+    accelerate(&mut camera.turn_velocity, turn.keyboard_accel, held(Action::TurnLeft), delta, &mut turn_decel.pos);
+    // As in goshawk, zooming stops deceleration against the other direction, unlike panning and
+    // turning, so idle deceleration holds the keyboard zoom back
+    accelerate(&mut camera.zoom_velocity, -zoom.keyboard_accel, held(Action::ZoomIn), delta, &mut zoom_decel.pos);
+    accelerate(&mut camera.zoom_velocity, zoom.keyboard_accel, held(Action::ZoomOut), delta, &mut zoom_decel.neg);
+
+    // Scrolling comes in ticks, so it changes the velocity at once rather than accelerating it
+    let scroll = actions.scrolled(Action::ZoomIn) - actions.scrolled(Action::ZoomOut);
+    if scroll != 0.0 {
+        if scroll > 0.0 {
+            zoom_decel.pos = false;
+        } else {
+            zoom_decel.neg = false;
+        }
+
+        camera.zoom_velocity -= scroll * zoom.scroll_accel;
+        camera.last_scroll_sec = now;
+    }
+
+    turn_decel.apply(&mut camera.turn_velocity, turn.idle_deceleration, delta);
+    zoom_decel.apply(&mut camera.zoom_velocity, zoom.idle_deceleration, delta);
+    x_decel.apply(&mut camera.pan_velocity.x, pan.idle_deceleration, delta);
+    y_decel.apply(&mut camera.pan_velocity.y, pan.idle_deceleration, delta);
+
+    if camera.pan_velocity.length_squared() > pan.max_speed * pan.max_speed {
+        camera.pan_velocity = pan.max_speed * camera.pan_velocity.normalize();
+    }
+    camera.zoom_velocity = camera.zoom_velocity.clamp(-zoom.max_velocity, zoom.max_velocity);
+    camera.turn_velocity = camera.turn_velocity.clamp(-turn.max_speed, turn.max_speed);
+
+    camera.zoom_distance += camera.zoom_velocity * delta;
+    camera.zoom_distance = clamp(camera.zoom_distance, &zoom.distance_range);
+    if camera.zoom_distance == *zoom.distance_range.start() || camera.zoom_distance == *zoom.distance_range.end() {
+        camera.zoom_velocity = 0.0;
+    }
+
+    let turn_by = camera.turn_velocity * delta;
+    rotate(camera, turn_by);
+    camera.yaw = clamp(camera.yaw, &turn.yaw_range);
+
+    // The camera tilts as it zooms, looking down from further out
+    let pitch = lerp_in_zone(camera.zoom_distance, &zoom.angle_change_zone, &zoom.angle_range);
+    camera.rotation = Quat::from_rotation_ypr(camera.yaw, -pitch, 0.0);
+
+    // goshawk scales panning by the zoom distance within the angle range rather than the distance
+    // range, which puts it at the end of the factor range at every distance. The pan speeds in the
+    // settings are tuned to that, so it is kept here.
+    let forward = Quat::from_rotation_y(camera.yaw);
+    let distance_factor = lerp_in_zone(camera.zoom_distance, &zoom.angle_range, &pan.pan_speed_zoom_factor_range);
+    let pan_by = forward * Vec3::new(camera.pan_velocity.x, 0.0, -camera.pan_velocity.y) * delta * distance_factor;
+    camera.looking_at += pan_by;
+}
+
+/// Speeds up a velocity by an acceleration scaled by how strongly its input is held, and stops it
+/// from decelerating in that direction while it is held
+fn accelerate(velocity: &mut f32, accel: f32, held: f32, delta: f32, decelerate: &mut bool) {
+    if held > 0.0 {
+        *velocity += accel * held * delta;
+        *decelerate = false;
+    }
+}
+
+/// Turns the camera around the point that it is looking at
+fn rotate(camera: &mut RtsCamera, angle: f32) {
+    camera.yaw = (camera.yaw + angle).rem_euclid(TAU);
+
+    let eye = camera.looking_at + camera.rotation * Vec3::new(0.0, 0.0, camera.zoom_distance);
+    camera.looking_at = Quat::from_rotation_y(angle) * (camera.looking_at - eye) + eye;
+}
+
+/// Which directions of motion a velocity is pushed against. With both, it slows to a stop, and with
+/// one, it is pushed the other way, which speeds up the camera in the direction its input is held.
+#[derive(Copy, Clone, Debug)]
+struct Deceleration {
+    pos: bool,
+    neg: bool,
+}
+
+impl Default for Deceleration {
+    fn default() -> Self {
+        Deceleration { pos: true, neg: true }
+    }
+}
+
+impl Deceleration {
+    fn apply(self, velocity: &mut f32, magnitude: f32, delta: f32) {
+        let direction = match (self.pos, self.neg) {
+            _ if *velocity == 0.0 => return,
+            (true, true) => -velocity.signum(),
+            (true, false) => -1.0,
+            (false, true) => 1.0,
+            (false, false) => return,
+        };
+
+        *velocity += f32::min(magnitude * delta, velocity.abs()) * direction;
+    }
+}
+
+fn clamp(x: f32, range: &RangeInclusive<f32>) -> f32 {
+    x.max(*range.start()).min(*range.end())
+}
+
+fn lerp_in_zone(value: f32, zone: &RangeInclusive<f32>, values: &RangeInclusive<f32>) -> f32 {
+    let normalised = (clamp(value, zone) - zone.start()) / (zone.end() - zone.start());
+    values.start() + normalised * (values.end() - values.start())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::input::mouse::MouseWheel;
+    use bevy::window::{WindowDescriptor, WindowId};
+
+    fn window() -> Window {
+        let mut window = Window::new(WindowId::primary(), &WindowDescriptor::default(), 1280, 720, 1.0);
+        // In the middle of the window, so that the mouse doesn't pan or turn the camera
+        window.update_cursor_position_from_backend(Some(Vec2::new(640.0, 360.0)));
+        window
+    }
+
+    fn camera() -> RtsCamera {
+        RtsCamera { zoom_distance: 50.0, zoom_velocity: 3.0, last_scroll_sec: -1.0, ..Default::default() }
+    }
+
+    /// Zoom velocity and distance after goshawk's own system has run for one frame with the zoom in
+    /// key held, with the default settings
+    fn goshawk_tick(world: &mut World, resources: &mut Resources) -> (f32, f32) {
+        let mut keys = Input::<KeyCode>::default();
+        keys.press(KeyCode::Equals);
+        let mut windows = Windows::default();
+        windows.add(window());
+
+        resources.insert(keys);
+        resources.insert(windows);
+        resources.insert(Events::<MouseWheel>::default());
+
+        let entity = world.spawn((camera(), Transform::default()));
+        let mut system = goshawk::rts_camera_system.system();
+        system.initialize(world, resources);
+        system.update(world);
+        system.run((), world, resources);
+
+        let camera = world.get::<RtsCamera>(entity).unwrap();
+        (camera.zoom_velocity, camera.zoom_distance)
+    }
+
+    #[test]
+    fn zooming_matches_goshawk() {
+        let (mut world, mut resources) = (World::default(), Resources::default());
+        let mut time = Time::default();
+        time.update();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        time.update();
+        resources.insert(time);
+
+        let (velocity, distance) = goshawk_tick(&mut world, &mut resources);
+
+        let mut actions = Actions::default();
+        actions.hold(Action::ZoomIn, 1.0);
+        let mut camera = camera();
+        let time = resources.get::<Time>().unwrap();
+        let (zoom, pan, turn) = (ZoomSettings::default(), PanSettings::default(), TurnSettings::default());
+        tick(&mut camera, &window(), &actions, &zoom, &pan, &turn, &time);
+
+        assert!((camera.zoom_velocity - velocity).abs() < 1.0e-5, "{} != {}", camera.zoom_velocity, velocity);
+        assert!((camera.zoom_distance - distance).abs() < 1.0e-5, "{} != {}", camera.zoom_distance, distance);
+    }
+}
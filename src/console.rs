@@ -0,0 +1,274 @@
+//! A developer console for debug commands such as `goto 41.9 12.49` or `mapmode political`, opened
+//! and closed with the backtick key by default. Plugins add their own commands with
+//! [`AddConsoleCommand::add_console_command`], and run them by reading [`ConsoleCommand`] events.
+//! The console comes with the [debug overlay](overlay), which plugins can also add to.
+use bevy::prelude::*;
+use bevy::window::ReceivedCharacter;
+use crate::input::{Action, Actions};
+use crate::{AppState, STATE_STAGE};
+
+pub mod overlay;
+
+/// How many of the most recent lines of output are shown
+const VISIBLE_LINES: usize = 16;
+/// How many lines of output are kept before the oldest are dropped
+const MAX_LINES: usize = 256;
+const BACKGROUND: Color = Color::rgba_linear(0.0, 0.0, 0.0, 0.75);
+const TEXT: Color = Color::rgb_linear(0.8, 0.8, 0.8);
+
+pub struct ConsolePlugin;
+
+impl Plugin for ConsolePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<Console>()
+            .add_event::<ConsoleCommand>()
+            .add_console_command("help", "help", "Lists every command")
+            .add_console_command("clear", "clear", "Clears the console")
+            .add_console_command("overlay", "overlay", "Shows or hides the debug overlay")
+            .add_plugin(bevy::diagnostic::FrameTimeDiagnosticsPlugin)
+            .init_resource::<overlay::DebugOverlay>()
+            .on_state_enter(STATE_STAGE, AppState::InGame, overlay::spawn_overlay.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, toggle_console.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, type_into_console.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, console_commands.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, update_console.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, overlay::toggle_overlay.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, overlay::update_frame_stats.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, overlay::update_camera_stats.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, overlay::update_hovered_pixel.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, overlay::update_memory_stats.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, overlay::update_clipmap_stats.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, overlay::update_overlay_text.system());
+    }
+}
+
+/// A command entered into the console. Systems that run a command read these, and check the name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsoleCommand {
+    /// Name of the command, in lowercase
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+impl ConsoleCommand {
+    /// Splits a line into a command's name and arguments on whitespace
+    pub fn parse(line: &str) -> Option<ConsoleCommand> {
+        let mut words = line.split_whitespace();
+        let name = words.next()?.to_lowercase();
+
+        Some(ConsoleCommand { name, args: words.map(str::to_string).collect() })
+    }
+}
+
+/// A command that can be entered into the console, for `help`
+#[derive(Clone, Debug)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// How the command is written, e.g. `mapmode <terrain|political|diplomatic|economic>`
+    pub usage: &'static str,
+    pub help: &'static str,
+}
+
+/// Every command that can be entered into the console
+#[derive(Default)]
+pub struct ConsoleCommands(pub Vec<CommandInfo>);
+
+pub trait AddConsoleCommand {
+    /// Adds a command to the console. This only makes it known to the console: a system still has
+    /// to run it by reading [`ConsoleCommand`]s with its name.
+    fn add_console_command(&mut self, name: &'static str, usage: &'static str, help: &'static str) -> &mut Self;
+}
+
+impl AddConsoleCommand for AppBuilder {
+    fn add_console_command(&mut self, name: &'static str, usage: &'static str, help: &'static str) -> &mut Self {
+        let resources = self.resources_mut();
+        if resources.get::<ConsoleCommands>().is_none() {
+            resources.insert(ConsoleCommands::default());
+        }
+
+        resources.get_mut::<ConsoleCommands>().unwrap().0.push(CommandInfo { name, usage, help });
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct Console {
+    open: bool,
+    /// The line being typed
+    input: String,
+    /// Commands entered and their output, oldest first, up to [`MAX_LINES`] of them
+    lines: Vec<String>,
+}
+
+impl Console {
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Adds a line of output to the console
+    pub fn print(&mut self, line: impl Into<String>) {
+        self.lines.push(line.into());
+
+        if self.lines.len() > MAX_LINES {
+            self.lines.drain(..self.lines.len() - MAX_LINES);
+        }
+    }
+}
+
+/// Marks the root node of the console
+struct ConsoleRoot;
+
+/// Marks the text of the console
+struct ConsoleText;
+
+fn toggle_console(keys: Res<Input<KeyCode>>, mut actions: ResMut<Actions>, mut console: ResMut<Console>) {
+    let closing = console.open && keys.just_pressed(KeyCode::Escape);
+
+    if actions.just_pressed(Action::ToggleConsole) || closing {
+        console.open = !console.open;
+
+        // Keys typed into the console shouldn't also move the camera and so on
+        actions.capture_keyboard(console.open);
+    }
+}
+
+fn type_into_console(
+    keys: Res<Input<KeyCode>>,
+    actions: Res<Actions>,
+    (mut reader, characters): (Local<EventReader<ReceivedCharacter>>, Res<Events<ReceivedCharacter>>),
+    registry: Res<ConsoleCommands>,
+    mut console: ResMut<Console>,
+    mut commands: ResMut<Events<ConsoleCommand>>,
+) {
+    // Characters are read even while the console is closed, so that they don't build up, and the
+    // key that opens the console isn't typed into it
+    let typed: String = reader.iter(&characters).map(|c| c.char).filter(|c| !c.is_control()).collect();
+
+    if !console.open || actions.just_pressed(Action::ToggleConsole) {
+        return;
+    }
+
+    if !typed.is_empty() {
+        console.input.push_str(&typed);
+    }
+
+    if keys.just_pressed(KeyCode::Back) {
+        console.input.pop();
+    }
+
+    if keys.just_pressed(KeyCode::Return) || keys.just_pressed(KeyCode::NumpadEnter) {
+        let line = std::mem::take(&mut console.input);
+        let command = match ConsoleCommand::parse(&line) {
+            Some(command) => command,
+            None => return,
+        };
+
+        console.print(format!("> {}", line.trim()));
+
+        if registry.0.iter().any(|info| info.name == command.name) {
+            commands.send(command);
+        } else {
+            console.print(format!("Unknown command `{}`. Enter `help` to list every command.", command.name));
+        }
+    }
+}
+
+/// Runs the console's own commands
+fn console_commands(
+    mut reader: Local<EventReader<ConsoleCommand>>,
+    commands: Res<Events<ConsoleCommand>>,
+    registry: Res<ConsoleCommands>,
+    mut console: ResMut<Console>,
+    mut overlay: ResMut<overlay::DebugOverlay>,
+) {
+    for command in reader.iter(&commands) {
+        match command.name.as_str() {
+            "help" => {
+                for info in registry.0.iter() {
+                    console.print(format!("{} - {}", info.usage, info.help));
+                }
+            }
+            "clear" => console.lines.clear(),
+            "overlay" => overlay.visible = !overlay.visible,
+            _ => {}
+        }
+    }
+}
+
+/// Shows the console while it is open
+fn update_console(
+    commands: &mut Commands,
+    console: ChangedRes<Console>,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    roots: Query<Entity, With<ConsoleRoot>>,
+    mut texts: Query<&mut Text, With<ConsoleText>>,
+) {
+    if !console.open {
+        for root in roots.iter() {
+            commands.despawn_recursive(root);
+        }
+        return;
+    }
+
+    let shown = console.lines.len().saturating_sub(VISIBLE_LINES);
+    let mut value = console.lines[shown..].join("\n");
+    value.push_str(&format!("\n> {}_", console.input));
+
+    if roots.iter().next().is_some() {
+        for mut text in texts.iter_mut() {
+            text.value = value.clone();
+        }
+        return;
+    }
+
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect { left: Val::Px(0.0), top: Val::Px(0.0), ..Default::default() },
+                size: Size::new(Val::Percent(100.0), Val::Auto),
+                padding: Rect::all(Val::Px(8.0)),
+                ..Default::default()
+            },
+            material: materials.add(BACKGROUND.into()),
+            ..Default::default()
+        })
+        .with(ConsoleRoot)
+        .with_children(|root| {
+            root.spawn(TextBundle {
+                text: Text {
+                    value,
+                    font: asset_server.load("fonts/FiraMono-Medium.ttf"),
+                    style: TextStyle { font_size: 18.0, color: TEXT, alignment: TextAlignment::default() },
+                },
+                ..Default::default()
+            })
+            .with(ConsoleText);
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_split_into_a_name_and_arguments() {
+        let command = ConsoleCommand::parse("  GoTo  41.9 12.49 ").unwrap();
+
+        assert_eq!(command.name, "goto");
+        assert_eq!(command.args, vec!["41.9".to_string(), "12.49".to_string()]);
+        assert_eq!(ConsoleCommand::parse("   "), None);
+    }
+
+    #[test]
+    fn oldest_lines_are_dropped() {
+        let mut console = Console::default();
+        for i in 0..MAX_LINES + 10 {
+            console.print(i.to_string());
+        }
+
+        assert_eq!(console.lines.len(), MAX_LINES);
+        assert_eq!(console.lines[0], "10");
+    }
+}
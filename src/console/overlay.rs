@@ -0,0 +1,233 @@
+//! The debug overlay in the corner of the screen, which shows the frame rate, the camera, the pixel
+//! of the map under the cursor, memory use and the clipmap. It is made of named sections, so other
+//! plugins can show their own stats on it with [`DebugOverlay::set`].
+use bevy::diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin};
+use bevy::prelude::*;
+use bevy::render::camera::Camera;
+use bevy::render::mesh::Indices;
+use goshawk::RtsCamera;
+use crate::RomeAssets;
+use crate::input::{Action, Actions};
+use crate::map::mesh::ClipmapConfig;
+use crate::map::overlay::{Ownership, Realms, OWNERSHIP_SCALE};
+use crate::map::paper::PaperMap;
+use crate::map::terrain::Terrain;
+use crate::map::{TileCoord, XYZ_SCALE};
+use crate::settlements::Gazetteer;
+
+/// How often memory use is measured, in seconds
+const MEMORY_INTERVAL: f64 = 1.0;
+
+pub struct DebugOverlay {
+    pub visible: bool,
+    /// Text of each section, in the order that they were first set
+    sections: Vec<(&'static str, String)>,
+}
+
+impl Default for DebugOverlay {
+    fn default() -> Self {
+        DebugOverlay { visible: true, sections: Vec::new() }
+    }
+}
+
+impl DebugOverlay {
+    /// Sets the text of a section of the overlay, adding it to the bottom if it is new
+    pub fn set(&mut self, section: &'static str, text: String) {
+        match self.sections.iter_mut().find(|(name, _)| *name == section) {
+            Some((_, old)) => *old = text,
+            None => self.sections.push((section, text)),
+        }
+    }
+
+    pub fn remove(&mut self, section: &'static str) {
+        self.sections.retain(|(name, _)| *name != section);
+    }
+
+    fn text(&self) -> String {
+        if !self.visible {
+            return String::new();
+        }
+
+        self.sections.iter().map(|(_, text)| text.as_str()).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Marks the text of the overlay
+pub struct OverlayText;
+
+pub fn spawn_overlay(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                align_self: AlignSelf::FlexEnd,
+                margin: Rect::all(Val::Px(4.0)),
+                ..Default::default()
+            },
+            text: Text {
+                value: String::new(),
+                font: asset_server.load("fonts/FiraMono-Medium.ttf"),
+                style: TextStyle { font_size: 20.0, color: Color::WHITE, alignment: TextAlignment::default() },
+            },
+            ..Default::default()
+        })
+        .with(OverlayText);
+}
+
+pub fn toggle_overlay(actions: Res<Actions>, mut overlay: ResMut<DebugOverlay>) {
+    if actions.just_pressed(Action::ToggleDebugOverlay) {
+        overlay.visible = !overlay.visible;
+    }
+}
+
+pub fn update_frame_stats(diagnostics: Res<Diagnostics>, mut overlay: ResMut<DebugOverlay>) {
+    let average = |diagnostic| diagnostics.get(diagnostic).and_then(|d| d.average());
+
+    if let (Some(fps), Some(frame_time)) = (average(FrameTimeDiagnosticsPlugin::FPS), average(FrameTimeDiagnosticsPlugin::FRAME_TIME)) {
+        overlay.set("frame", format!("FPS: {:.0}. Frame time: {:.2}ms.", fps.round(), frame_time * 1000.0));
+    }
+}
+
+pub fn update_camera_stats(cameras: Query<&RtsCamera>, mut overlay: ResMut<DebugOverlay>) {
+    if let Some(camera) = cameras.iter().next() {
+        let (centre, zoom) = (camera.looking_at, camera.zoom_distance);
+        overlay.set("camera", format!("Camera: looking at ({:.2}; {:.2}), zoom {:.2}.", centre.x, centre.z, zoom));
+    }
+}
+
+/// Shows the pixel of the map under the cursor: where it is, how high it is, who owns it, the
+/// nearest settlement and which clipmap LOD it is drawn with
+#[allow(clippy::too_many_arguments)]
+pub fn update_hovered_pixel(
+    windows: Res<Windows>,
+    cameras: Query<(&Camera, &GlobalTransform, &RtsCamera)>,
+    terrain: Res<Terrain>,
+    paper_map: Res<PaperMap>,
+    ownership: Res<Ownership>,
+    realms: Res<Realms>,
+    assets: Res<RomeAssets>,
+    gazetteers: Res<Assets<Gazetteer>>,
+    mut overlay: ResMut<DebugOverlay>,
+) {
+    // Raycasting the terrain is too slow to do for a hidden overlay
+    if !overlay.visible {
+        return;
+    }
+
+    let hit = windows.get_primary().and_then(|window| {
+        let cursor = window.cursor_position()?;
+        let (camera, transform, rts_camera) = cameras.iter().next()?;
+
+        // The cursor is measured from the bottom left, like normalised device coordinates
+        let ndc = cursor / Vec2::new(window.width(), window.height()) * 2.0 - Vec2::one();
+        let inverse_view_proj = (camera.projection_matrix * transform.compute_matrix().inverse()).inverse();
+        let far = inverse_view_proj * Vec4::new(ndc.x, ndc.y, 1.0, 1.0);
+        let ray = far.truncate() / far.w - transform.translation;
+
+        let hit = terrain.raycast(transform.translation, ray, ray.length(), rts_camera.looking_at, &paper_map)?;
+        Some((hit, rts_camera.looking_at))
+    });
+
+    let (hit, centre) = match hit {
+        Some(hit) => hit,
+        None => return overlay.set("pixel", "Pixel: none under the cursor.".to_string()),
+    };
+
+    let position = Vec2::new(hit.x, hit.z);
+    let texel = position / XYZ_SCALE;
+    let lat_long = TileCoord::from_world_space(position).to_lat_long();
+
+    let (x, y) = ((texel.x.max(0.0) as u32 / OWNERSHIP_SCALE) as usize, (texel.y.max(0.0) as u32 / OWNERSHIP_SCALE) as usize);
+    let owner = match ownership.owner(x.min(ownership.width - 1), y.min(ownership.height - 1)) {
+        0 => None,
+        owner => realms.0.get(owner as usize),
+    };
+
+    let nearest = gazetteers.get(&assets.gazetteer).and_then(|gazetteer| gazetteer.nearest(lat_long));
+
+    overlay.set("pixel", format!(
+        "Pixel: ({:.0}; {:.0}) at {:.2}, {:.2}. Elevation {:.0}m. Owner: {}. Near {}. LOD {}.",
+        texel.x.floor(),
+        texel.y.floor(),
+        lat_long.latitude,
+        lat_long.longitude,
+        terrain.elevation_at(position),
+        owner.map_or("none", |realm| realm.name.as_str()),
+        nearest.map_or("nowhere", |settlement| settlement.latin_name.as_str()),
+        terrain.lod_at_position(position, centre),
+    ));
+}
+
+/// Shows how much memory textures take up, and how much the whole process does where that can be
+/// measured
+pub fn update_memory_stats(
+    time: Res<Time>,
+    mut last_measured: Local<Option<f64>>,
+    textures: Res<Assets<Texture>>,
+    mut overlay: ResMut<DebugOverlay>,
+) {
+    let now = time.seconds_since_startup();
+    if matches!(*last_measured, Some(last) if now - last < MEMORY_INTERVAL) {
+        return;
+    }
+    *last_measured = Some(now);
+
+    let texture_bytes: usize = textures.iter().map(|(_, texture)| texture.data.len()).sum();
+    let mut text = format!("Memory: {} textures, {:.1} MiB", textures.len(), mebibytes(texture_bytes));
+
+    if let Some(resident) = resident_memory() {
+        text.push_str(&format!(". Process: {:.1} MiB resident", mebibytes(resident)));
+    }
+
+    text.push('.');
+    overlay.set("memory", text);
+}
+
+fn mebibytes(bytes: usize) -> f32 {
+    bytes as f32 / (1024.0 * 1024.0)
+}
+
+/// Resident memory of the process, in bytes. This is only known on Linux.
+fn resident_memory() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kibibytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kibibytes * 1024)
+}
+
+pub fn update_clipmap_stats(
+    assets: Res<RomeAssets>,
+    meshes: Res<Assets<Mesh>>,
+    clipmap_config: Res<ClipmapConfig>,
+    mut overlay: ResMut<DebugOverlay>,
+) {
+    let mesh = match meshes.get(&assets.clipmap_mesh) {
+        Some(mesh) => mesh,
+        None => return,
+    };
+
+    let vertices = mesh.attribute(Mesh::ATTRIBUTE_POSITION).map_or(0, |positions| positions.len());
+    let triangles = match mesh.indices() {
+        Some(Indices::U16(indices)) => indices.len() / 3,
+        Some(Indices::U32(indices)) => indices.len() / 3,
+        None => vertices / 3,
+    };
+
+    overlay.set("clipmap", format!(
+        "Clipmap: {} LODs, {} vertices, {} triangles. Visible for {} texels.",
+        clipmap_config.lod_levels,
+        vertices,
+        triangles,
+        clipmap_config.visible_radius(),
+    ));
+}
+
+pub fn update_overlay_text(overlay: ChangedRes<DebugOverlay>, mut texts: Query<&mut Text, With<OverlayText>>) {
+    let value = overlay.text();
+
+    for mut text in texts.iter_mut() {
+        // Changing text re-lays it out, so only do it when it actually changes
+        if text.value != value {
+            text.value = value.clone();
+        }
+    }
+}
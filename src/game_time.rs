@@ -0,0 +1,53 @@
+use bevy::prelude::*;
+use crate::{AppState, STATE_STAGE};
+
+const DAYS_PER_YEAR: f64 = 365.25;
+
+pub struct GameTimePlugin;
+
+impl Plugin for GameTimePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(GameTime::default())
+            .on_state_update(STATE_STAGE, AppState::InGame, advance_game_time.system());
+    }
+}
+
+/// The in-game date and time, which drives the day/night cycle and seasons
+pub struct GameTime {
+    /// Days since midnight on the 1st of January of the first year. The fractional part is the time
+    /// of day.
+    pub days: f64,
+    /// How many in-game days pass per real second
+    pub speed: f64,
+    pub paused: bool,
+}
+
+impl Default for GameTime {
+    fn default() -> Self {
+        GameTime {
+            // Mid morning in early April
+            days: 95.4,
+            // Two minutes per day
+            speed: 1.0 / 120.0,
+            paused: false,
+        }
+    }
+}
+
+impl GameTime {
+    /// Fraction of the day that has passed, with 0 being midnight and 0.5 being midday
+    pub fn time_of_day(&self) -> f32 {
+        self.days.fract() as f32
+    }
+
+    /// Fraction of the year that has passed, with 0 being the 1st of January
+    pub fn time_of_year(&self) -> f32 {
+        ((self.days % DAYS_PER_YEAR) / DAYS_PER_YEAR) as f32
+    }
+}
+
+fn advance_game_time(time: Res<Time>, mut game_time: ResMut<GameTime>) {
+    if !game_time.paused {
+        game_time.days += time.delta_seconds_f64() * game_time.speed;
+    }
+}
@@ -0,0 +1,387 @@
+//! Input actions: what the player wants to do, such as pan the camera or switch map modes, rather
+//! than which keys they pressed for it. Each action is bound to any number of keys, mouse buttons,
+//! scroll directions and gamepad buttons or sticks, which are kept in the settings file under
+//! `input`, so systems read [`Actions`] rather than raw input and the controls can be rebound. They
+//! can also be rebound in game with the `bind` and `unbind` console commands, which save the new
+//! bindings to the settings file.
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::console::{AddConsoleCommand, Console, ConsoleCommand};
+use crate::settings::Settings;
+
+/// How far a gamepad stick has to be pushed before it counts as input
+const STICK_DEAD_ZONE: f32 = 0.15;
+
+/// Number of bookmark slots, which are bound to the number keys by default
+pub const BOOKMARK_SLOTS: u8 = 9;
+
+pub struct ActionsPlugin;
+
+impl Plugin for ActionsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        // Bevy updates its input in the event stage, so actions are ready before anything uses them
+        app.init_resource::<Actions>()
+            .add_console_command("bind", "bind <action> <input>", "Binds an input, e.g. `Key(Z)`, to an action")
+            .add_console_command("unbind", "unbind <input>", "Unbinds an input from its action")
+            .add_system_to_stage(stage::PRE_UPDATE, update_actions.system())
+            .add_system(bind_command.system());
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Action {
+    PanLeft,
+    PanRight,
+    PanUp,
+    PanDown,
+    ZoomIn,
+    ZoomOut,
+    TurnLeft,
+    TurnRight,
+    Select,
+    /// Held while recalling a bookmark to save the current view into its slot instead
+    SaveBookmark,
+    Bookmark(u8),
+    TerrainMapMode,
+    PoliticalMapMode,
+    DiplomaticMapMode,
+    EconomicMapMode,
+    ToggleLodColours,
+    ToggleConsole,
+    ToggleDebugOverlay,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// Scrolling the mouse wheel. This is only read through [`Actions::scrolled`], as scrolling
+    /// comes in ticks rather than being held.
+    Wheel(AxisDirection),
+    GamepadButton(GamepadButtonType),
+    /// A gamepad stick or trigger pushed in one direction along its axis
+    GamepadAxis(GamepadAxisType, AxisDirection),
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AxisDirection {
+    Positive,
+    Negative,
+}
+
+/// The bindings of every action. Actions missing from the settings file keep their default
+/// bindings, unless another action has already been bound to them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(transparent)]
+pub struct InputBindings(pub BTreeMap<Action, Vec<Binding>>);
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        use Binding::{GamepadAxis as Axis, GamepadButton as Button, Key, Mouse, Wheel};
+        use AxisDirection::{Negative, Positive};
+
+        let mut bindings = BTreeMap::new();
+        let mut bind = |action, bound: &[Binding]| bindings.insert(action, bound.to_vec());
+
+        bind(Action::PanLeft, &[Key(KeyCode::Left), Key(KeyCode::A), Axis(GamepadAxisType::LeftStickX, Negative)]);
+        bind(Action::PanRight, &[Key(KeyCode::Right), Key(KeyCode::D), Axis(GamepadAxisType::LeftStickX, Positive)]);
+        bind(Action::PanUp, &[Key(KeyCode::Up), Key(KeyCode::W), Axis(GamepadAxisType::LeftStickY, Positive)]);
+        bind(Action::PanDown, &[Key(KeyCode::Down), Key(KeyCode::S), Axis(GamepadAxisType::LeftStickY, Negative)]);
+        bind(Action::ZoomIn, &[
+            Key(KeyCode::Equals),
+            Key(KeyCode::NumpadAdd),
+            Wheel(Positive),
+            Button(GamepadButtonType::RightTrigger2),
+        ]);
+        bind(Action::ZoomOut, &[
+            Key(KeyCode::Minus),
+            Key(KeyCode::NumpadSubtract),
+            Wheel(Negative),
+            Button(GamepadButtonType::LeftTrigger2),
+        ]);
+        bind(Action::TurnLeft, &[Key(KeyCode::Q), Axis(GamepadAxisType::RightStickX, Negative)]);
+        bind(Action::TurnRight, &[Key(KeyCode::E), Axis(GamepadAxisType::RightStickX, Positive)]);
+        bind(Action::Select, &[Mouse(MouseButton::Left), Button(GamepadButtonType::South)]);
+        bind(Action::SaveBookmark, &[Key(KeyCode::LControl), Key(KeyCode::RControl)]);
+        bind(Action::TerrainMapMode, &[Key(KeyCode::F5)]);
+        bind(Action::PoliticalMapMode, &[Key(KeyCode::F6)]);
+        bind(Action::DiplomaticMapMode, &[Key(KeyCode::F7)]);
+        bind(Action::EconomicMapMode, &[Key(KeyCode::F8)]);
+        bind(Action::ToggleLodColours, &[Key(KeyCode::F3)]);
+        bind(Action::ToggleConsole, &[Key(KeyCode::Grave)]);
+        bind(Action::ToggleDebugOverlay, &[Key(KeyCode::F12)]);
+
+        let number_keys = [
+            KeyCode::Key1,
+            KeyCode::Key2,
+            KeyCode::Key3,
+            KeyCode::Key4,
+            KeyCode::Key5,
+            KeyCode::Key6,
+            KeyCode::Key7,
+            KeyCode::Key8,
+            KeyCode::Key9,
+        ];
+        for (slot, &key) in (1..=BOOKMARK_SLOTS).zip(number_keys.iter()) {
+            bind(Action::Bookmark(slot), &[Key(key)]);
+        }
+
+        InputBindings(bindings)
+    }
+}
+
+impl InputBindings {
+    /// Gives actions missing from the bindings their defaults, and unbinds inputs bound to more
+    /// than one action from all but the first, returning the conflicts
+    pub fn validate(&mut self) -> Vec<String> {
+        let mut used: HashSet<Binding> = self.0.values().flatten().copied().collect();
+        for (action, defaults) in InputBindings::default().0 {
+            self.0.entry(action).or_insert_with(|| {
+                defaults.into_iter().filter(|binding| used.insert(*binding)).collect()
+            });
+        }
+
+        let mut problems = Vec::new();
+        let mut bound_to: HashMap<Binding, Action> = HashMap::default();
+
+        for (&action, bindings) in self.0.iter_mut() {
+            bindings.retain(|&binding| match bound_to.get(&binding) {
+                Some(first) => {
+                    problems.push(format!("{:?} is bound to both {:?} and {:?}, so it only does {:?}", binding, first, action, first));
+                    false
+                }
+                None => {
+                    bound_to.insert(binding, action);
+                    true
+                }
+            });
+        }
+
+        problems
+    }
+
+    /// Binds an input to an action, unbinding it from whichever action it was bound to before
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        self.unbind(binding);
+        self.0.entry(action).or_default().push(binding);
+    }
+
+    /// Unbinds an input, returning whether it was bound to anything
+    pub fn unbind(&mut self, binding: Binding) -> bool {
+        let mut unbound = false;
+        for bindings in self.0.values_mut() {
+            let len = bindings.len();
+            bindings.retain(|&bound| bound != binding);
+            unbound |= bindings.len() != len;
+        }
+
+        unbound
+    }
+}
+
+/// Which actions are being done this frame, from the input bound to them
+#[derive(Default)]
+pub struct Actions {
+    /// How strongly each action is held, from 0 to 1. Buttons and keys are 0 or 1, but sticks and
+    /// triggers can be partway.
+    held: HashMap<Action, f32>,
+    held_last_frame: HashMap<Action, f32>,
+    scrolled: HashMap<Action, f32>,
+    gamepads: Vec<Gamepad>,
+    /// Whether something else is taking keyboard input, such as the console
+    keyboard_captured: bool,
+}
+
+impl Actions {
+    /// How strongly the action is held, from 0 (not at all) to 1
+    pub fn value(&self, action: Action) -> f32 {
+        self.held.get(&action).copied().unwrap_or(0.0)
+    }
+
+    pub fn pressed(&self, action: Action) -> bool {
+        self.value(action) > 0.0
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.pressed(action) && self.held_last_frame.get(&action).copied().unwrap_or(0.0) == 0.0
+    }
+
+    /// How far the mouse wheel was scrolled towards the action this frame, in lines or pixels
+    pub fn scrolled(&self, action: Action) -> f32 {
+        self.scrolled.get(&action).copied().unwrap_or(0.0)
+    }
+
+    /// Holds an action this strongly, as if its input were held
+    #[cfg(test)]
+    pub fn hold(&mut self, action: Action, value: f32) {
+        self.held.insert(action, value);
+    }
+
+    /// Stops keys from doing actions while something else, such as the console, takes keyboard
+    /// input. The console can still be toggled.
+    pub fn capture_keyboard(&mut self, captured: bool) {
+        self.keyboard_captured = captured;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_actions(
+    mut actions: ResMut<Actions>,
+    settings: Res<Settings>,
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    (mut wheel_reader, wheel_events): (Local<EventReader<MouseWheel>>, Res<Events<MouseWheel>>),
+    (mut gamepad_reader, gamepad_events): (Local<EventReader<GamepadEvent>>, Res<Events<GamepadEvent>>),
+) {
+    let actions = &mut *actions;
+
+    for GamepadEvent(gamepad, event) in gamepad_reader.iter(&gamepad_events) {
+        match event {
+            GamepadEventType::Connected => actions.gamepads.push(*gamepad),
+            GamepadEventType::Disconnected => actions.gamepads.retain(|g| g != gamepad),
+            _ => {}
+        }
+    }
+
+    let scroll: f32 = wheel_reader.iter(&wheel_events).map(|event| event.y).sum();
+
+    std::mem::swap(&mut actions.held, &mut actions.held_last_frame);
+    actions.held.clear();
+    actions.scrolled.clear();
+
+    for (&action, bindings) in settings.input.0.iter() {
+        let keys_ignored = actions.keyboard_captured && action != Action::ToggleConsole;
+        let mut held = 0.0f32;
+        let mut scrolled = 0.0;
+
+        for binding in bindings {
+            let value = match *binding {
+                Binding::Key(_) if keys_ignored => 0.0,
+                Binding::Key(key) => keys.pressed(key) as u8 as f32,
+                Binding::Mouse(button) => mouse_buttons.pressed(button) as u8 as f32,
+                Binding::Wheel(direction) => {
+                    scrolled += direction.along(scroll);
+                    0.0
+                }
+                Binding::GamepadButton(button) => {
+                    let pressed = actions.gamepads.iter().any(|&g| gamepad_buttons.pressed(GamepadButton(g, button)));
+                    pressed as u8 as f32
+                }
+                Binding::GamepadAxis(axis, direction) => actions.gamepads
+                    .iter()
+                    .filter_map(|&g| gamepad_axes.get(GamepadAxis(g, axis)))
+                    .map(|value| direction.along(value))
+                    .filter(|&value| value > STICK_DEAD_ZONE)
+                    .fold(0.0, f32::max),
+            };
+            held = held.max(value.min(1.0));
+        }
+
+        if held > 0.0 {
+            actions.held.insert(action, held);
+        }
+        if scrolled > 0.0 {
+            actions.scrolled.insert(action, scrolled);
+        }
+    }
+}
+
+/// Runs the `bind` and `unbind` console commands, and saves the changed bindings. Actions and inputs
+/// are written as in the settings file, e.g. `bind ZoomIn GamepadAxis(RightStickY, Positive)`.
+fn bind_command(
+    mut reader: Local<EventReader<ConsoleCommand>>,
+    commands: Res<Events<ConsoleCommand>>,
+    mut console: ResMut<Console>,
+    mut settings: ResMut<Settings>,
+) {
+    for command in reader.iter(&commands) {
+        let (action, input) = match (command.name.as_str(), command.args.as_slice()) {
+            ("bind", [action, input @ ..]) if !input.is_empty() => (Some(action), input.join(" ")),
+            ("unbind", input) if !input.is_empty() => (None, input.join(" ")),
+            ("bind", _) => {
+                console.print("Usage: bind <action> <input>");
+                continue;
+            }
+            ("unbind", _) => {
+                console.print("Usage: unbind <input>");
+                continue;
+            }
+            _ => continue,
+        };
+
+        let binding: Binding = match ron::de::from_str(&input) {
+            Ok(binding) => binding,
+            Err(_) => {
+                console.print(format!("{} isn't an input, e.g. Key(Z), Mouse(Left) or GamepadButton(South)", input));
+                continue;
+            }
+        };
+
+        match action.map(|action| ron::de::from_str::<Action>(action).map_err(|_| action)) {
+            Some(Ok(action)) => settings.input.bind(action, binding),
+            Some(Err(action)) => {
+                console.print(format!("{} isn't an action, e.g. ZoomIn or Bookmark(1)", action));
+                continue;
+            }
+            None if settings.input.unbind(binding) => {}
+            None => {
+                console.print(format!("{:?} isn't bound to anything", binding));
+                continue;
+            }
+        }
+
+        if let Err(err) = settings.save() {
+            console.print(format!("Couldn't save the new bindings: {}", err));
+        }
+    }
+}
+
+impl AxisDirection {
+    /// How far a value is in this direction, or 0 if it is in the other direction
+    fn along(self, value: f32) -> f32 {
+        match self {
+            AxisDirection::Positive => value.max(0.0),
+            AxisDirection::Negative => (-value).max(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicting_bindings_are_only_kept_for_the_first_action() {
+        let mut bindings = InputBindings::default();
+        bindings.0.insert(Action::PanLeft, vec![Binding::Key(KeyCode::F5), Binding::Key(KeyCode::A)]);
+        bindings.0.insert(Action::TerrainMapMode, vec![Binding::Key(KeyCode::F5)]);
+        bindings.0.remove(&Action::ZoomIn);
+        bindings.0.insert(Action::ZoomOut, vec![Binding::Wheel(AxisDirection::Positive)]);
+
+        let problems = bindings.validate();
+
+        assert_eq!(problems.len(), 1);
+        assert_eq!(bindings.0[&Action::TerrainMapMode], vec![]);
+        // Missing actions get their defaults, except for ones already bound elsewhere
+        assert!(!bindings.0[&Action::ZoomIn].contains(&Binding::Wheel(AxisDirection::Positive)));
+        assert!(bindings.0[&Action::ZoomIn].contains(&Binding::Key(KeyCode::Equals)));
+    }
+
+    #[test]
+    fn binding_an_input_unbinds_it_from_its_old_action() {
+        let mut bindings = InputBindings::default();
+        bindings.bind(Action::ZoomIn, Binding::Key(KeyCode::W));
+
+        assert!(bindings.0[&Action::ZoomIn].contains(&Binding::Key(KeyCode::W)));
+        assert!(!bindings.0[&Action::PanUp].contains(&Binding::Key(KeyCode::W)));
+        assert!(bindings.validate().is_empty());
+
+        assert!(bindings.unbind(Binding::Key(KeyCode::W)));
+        assert!(!bindings.unbind(Binding::Key(KeyCode::W)));
+    }
+}
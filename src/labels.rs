@@ -0,0 +1,551 @@
+//! Names of places on the map (cities, regions and seas), drawn over the map at their positions.
+//! Regions and seas are listed in a `.labels` file, while cities are the settlements of the
+//! [settlements gazetteer](crate::settlements::Gazetteer). Labels of large areas can be curved
+//! along a path across the area, with each letter turned to follow it. Bevy can't rotate UI text, so
+//! each letter is rasterised from the font once and drawn as a rotated UI node. Labels follow the
+//! terrain as it is drawn, and are sized and faded by how far out the camera is zoomed, and less
+//! important labels are hidden where they would overlap more important ones.
+use ab_glyph::{point, Font as _, FontArc, ScaleFont as _};
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::camera::Camera;
+use bevy::render::texture::{Extent3d, FilterMode, TextureDimension, TextureFormat};
+use bevy::utils::{BoxedFuture, HashMap};
+use serde::Deserialize;
+use std::ops::RangeInclusive;
+use crate::loading::progress::LoadingProgress;
+use crate::map::LatLong;
+use crate::map::paper::PaperMap;
+use crate::map::terrain::Terrain;
+use crate::settlements::Gazetteer;
+use crate::{AppState, RomeAssets, STATE_STAGE};
+
+/// The font is monospaced, so every glyph is this many ems wide
+const GLYPH_ADVANCE: f32 = 0.6;
+/// Zoom distance over which labels fade in and out at the ends of their zoom range
+const FADE_DISTANCE: f32 = 50.0;
+/// Extra space around each label that other labels can't be placed in, in pixels
+const DECLUTTER_MARGIN: f32 = 4.0;
+/// Labels float this far above the terrain, in world units
+const LABEL_HEIGHT: f32 = 1.0;
+/// Glyphs are rasterised at this many pixels per em, and scaled to each label's font size
+const RASTER_SIZE: f32 = 64.0;
+
+pub struct LabelsPlugin;
+
+impl Plugin for LabelsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_asset::<LabelGazetteer>()
+            .init_asset_loader::<LabelGazetteerLoader>()
+            .add_startup_system(load_labels.system())
+            .on_state_update(STATE_STAGE, AppState::Loading, check_labels_loaded.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, spawn_labels.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, layout_labels.system());
+    }
+}
+
+/// Regions and seas to label, loaded from a `.labels` file
+#[derive(Deserialize, TypeUuid, Debug)]
+#[uuid = "c1b7a0f2-5e3d-4a8b-9f61-2d7e4c0b8a93"]
+pub struct LabelGazetteer {
+    pub places: Vec<PlaceLabel>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlaceLabel {
+    pub name: String,
+    pub kind: LabelKind,
+    /// Where the label is centred, if it has no path
+    pub location: LatLong,
+    /// Points that the label's letters are spread along, from its first letter to its last
+    #[serde(default)]
+    pub path: Vec<LatLong>,
+    /// Labels with higher importance are kept when labels of the same kind overlap
+    #[serde(default = "PlaceLabel::default_importance")]
+    pub importance: f32,
+}
+
+impl PlaceLabel {
+    fn default_importance() -> f32 {
+        1.0
+    }
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LabelKind {
+    City,
+    Region,
+    Sea,
+}
+
+impl LabelKind {
+    /// Zoom distances that labels of this kind are shown at
+    fn zoom_range(self) -> RangeInclusive<f32> {
+        match self {
+            LabelKind::City => 75.0..=450.0,
+            LabelKind::Region => 250.0..=1200.0,
+            LabelKind::Sea => 300.0..=1200.0,
+        }
+    }
+
+    /// Font size at the given zoom distance. Areas are scaled with the map, within limits, while
+    /// cities stay about the same size on screen.
+    fn font_size(self, zoom_distance: f32) -> f32 {
+        match self {
+            LabelKind::City => 18.0 * (150.0 / zoom_distance).clamp(0.8, 1.2),
+            LabelKind::Region => 28.0 * (600.0 / zoom_distance).clamp(0.6, 1.6),
+            LabelKind::Sea => 24.0 * (600.0 / zoom_distance).clamp(0.6, 1.6),
+        }
+    }
+
+    /// Gap between the starts of letters, in ems
+    fn letter_spacing(self) -> f32 {
+        match self {
+            LabelKind::City => GLYPH_ADVANCE,
+            LabelKind::Region | LabelKind::Sea => GLYPH_ADVANCE * 1.6,
+        }
+    }
+
+    fn colour(self) -> Color {
+        match self {
+            LabelKind::City => Color::rgb(0.12, 0.08, 0.05),
+            LabelKind::Region => Color::rgb(0.35, 0.12, 0.08),
+            LabelKind::Sea => Color::rgb(0.1, 0.2, 0.4),
+        }
+    }
+
+    /// Labels with a higher priority are placed first when decluttering
+    fn priority(self) -> u8 {
+        match self {
+            LabelKind::City => 2,
+            LabelKind::Region => 1,
+            LabelKind::Sea => 0,
+        }
+    }
+}
+
+/// A label on the map. Each of its letters is a separate UI node so that they can be placed and
+/// turned along a path.
+pub struct Label {
+    pub kind: LabelKind,
+    pub importance: f32,
+    /// World space x and z of the points that the label is laid along. They are raised onto the
+    /// terrain as it is drawn each frame. A label with one point is laid out straight.
+    pub path: Vec<Vec2>,
+    glyphs: Vec<Entity>,
+}
+
+struct LabelAssets {
+    gazetteer: Handle<LabelGazetteer>,
+    font: Handle<Font>,
+    /// Set once the labels are spawned
+    glyphs: Option<GlyphMaterials>,
+}
+
+/// Letters rasterised from the label font, each into a cell of the same size, and a material for
+/// each letter in the colour of each kind of label
+struct GlyphMaterials {
+    font: FontArc,
+    /// Width and height of each glyph's texture, in pixels
+    cell: Vec2,
+    textures: HashMap<char, Handle<Texture>>,
+    materials: HashMap<(LabelKind, char), Handle<ColorMaterial>>,
+}
+
+impl GlyphMaterials {
+    fn new(font: FontArc) -> GlyphMaterials {
+        let scaled = font.as_scaled(RASTER_SIZE);
+        let cell = Vec2::new(GLYPH_ADVANCE * RASTER_SIZE, scaled.ascent() - scaled.descent()).ceil();
+        GlyphMaterials { font, cell, textures: HashMap::default(), materials: HashMap::default() }
+    }
+
+    /// Size of a glyph's node at a font size, in pixels
+    fn size(&self, font_size: f32) -> Vec2 {
+        self.cell * (font_size / RASTER_SIZE)
+    }
+
+    /// Material of a letter in a kind of label's colour, rasterising the letter if it is new
+    fn material(
+        &mut self,
+        kind: LabelKind,
+        letter: char,
+        textures: &mut Assets<Texture>,
+        materials: &mut Assets<ColorMaterial>,
+    ) -> Handle<ColorMaterial> {
+        let (font, cell) = (&self.font, self.cell);
+        let texture = self
+            .textures
+            .entry(letter)
+            .or_insert_with(|| textures.add(rasterise(font, letter, cell)));
+
+        self.materials
+            .entry((kind, letter))
+            .or_insert_with(|| materials.add(ColorMaterial::modulated_texture(texture.clone(), kind.colour())))
+            .clone()
+    }
+}
+
+/// A white letter in the middle of a transparent cell, with its baseline at the font's ascent
+fn rasterise(font: &FontArc, letter: char, cell: Vec2) -> Texture {
+    let scaled = font.as_scaled(RASTER_SIZE);
+    let (width, height) = (cell.x as usize, cell.y as usize);
+    let mut data = [u8::MAX, u8::MAX, u8::MAX, 0].repeat(width * height);
+
+    let id = font.glyph_id(letter);
+    let origin = point((cell.x - scaled.h_advance(id)) / 2.0, scaled.ascent());
+    let glyph = id.with_scale_and_position(RASTER_SIZE, origin);
+    if let Some(outline) = font.outline_glyph(glyph) {
+        let bounds = outline.px_bounds();
+        outline.draw(|x, y, coverage| {
+            let (x, y) = (bounds.min.x as i32 + x as i32, bounds.min.y as i32 + y as i32);
+            if (0..width as i32).contains(&x) && (0..height as i32).contains(&y) {
+                data[(y as usize * width + x as usize) * 4 + 3] = (coverage * u8::MAX as f32).round() as u8;
+            }
+        });
+    }
+
+    let size = Extent3d::new(width as u32, height as u32, 1);
+    let mut texture = Texture::new(size, TextureDimension::D2, data, TextureFormat::Rgba8UnormSrgb);
+    texture.sampler.mag_filter = FilterMode::Linear;
+    texture
+}
+
+fn load_labels(commands: &mut Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(LabelAssets {
+        gazetteer: asset_server.load("map/gazetteer.labels"),
+        font: asset_server.load("fonts/FiraMono-Medium.ttf"),
+        glyphs: None,
+    });
+}
+
+/// Fails loading if the labels or their font can't be loaded
+fn check_labels_loaded(asset_server: Res<AssetServer>, assets: Res<LabelAssets>, progress: Res<LoadingProgress>) {
+    progress.check_load_states(&asset_server, [assets.gazetteer.id, assets.font.id].iter().copied());
+}
+
+/// Spawns a label's glyphs, hidden until it is laid out
+fn spawn_label(
+    commands: &mut Commands,
+    glyph_materials: &mut GlyphMaterials,
+    (textures, materials): (&mut Assets<Texture>, &mut Assets<ColorMaterial>),
+    name: &str,
+    kind: LabelKind,
+    importance: f32,
+    path: Vec<Vec2>,
+) {
+    let glyphs = name
+        .chars()
+        .map(|c| {
+            commands
+                .spawn(NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        ..Default::default()
+                    },
+                    material: glyph_materials.material(kind, c, textures, materials),
+                    visible: Visible { is_visible: false, is_transparent: true },
+                    ..Default::default()
+                })
+                .current_entity()
+                .unwrap()
+        })
+        .collect();
+
+    commands.spawn((Label { kind, importance, path, glyphs },));
+}
+
+/// Spawns the labels of the places in the label gazetteer, and of every settlement by its Latin name
+#[allow(clippy::too_many_arguments)]
+fn spawn_labels(
+    commands: &mut Commands,
+    gazetteers: Res<Assets<LabelGazetteer>>,
+    rome_assets: Res<RomeAssets>,
+    settlements: Res<Assets<Gazetteer>>,
+    fonts: Res<Assets<Font>>,
+    mut textures: ResMut<Assets<Texture>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut assets: ResMut<LabelAssets>,
+) {
+    let (gazetteer, settlements, font) = match (
+        gazetteers.get(&assets.gazetteer),
+        settlements.get(&rome_assets.gazetteer),
+        fonts.get(&assets.font),
+    ) {
+        (Some(gazetteer), Some(settlements), Some(font)) if assets.glyphs.is_none() => (gazetteer, settlements, font),
+        _ => return,
+    };
+
+    let mut glyph_materials = GlyphMaterials::new(font.font.clone());
+    let to_map = |location: LatLong| location.to_tile_coord().to_world_space();
+
+    for place in &gazetteer.places {
+        let path = if place.path.is_empty() {
+            vec![to_map(place.location)]
+        } else {
+            place.path.iter().map(|&location| to_map(location)).collect()
+        };
+
+        let assets = (&mut *textures, &mut *materials);
+        spawn_label(commands, &mut glyph_materials, assets, &place.name, place.kind, place.importance, path);
+    }
+
+    for settlement in &settlements.settlements {
+        let path = vec![to_map(settlement.location)];
+        let assets = (&mut *textures, &mut *materials);
+        let importance = settlement.population as f32;
+        spawn_label(commands, &mut glyph_materials, assets, &settlement.latin_name, LabelKind::City, importance, path);
+    }
+
+    assets.glyphs = Some(glyph_materials);
+}
+
+/// How visible labels of a kind are at a zoom distance, from 0 to 1
+fn zoom_fade(kind: LabelKind, zoom_distance: f32) -> f32 {
+    let range = kind.zoom_range();
+    let fade_in = ((zoom_distance - range.start()) / FADE_DISTANCE + 0.5).clamp(0.0, 1.0);
+    let fade_out = ((range.end() - zoom_distance) / FADE_DISTANCE + 0.5).clamp(0.0, 1.0);
+    fade_in.min(fade_out)
+}
+
+/// Point at a distance along a polyline, continuing straight past its ends
+fn point_along(points: &[Vec2], distance: f32) -> Vec2 {
+    let mut remaining = distance;
+
+    for (i, segment) in points.windows(2).enumerate() {
+        let length = (segment[1] - segment[0]).length();
+        let is_last = i == points.len() - 2;
+
+        if (remaining <= length || is_last) && length > 0.0 {
+            return segment[0] + (segment[1] - segment[0]) * (remaining / length);
+        }
+
+        remaining -= length;
+    }
+
+    points[0]
+}
+
+/// Screen space centre of each glyph of a label laid along the given screen space points, and the
+/// angle that it is turned anticlockwise by to follow them. A path running from right to left is
+/// laid out from its end, so that the label doesn't read upside down.
+fn glyph_positions(points: &[Vec2], glyphs: usize, spacing: f32) -> Vec<(Vec2, f32)> {
+    let width = spacing * glyphs.saturating_sub(1) as f32;
+
+    if points.len() == 1 {
+        return (0..glyphs)
+            .map(|i| (points[0] + Vec2::new(i as f32 * spacing - width / 2.0, 0.0), 0.0))
+            .collect();
+    }
+
+    let mut points = points.to_vec();
+    if points[points.len() - 1].x < points[0].x {
+        points.reverse();
+    }
+
+    let length: f32 = points.windows(2).map(|s| (s[1] - s[0]).length()).sum();
+    let start = (length - width) / 2.0;
+    (0..glyphs)
+        .map(|i| {
+            let distance = start + i as f32 * spacing;
+            // Each glyph follows the line between its edges, which smooths over corners of the path
+            let (behind, ahead) = (distance - spacing / 2.0, distance + spacing / 2.0);
+            let tangent = point_along(&points, ahead) - point_along(&points, behind);
+            (point_along(&points, distance), tangent.y.atan2(tangent.x))
+        })
+        .collect()
+}
+
+struct ScreenRect {
+    min: Vec2,
+    max: Vec2,
+}
+
+impl ScreenRect {
+    fn overlaps(&self, other: &ScreenRect) -> bool {
+        self.min.x < other.max.x && other.min.x < self.max.x && self.min.y < other.max.y && other.min.y < self.max.y
+    }
+}
+
+/// Places each label's glyphs on screen every frame, and fades them with the zoom
+#[allow(clippy::too_many_arguments)]
+fn layout_labels(
+    windows: Res<Windows>,
+    terrain: Res<Terrain>,
+    paper_map: Res<PaperMap>,
+    assets: Res<LabelAssets>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    cameras: Query<(&Camera, &GlobalTransform, &goshawk::RtsCamera)>,
+    labels: Query<&Label>,
+    mut glyphs: Query<(&mut Style, &mut Transform, &mut Visible)>,
+) {
+    let glyph_materials = match &assets.glyphs {
+        Some(glyph_materials) => glyph_materials,
+        None => return,
+    };
+    let (camera, camera_transform, rts_camera) = match cameras.iter().next() {
+        Some(camera) => camera,
+        None => return,
+    };
+    let window = match windows.get(camera.window) {
+        Some(window) => window,
+        None => return,
+    };
+
+    let screen_size = Vec2::new(window.width(), window.height());
+    let view_proj = camera.projection_matrix * camera_transform.compute_matrix().inverse();
+    let to_screen = |world: Vec3| -> Option<Vec2> {
+        let clip = view_proj * world.extend(1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+
+        let ndc = clip.truncate() / clip.w;
+        Some((Vec2::new(ndc.x, ndc.y) * 0.5 + Vec2::splat(0.5)) * screen_size)
+    };
+
+    // Every label of a kind fades together, so their materials are shared. They are only written
+    // when the camera has zoomed, as writing them uploads them again.
+    for ((kind, _), material) in &glyph_materials.materials {
+        let fade = zoom_fade(*kind, rts_camera.zoom_distance);
+        if materials.get(material).is_some_and(|material| material.color.a() != fade) {
+            materials.get_mut(material).unwrap().color.set_a(fade);
+        }
+    }
+
+    // Labels sit on the terrain as it is drawn, which is flattened as it fades into the paper map
+    let centre = rts_camera.looking_at;
+    let on_terrain = |p: Vec2| Vec3::new(p.x, terrain.height_at(p, centre, &paper_map) + LABEL_HEIGHT, p.y);
+
+    let mut ordered: Vec<&Label> = labels.iter().collect();
+    ordered.sort_by(|a, b| {
+        b.kind.priority()
+            .cmp(&a.kind.priority())
+            .then(b.importance.partial_cmp(&a.importance).unwrap_or(std::cmp::Ordering::Equal))
+    });
+
+    let mut placed: Vec<ScreenRect> = Vec::new();
+
+    for label in ordered {
+        let fade = zoom_fade(label.kind, rts_camera.zoom_distance);
+        let font_size = label.kind.font_size(rts_camera.zoom_distance);
+        let glyph_size = glyph_materials.size(font_size);
+        let points: Option<Vec<Vec2>> = if fade > 0.0 {
+            label.path.iter().map(|&p| to_screen(on_terrain(p))).collect()
+        } else {
+            None
+        };
+
+        let spacing = font_size * label.kind.letter_spacing();
+        let positions = points.map(|points| glyph_positions(&points, label.glyphs.len(), spacing));
+
+        // Find the label's bounds, and hide it if it is off screen or overlaps one already placed.
+        // Glyphs can be turned either way, so each one is given room to turn.
+        let positions = positions.filter(|positions| {
+            let half_glyph = Vec2::splat(glyph_size.max_element() / 2.0 + DECLUTTER_MARGIN);
+            let min = positions.iter().fold(Vec2::splat(f32::MAX), |min, (p, _)| min.min(*p)) - half_glyph;
+            let max = positions.iter().fold(Vec2::splat(f32::MIN), |max, (p, _)| max.max(*p)) + half_glyph;
+            let rect = ScreenRect { min, max };
+
+            let on_screen = max.x > 0.0 && max.y > 0.0 && min.x < screen_size.x && min.y < screen_size.y;
+            if !on_screen || placed.iter().any(|other| other.overlaps(&rect)) {
+                return false;
+            }
+
+            placed.push(rect);
+            true
+        });
+
+        for (i, &glyph) in label.glyphs.iter().enumerate() {
+            let (mut style, mut transform, mut visible) = match glyphs.get_mut(glyph) {
+                Ok(glyph) => glyph,
+                Err(_) => continue,
+            };
+
+            match &positions {
+                Some(positions) => {
+                    // Nodes are turned about their centres, and the layout only moves them
+                    let (position, angle) = positions[i];
+                    style.size = Size::new(Val::Px(glyph_size.x), Val::Px(glyph_size.y));
+                    style.position.left = Val::Px(position.x - glyph_size.x / 2.0);
+                    style.position.bottom = Val::Px(position.y - glyph_size.y / 2.0);
+                    transform.rotation = Quat::from_rotation_z(angle);
+                    visible.is_visible = true;
+                }
+                None => visible.is_visible = false,
+            }
+        }
+    }
+}
+
+pub struct LabelGazetteerLoader {
+    progress: LoadingProgress,
+}
+
+impl FromResources for LabelGazetteerLoader {
+    fn from_resources(resources: &Resources) -> Self {
+        let progress = resources.get::<LoadingProgress>().expect("LoadRomeAssets must be added first");
+        LabelGazetteerLoader { progress: (*progress).clone() }
+    }
+}
+
+impl AssetLoader for LabelGazetteerLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], ctx: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let gazetteer: LabelGazetteer = self.progress.report(ctx, ron::de::from_bytes(bytes).map_err(anyhow::Error::from))?;
+            ctx.set_default_asset(LoadedAsset::new(gazetteer));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["labels"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_gazetteer_parses() {
+        let gazetteer: LabelGazetteer = ron::de::from_str(include_str!("../assets/map/gazetteer.labels")).unwrap();
+        assert!(gazetteer.places.iter().any(|place| place.name == "ITALIA"));
+    }
+
+    #[test]
+    fn glyphs_are_centred_on_path() {
+        let points = [Vec2::new(0.0, 0.0), Vec2::new(100.0, 0.0)];
+        let positions = glyph_positions(&points, 3, 10.0);
+
+        let expected = [Vec2::new(40.0, 0.0), Vec2::new(50.0, 0.0), Vec2::new(60.0, 0.0)];
+        assert_eq!(positions.len(), expected.len());
+        for ((position, angle), expected) in positions.iter().zip(&expected) {
+            assert!((*position - *expected).length() < 1.0e-3, "{:?} != {:?}", position, expected);
+            assert!(angle.abs() < 1.0e-3);
+        }
+    }
+
+    #[test]
+    fn glyphs_turn_along_path() {
+        let points = [Vec2::new(0.0, 0.0), Vec2::new(50.0, 50.0), Vec2::new(100.0, 0.0)];
+        let positions = glyph_positions(&points, 5, 20.0);
+
+        // Rising, then level over the corner, then falling
+        let angles: Vec<f32> = positions.iter().map(|&(_, angle)| angle.to_degrees()).collect();
+        assert!((angles[0] - 45.0).abs() < 1.0e-3, "{:?}", angles);
+        assert!(angles[2].abs() < 1.0e-3, "{:?}", angles);
+        assert!((angles[4] + 45.0).abs() < 1.0e-3, "{:?}", angles);
+    }
+
+    #[test]
+    fn glyphs_read_left_to_right() {
+        let points = [Vec2::new(100.0, 10.0), Vec2::new(0.0, 0.0)];
+        let positions = glyph_positions(&points, 3, 10.0);
+
+        assert!(positions[0].0.x < positions[2].0.x);
+        for (_, angle) in positions {
+            assert!(angle.abs() < 90.0f32.to_radians());
+        }
+    }
+}
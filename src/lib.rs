@@ -0,0 +1,47 @@
+use bevy::asset::{AssetServerSettings, FileAssetIo};
+use bevy::prelude::*;
+use std::path::PathBuf;
+use crate::map::overlay::PoliticalMap;
+use crate::map::shader::MapMaterial;
+use crate::map::sky::SkyMaterial;
+use crate::settlements::Gazetteer;
+
+pub mod camera;
+pub mod console;
+pub mod loading;
+pub mod map;
+pub mod minimap;
+pub mod game_time;
+pub mod input;
+pub mod labels;
+pub mod settlements;
+pub mod settings;
+
+pub const STATE_STAGE: &str = "rome_app_state_stage";
+
+#[derive(Clone)]
+pub enum AppState {
+    Loading,
+    /// An asset couldn't be loaded, so the game can't start
+    LoadingFailed,
+    InGame,
+}
+
+/// Folder that the asset server loads assets from. Files that are read without the asset server are
+/// looked for in it too, so that they are found however the game is run.
+pub fn asset_folder(resources: &Resources) -> PathBuf {
+    let folder = match resources.get::<AssetServerSettings>() {
+        Some(settings) => settings.asset_folder.clone(),
+        None => AssetServerSettings::default().asset_folder,
+    };
+
+    FileAssetIo::get_root_path().join(folder)
+}
+
+pub struct RomeAssets {
+    pub map_material: Handle<MapMaterial>,
+    pub clipmap_mesh: Handle<Mesh>,
+    pub sky_material: Handle<SkyMaterial>,
+    pub gazetteer: Handle<Gazetteer>,
+    pub political_map: Handle<PoliticalMap>,
+}
@@ -1,151 +1,342 @@
-use crate::map::shader::{MapMaterial};
-use crate::map::HeightMap;
-use crate::map::{mesh::build_mesh, HeightMapLoader};
+use crate::map::shader::{self, MapMaterial};
+use crate::map::{HeightMap, HeightEncoding, HeightmapPath};
+use crate::map::lighting::Sun;
+use crate::map::mesh::{build_mesh, ClipmapConfig};
 use crate::{AppState, RomeAssets, STATE_STAGE};
 use bevy::prelude::*;
-use bevy::render::texture::{AddressMode, SamplerDescriptor, FilterMode};
-use bevy::tasks::AsyncComputeTaskPool;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use futures_lite::future;
 use crate::map::mipmap::generate_mipmaps;
-use std::time::Instant;
+use crate::map::water;
+use crate::map::sky::{self, SkyMaterial};
+use bevy::render::pipeline::PipelineDescriptor;
+use crate::map::XYZ_SCALE;
+use crate::map::overlay::{PoliticalMap, PoliticalMapLoader, OWNERSHIP_SCALE};
+use crate::map::splat::{self, TerrainLayers, TerrainLayersLoader};
+use crate::map::terrain::Terrain;
+use crate::settlements::Gazetteer;
+use crate::minimap::Minimap;
+use crate::console::Console;
+use std::sync::Arc;
+use progress::{LoadingProgress, LoadingStage};
+
+pub mod progress;
+mod reload;
+mod screen;
 
 pub struct LoadRomeAssets;
 
 impl Plugin for LoadRomeAssets {
     fn build(&self, app: &mut AppBuilder) {
-        let task_pool = (*app.resources().get::<AsyncComputeTaskPool>().unwrap()).clone();
+        let progress = LoadingProgress::default();
+        let heightmap_path = HeightmapPath::new(&crate::asset_folder(app.resources()));
 
         app
+            .add_resource(heightmap_path)
             .add_resource(LoadingAssets::default())
-            .add_asset_loader(HeightMapLoader { task_pool })
+            .add_resource(progress.clone())
+            .add_asset::<TerrainLayers>()
+            .add_asset_loader(TerrainLayersLoader { progress: progress.clone() })
+            .add_asset::<PoliticalMap>()
+            .add_asset_loader(PoliticalMapLoader { progress })
             .add_startup_system(queue_asset_load.system())
-            .on_state_update(STATE_STAGE, AppState::Loading, loading.system());
+            .add_startup_system(screen::spawn_loading_screen.system())
+            .on_state_update(STATE_STAGE, AppState::Loading, loading.system())
+            .on_state_update(STATE_STAGE, AppState::Loading, screen::update_loading_screen.system())
+            .on_state_exit(STATE_STAGE, AppState::Loading, screen::despawn_loading_screen.system())
+            .on_state_enter(STATE_STAGE, AppState::LoadingFailed, screen::spawn_error_screen.system())
+            .on_state_enter(STATE_STAGE, AppState::InGame, report_timings.system())
+            .on_state_enter(STATE_STAGE, AppState::InGame, reload::start_watching.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, reload::watch_heightmap.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, reload::swap_in_heightmap.system());
     }
 }
 
-/// Assets loading from disk
+/// Assets loading from disk, and the work being done on them in the background
 #[derive(Default)]
 struct LoadingAssets {
-    forest: Option<Handle<Texture>>,
-    sand: Option<Handle<Texture>>,
-    heightmap: Option<Handle<Texture>>,
-    raw_heightmap: Option<(Handle<HeightMap>, u16)>,
+    terrain_layers: Option<Handle<TerrainLayers>>,
+    /// Textures of each terrain layer, in order, once the layers have loaded
+    layer_textures: Vec<Handle<Texture>>,
+    layer_atlas: Loading<Result<Texture, anyhow::Error>, Handle<Texture>>,
+    built_terrain: Loading<Result<BuiltTerrain, anyhow::Error>, BuiltTerrain>,
+    clipmap_mesh: Loading<Mesh, Handle<Mesh>>,
+    gazetteer: Option<Handle<Gazetteer>>,
+    political_map: Option<Handle<PoliticalMap>>,
 }
 
-/// Assets loaded from disk
-struct LoadedAssets {
-    forest: Handle<Texture>,
-    sand: Handle<Texture>,
-    heightmap: Handle<Texture>,
-    raw_heightmap: Handle<HeightMap>,
-    max_y: u16,
+/// Something that is built by a task on the [`AsyncComputeTaskPool`]
+#[derive(Default)]
+enum Loading<T, R> {
+    #[default]
+    NotStarted,
+    Running(Task<T>),
+    Done(R),
 }
 
-impl LoadingAssets {
-    fn all_loaded(&self) -> Option<LoadedAssets> {
-        match (
-            self.forest.as_ref(),
-            self.sand.as_ref(),
-            self.heightmap.as_ref(),
-            self.raw_heightmap.as_ref(),
-        ) {
-            (Some(forest), Some(sand), Some(heightmap), Some(raw_heightmap)) => Some(LoadedAssets {
-                forest: Handle::clone(forest),
-                sand: Handle::clone(sand),
-                heightmap: Handle::clone(heightmap),
-                raw_heightmap: Handle::clone(&raw_heightmap.0),
-                max_y: raw_heightmap.1,
-            }),
+impl<T, R> Loading<T, R> {
+    fn not_started(&self) -> bool {
+        matches!(self, Loading::NotStarted)
+    }
+
+    /// Output of the task if it has just finished, without blocking
+    fn poll(&mut self) -> Option<T> {
+        match self {
+            Loading::Running(task) => future::block_on(future::poll_once(task)),
+            _ => None,
+        }
+    }
+
+    fn done(&self) -> Option<&R> {
+        match self {
+            Loading::Done(result) => Some(result),
             _ => None,
         }
     }
 }
 
-fn queue_asset_load(asset_server: Res<AssetServer>) {
+/// Everything that is built from the heightmap. The heightmap itself is only needed while these are
+/// built, so it isn't kept.
+struct BuiltTerrain {
+    /// Width and height of the heightmap
+    size: (usize, usize),
+    heightmap: Texture,
+    height_encoding: HeightEncoding,
+    mipmap: Texture,
+    water: Texture,
+    terrain: Terrain,
+    minimap: Minimap,
+}
+
+impl BuiltTerrain {
+    /// Memory maps the heightmap and builds its textures and data, each part in its own task so
+    /// that they are built in parallel
+    async fn build(
+        task_pool: AsyncComputeTaskPool,
+        path: HeightmapPath,
+        clipmap_config: ClipmapConfig,
+        progress: LoadingProgress,
+    ) -> Result<BuiltTerrain, anyhow::Error> {
+        let map = Arc::new(HeightMap::open_or_convert(&path.0)?);
+        let size = (map.view().width, map.view().height);
+        // This is the first pass over the whole map, so it is when it is read from disk
+        let height_encoding = progress.time("Reading heightmap", || map.height_encoding());
+        progress.set(LoadingStage::ReadingMap, 1.0);
+
+        let heightmap = task_pool.spawn({
+            let (map, progress) = (map.clone(), progress.clone());
+            async move {
+                let texture = progress.time("Generating heightmap texture", || map.to_texture(height_encoding));
+                progress.add(LoadingStage::BuildingTextures, 0.25);
+                texture
+            }
+        });
+
+        let mipmap = task_pool.spawn({
+            let (map, progress) = (map.clone(), progress.clone());
+            async move {
+                let mipmaps = progress.time("Generating mipmap", || generate_mipmaps(map.view(), 1));
+                let mipmap = &mipmaps[0];
+                progress.set(LoadingStage::GeneratingMipmaps, 1.0);
+                let terrain = Terrain::new(map.view(), mipmap, height_encoding, clipmap_config);
+                let texture = progress.time("Converting mipmap to texture", || mipmap.to_texture(height_encoding));
+                (terrain, texture)
+            }
+        });
+
+        let water = task_pool.spawn({
+            let (map, progress) = (map.clone(), progress.clone());
+            async move { progress.time("Generating water texture", || water::to_texture(map.view())) }
+        });
+
+        let minimap = task_pool.spawn({
+            let (map, progress) = (map.clone(), progress.clone());
+            async move { progress.time("Drawing minimap", || Minimap::new(&map)) }
+        });
+
+        let heightmap = heightmap.await;
+        let (terrain, mipmap) = mipmap.await;
+        let water = water.await;
+        progress.add(LoadingStage::BuildingTextures, 0.25);
+
+        Ok(BuiltTerrain {
+            size,
+            heightmap,
+            height_encoding,
+            mipmap,
+            water,
+            terrain,
+            minimap: minimap.await,
+        })
+    }
+}
+
+fn queue_asset_load(asset_server: Res<AssetServer>, mut loading: ResMut<LoadingAssets>) {
     asset_server.watch_for_changes().unwrap();
-    asset_server.load_folder("map/heightmap").unwrap();
-    asset_server.load_folder("map/textures").unwrap();
+    loading.terrain_layers = Some(asset_server.load("map/terrain.layers"));
+    loading.gazetteer = Some(asset_server.load("map/world.settlements"));
+    loading.political_map = Some(asset_server.load("map/world.realms"));
 }
 
+/// Starts building what is needed from each asset once it has loaded, and starts the game once
+/// everything has been built
+#[allow(clippy::too_many_arguments)]
 fn loading(
     commands: &mut Commands,
     mut textures: ResMut<Assets<Texture>>,
-    mut heightmaps: ResMut<Assets<HeightMap>>,
+    terrain_layers: Res<Assets<TerrainLayers>>,
+    gazetteers: Res<Assets<Gazetteer>>,
     mut materials: ResMut<Assets<MapMaterial>>,
+    mut sky_materials: ResMut<Assets<SkyMaterial>>,
     mut loading: ResMut<LoadingAssets>,
     mut state: ResMut<State<AppState>>,
     mut meshes: ResMut<Assets<Mesh>>,
     asset_server: Res<AssetServer>,
+    (task_pool, heightmap_path): (Res<AsyncComputeTaskPool>, Res<HeightmapPath>),
+    clipmap_config: Res<ClipmapConfig>,
+    sun: Res<Sun>,
+    political_maps: Res<Assets<PoliticalMap>>,
+    progress: Res<LoadingProgress>,
+    (pipelines, shaders): (Res<Assets<PipelineDescriptor>>, Res<Assets<Shader>>),
 ) {
-    fn setup_texture(s: &mut SamplerDescriptor) {
-        s.address_mode_u = AddressMode::Repeat;
-        s.address_mode_v = AddressMode::Repeat;
-        s.address_mode_w = AddressMode::Repeat;
-        s.mipmap_filter = FilterMode::Linear;
+    if progress.error().is_some() {
+        state.set_next(AppState::LoadingFailed).unwrap();
+        return;
     }
 
-    let sand_handle = asset_server.load::<Texture, &str>("map/textures/beach_sand.png");
-    if let Some(tx) = textures
-        .get_mut(&sand_handle)
-        .filter(|_| loading.sand.is_none())
-    {
-        setup_texture(&mut tx.sampler);
-        let tx = tx.clone();
-        // TODO: For some reason this is required, else the texture will be dropped early (???)
-        loading.sand = Some(textures.add(tx));
+    let handles = loading.terrain_layers.iter().map(|handle| handle.id)
+        .chain(loading.layer_textures.iter().map(|handle| handle.id))
+        .chain(loading.gazetteer.iter().map(|handle| handle.id))
+        .chain(loading.political_map.iter().map(|handle| handle.id));
+    progress.check_load_states(&asset_server, handles);
+
+    if let Some(layers) = loading.terrain_layers.as_ref().and_then(|handle| terrain_layers.get(handle)) {
+        if loading.layer_textures.is_empty() {
+            loading.layer_textures = layers.layers
+                .iter()
+                .map(|layer| asset_server.load(layer.texture.as_str()))
+                .collect();
+        }
+
+        // The textures are copied for the atlas task, so only check for them until it has started
+        let layer_textures: Option<Vec<Texture>> = if loading.layer_atlas.not_started() {
+            loading.layer_textures.iter().map(|handle| textures.get(handle).cloned()).collect()
+        } else {
+            None
+        };
+
+        if let Some(layer_textures) = layer_textures {
+            let layers = layers.clone();
+            let progress = (*progress).clone();
+            loading.layer_atlas = Loading::Running(task_pool.spawn(async move {
+                let layer_textures: Vec<&Texture> = layer_textures.iter().collect();
+                let atlas = progress.time("Building terrain layer atlas", || layers.build_atlas(&layer_textures));
+                progress.add(LoadingStage::BuildingTextures, 0.5);
+                atlas
+            }));
+        }
     }
 
-    let forest_handle = asset_server.load::<Texture, &str>("map/textures/grassland.png");
-    if let Some(tx) = textures
-        .get_mut(&forest_handle)
-        .filter(|_| loading.forest.is_none())
-    {
-        setup_texture(&mut tx.sampler);
-        let tx = tx.clone();
-        loading.forest = Some(textures.add(tx));
+    if let Some(atlas) = loading.layer_atlas.poll() {
+        match atlas {
+            Ok(atlas) => loading.layer_atlas = Loading::Done(textures.add(atlas)),
+            Err(err) => return progress.fail(format!("Couldn't build the terrain layer atlas: {}", err)),
+        }
     }
 
-    if let Some(map) = heightmaps
-        .get("map/heightmap/map.mapdat")
-        .filter(|_| loading.heightmap.is_none())
-    {
-        // TODO in task pool
-        let (texture, max_y) = time("Generating heightmap texture", || map.into());
-        loading.heightmap = Some(textures.add(texture));
-        let cloned = map.clone();
+    if loading.built_terrain.not_started() {
+        let build = BuiltTerrain::build((*task_pool).clone(), (*heightmap_path).clone(), *clipmap_config, (*progress).clone());
+        loading.built_terrain = Loading::Running(task_pool.spawn(build));
+    }
 
-        loading.raw_heightmap = Some((heightmaps.add(cloned), max_y));
+    if let Some(built) = loading.built_terrain.poll() {
+        match built {
+            Ok(built) => loading.built_terrain = Loading::Done(built),
+            Err(err) => return progress.fail(format!("Couldn't load the heightmap from {}: {}", heightmap_path.0.display(), err)),
+        }
     }
 
-    if let Some(LoadedAssets {
-        forest,
-        sand,
-        heightmap,
-        raw_heightmap,
-        max_y
-    }) = loading.all_loaded()
-    {
-        let mipmaps = time("Generating mipmap", move || generate_mipmaps(&heightmaps.get(raw_heightmap).unwrap().0, 1));
-        let mipmap = &mipmaps[0];
-        let tx = time("Converting mipmap to texture", || mipmap.to_texture(max_y));
-        let map_material = materials.add(
-            MapMaterial {
-                forest, 
-                sand, 
-                heightmap,
-                mipmap: textures.add(tx),
-            }
-        );
-        let clipmap_mesh = meshes.add(time("Building clipmap mesh", || build_mesh(6))); // TODO in task pool
-        commands.insert_resource(RomeAssets { map_material, clipmap_mesh });
+    if loading.clipmap_mesh.not_started() {
+        let (config, progress) = (*clipmap_config, (*progress).clone());
+        loading.clipmap_mesh = Loading::Running(task_pool.spawn(async move {
+            let mesh = progress.time("Building clipmap mesh", || build_mesh(&config));
+            progress.set(LoadingStage::BuildingMesh, 1.0);
+            mesh
+        }));
+    }
 
-        state.set_next(AppState::InGame).unwrap();
-        // TODO remove loading_state resource
+    if let Some(mesh) = loading.clipmap_mesh.poll() {
+        loading.clipmap_mesh = Loading::Done(meshes.add(mesh));
     }
+
+    let all_loaded = loading.layer_atlas.done().is_some()
+        && loading.built_terrain.done().is_some()
+        && loading.clipmap_mesh.done().is_some()
+        && loading.terrain_layers.as_ref().and_then(|handle| terrain_layers.get(handle)).is_some()
+        && loading.gazetteer.as_ref().and_then(|handle| gazetteers.get(handle)).is_some()
+        && loading.political_map.as_ref().and_then(|handle| political_maps.get(handle)).is_some()
+        && shader::shaders_loaded(shader::pipeline(), &pipelines, &shaders)
+        && shader::shaders_loaded(sky::pipeline(), &pipelines, &shaders);
+
+    if !all_loaded {
+        return;
+    }
+
+    let loaded = std::mem::take(&mut *loading);
+    let (layer_atlas, built, clipmap_mesh) = match (loaded.layer_atlas, loaded.built_terrain, loaded.clipmap_mesh) {
+        (Loading::Done(layer_atlas), Loading::Done(built), Loading::Done(clipmap_mesh)) => (layer_atlas, built, clipmap_mesh),
+        _ => unreachable!(),
+    };
+    let height_encoding = built.height_encoding;
+    let political_map = political_maps.get(loaded.political_map.as_ref().unwrap()).unwrap();
+    let ownership = political_map.ownership(built.size.0, built.size.1);
+    let realms = political_map.realms();
+
+    let map_material = materials.add(
+        MapMaterial {
+            layer_atlas,
+            terrain_layers: terrain_layers.get(loaded.terrain_layers.unwrap()).unwrap().to_uniforms(),
+            heightmap: textures.add(built.heightmap),
+            mipmap: textures.add(built.mipmap),
+            water: textures.add(built.water),
+            lod_colours: 0,
+            wireframe: 0,
+            clipmap_half_extent: clipmap_config.half_extent as f32,
+            clipmap_lod_levels: clipmap_config.lod_levels as i32,
+            sea_level: height_encoding.sea_level(),
+            metres_per_level: 1.0 / height_encoding.levels_per_metre(),
+            latitude: splat::latitude_mapping(),
+            sun_direction: sun.direction,
+            sun_colour: sun.colour,
+            ambient_strength: sun.ambient_strength,
+            fog_colour: sun.horizon_colour(),
+            fog_distance: clipmap_config.visible_radius() as f32 * XYZ_SCALE,
+            paper_map: 0.0,
+            map_mode: 0,
+            overlay: textures.add(ownership.to_texture()),
+            overlay_scale: OWNERSHIP_SCALE as f32,
+            realms: realms.to_uniforms(),
+        }
+    );
+    let sky_material = sky_materials.add(sun.sky_material());
+    commands.insert_resource(RomeAssets {
+        map_material,
+        clipmap_mesh,
+        sky_material,
+        gazetteer: loaded.gazetteer.unwrap(),
+        political_map: loaded.political_map.unwrap(),
+    });
+    commands.insert_resource(ownership);
+    commands.insert_resource(realms);
+    commands.insert_resource(built.terrain);
+    commands.insert_resource(built.minimap);
+
+    state.set_next(AppState::InGame).unwrap();
 }
 
-pub fn time<F: FnOnce() -> T, T>(label: &str, f: F) -> T {
-    let now = Instant::now();
-    let ret = f();
-    eprintln!("{} took {:.2}s", label, now.elapsed().as_secs_f32());
-    ret
+/// Lists how long each step of loading took in the console
+fn report_timings(progress: Res<LoadingProgress>, mut console: ResMut<Console>) {
+    for (step, seconds) in progress.timings() {
+        console.print(format!("{} took {:.2}s", step, seconds));
+    }
 }
@@ -0,0 +1,153 @@
+//! Progress of loading, shared between the asset loaders and tasks (which run on other threads) and
+//! the loading screen
+use bevy::asset::{AssetServer, HandleId, LoadContext, LoadState};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoadingStage {
+    ReadingMap,
+    BuildingTextures,
+    GeneratingMipmaps,
+    BuildingMesh,
+}
+
+impl LoadingStage {
+    pub const ALL: [LoadingStage; 4] = [
+        LoadingStage::ReadingMap,
+        LoadingStage::BuildingTextures,
+        LoadingStage::GeneratingMipmaps,
+        LoadingStage::BuildingMesh,
+    ];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            LoadingStage::ReadingMap => "Reading map",
+            LoadingStage::BuildingTextures => "Building textures",
+            LoadingStage::GeneratingMipmaps => "Generating mipmaps",
+            LoadingStage::BuildingMesh => "Building mesh",
+        }
+    }
+
+    /// Rough share of the total loading time spent in this stage
+    fn weight(self) -> f32 {
+        match self {
+            LoadingStage::ReadingMap => 0.3,
+            LoadingStage::BuildingTextures => 0.4,
+            LoadingStage::GeneratingMipmaps => 0.2,
+            LoadingStage::BuildingMesh => 0.1,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Progress {
+    /// How far through each stage loading is, from 0 to 1, in the order of [`LoadingStage::ALL`]
+    stages: [f32; LoadingStage::ALL.len()],
+    error: Option<String>,
+    /// How long each timed step took, in seconds, in the order that they finished
+    timings: Vec<(&'static str, f32)>,
+}
+
+/// Handle to the progress of loading, which can be cloned into asset loaders
+#[derive(Clone, Default)]
+pub struct LoadingProgress(Arc<Mutex<Progress>>);
+
+impl LoadingProgress {
+    pub fn set(&self, stage: LoadingStage, fraction: f32) {
+        self.0.lock().unwrap().stages[stage as usize] = fraction.clamp(0.0, 1.0);
+    }
+
+    pub fn add(&self, stage: LoadingStage, fraction: f32) {
+        let mut progress = self.0.lock().unwrap();
+        let stage = &mut progress.stages[stage as usize];
+        *stage = (*stage + fraction).clamp(0.0, 1.0);
+    }
+
+    /// Stops loading with an error. Only the first error is kept, as later ones tend to be caused by
+    /// it.
+    pub fn fail(&self, error: String) {
+        eprintln!("Loading failed: {}", error);
+        self.0.lock().unwrap().error.get_or_insert(error);
+    }
+
+    /// Fails loading if an asset loader failed, with the loader's error. Assets that can't be found
+    /// never reach their loader, so are caught by [`LoadingProgress::check_load_states`] instead.
+    pub fn report<T>(&self, ctx: &LoadContext, result: Result<T, anyhow::Error>) -> Result<T, anyhow::Error> {
+        if let Err(err) = &result {
+            self.fail(format!("Couldn't load {}: {}", ctx.path().display(), err));
+        }
+
+        result
+    }
+
+    /// Fails loading if any of the assets failed to load, including ones that couldn't be found
+    pub fn check_load_states(&self, asset_server: &AssetServer, handles: impl IntoIterator<Item = HandleId>) {
+        for handle in handles {
+            if asset_server.get_load_state(handle) == LoadState::Failed {
+                let path = asset_server.get_handle_path(handle);
+                let path = path.as_ref().map_or("an asset".into(), |path| path.path().display().to_string());
+                self.fail(format!("Couldn't load {}", path));
+            }
+        }
+    }
+
+    /// Runs a step of loading, recording how long it took
+    pub fn time<T>(&self, step: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.0.lock().unwrap().timings.push((step, start.elapsed().as_secs_f32()));
+        result
+    }
+
+    /// How long each timed step of loading took, in seconds
+    pub fn timings(&self) -> Vec<(&'static str, f32)> {
+        self.0.lock().unwrap().timings.clone()
+    }
+
+    pub fn stage(&self, stage: LoadingStage) -> f32 {
+        self.0.lock().unwrap().stages[stage as usize]
+    }
+
+    /// How far through loading as a whole, from 0 to 1
+    pub fn total(&self) -> f32 {
+        let progress = self.0.lock().unwrap();
+        LoadingStage::ALL.iter().map(|&stage| progress.stages[stage as usize] * stage.weight()).sum()
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.0.lock().unwrap().error.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_add_up_to_the_total() {
+        let progress = LoadingProgress::default();
+        progress.set(LoadingStage::ReadingMap, 1.0);
+        progress.add(LoadingStage::BuildingTextures, 0.5);
+        progress.add(LoadingStage::BuildingTextures, 0.75);
+
+        assert_eq!(progress.stage(LoadingStage::BuildingTextures), 1.0);
+        let expected = LoadingStage::ReadingMap.weight() + LoadingStage::BuildingTextures.weight();
+        assert!((progress.total() - expected).abs() < 1.0e-6);
+
+        for &stage in LoadingStage::ALL.iter() {
+            progress.set(stage, 1.0);
+        }
+        assert!((progress.total() - 1.0).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn steps_are_timed_in_the_order_they_finish() {
+        let progress = LoadingProgress::default();
+        let result = progress.time("outer", || progress.time("inner", || 42));
+
+        assert_eq!(result, 42);
+        let steps: Vec<&str> = progress.timings().iter().map(|&(step, _)| step).collect();
+        assert_eq!(steps, vec!["inner", "outer"]);
+    }
+}
@@ -0,0 +1,173 @@
+//! Reloads the heightmap while the game is running when it changes on disk, e.g. when
+//! `rome_preprocessor` is run again. Its textures and everything else built from it are rebuilt in
+//! the background and then swapped in. Shaders are reloaded by Bevy itself, as they are loaded as
+//! assets.
+//!
+//! The heightmap is memory mapped while it is read, and a mapped file that is truncated under the
+//! game crashes it, so it is only reloaded when a new file has been moved over it (as
+//! `rome_preprocessor` does, or e.g. `mv output/map.heightmap assets/map/heightmap/`). Heightmaps
+//! that are written to in place, e.g. with `cp`, are never read while they might still be written.
+use bevy::prelude::*;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use futures_lite::future;
+use std::fs::Metadata;
+use std::time::SystemTime;
+use crate::RomeAssets;
+use crate::loading::BuiltTerrain;
+use crate::loading::progress::LoadingProgress;
+use crate::map::HeightmapPath;
+use crate::map::mesh::ClipmapConfig;
+use crate::map::overlay::{Ownership, PoliticalMap};
+use crate::map::shader::MapMaterial;
+use crate::minimap::Minimap;
+
+/// How often the heightmap is checked for changes, in seconds. It is only reloaded once it is the
+/// same at two checks in a row, so that it isn't read while it is still being written.
+const POLL_INTERVAL: f32 = 0.5;
+
+pub struct HeightmapWatcher {
+    path: HeightmapPath,
+    timer: Timer,
+    /// The heightmap in use
+    loaded: Option<FileVersion>,
+    /// The heightmap on disk, as of the last check
+    seen: Option<FileVersion>,
+    rebuild: Option<Task<Result<BuiltTerrain, anyhow::Error>>>,
+}
+
+impl HeightmapWatcher {
+    fn new(path: HeightmapPath) -> Self {
+        let version = FileVersion::current(&path);
+        HeightmapWatcher {
+            path,
+            timer: Timer::from_seconds(POLL_INTERVAL, true),
+            loaded: version,
+            seen: version,
+            rebuild: None,
+        }
+    }
+}
+
+/// A version of the heightmap on disk
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct FileVersion {
+    modified: SystemTime,
+    /// Device and inode of the file, which only change when another file is moved over it
+    file: Option<(u64, u64)>,
+}
+
+impl FileVersion {
+    fn current(path: &HeightmapPath) -> Option<FileVersion> {
+        let metadata = std::fs::metadata(&path.0).ok()?;
+        Some(FileVersion { modified: metadata.modified().ok()?, file: file_id(&metadata) })
+    }
+
+    /// Whether this version was written into the same file as another, rather than replacing it
+    fn written_in_place(self, other: FileVersion) -> bool {
+        self.file.is_some() && self.file == other.file
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Other platforms don't let mapped files be written to, so they are never written to in place
+/// while they are read
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+pub fn start_watching(commands: &mut Commands, path: Res<HeightmapPath>) {
+    commands.insert_resource(HeightmapWatcher::new((*path).clone()));
+}
+
+pub fn watch_heightmap(
+    time: Res<Time>,
+    task_pool: Res<AsyncComputeTaskPool>,
+    clipmap_config: Res<ClipmapConfig>,
+    mut watcher: ResMut<HeightmapWatcher>,
+) {
+    if !watcher.timer.tick(time.delta_seconds()).just_finished() || watcher.rebuild.is_some() {
+        return;
+    }
+
+    let version = FileVersion::current(&watcher.path);
+    let path = watcher.path.0.display();
+    if version != watcher.loaded && version == watcher.seen {
+        match (version, watcher.loaded) {
+            (Some(version), Some(loaded)) if version.written_in_place(loaded) => eprintln!(
+                "{} was written to in place, so it isn't reloaded. Move a new heightmap over it instead.",
+                path,
+            ),
+            _ => {
+                eprintln!("{} has changed, reloading it", path);
+                let build = BuiltTerrain::build(
+                    (*task_pool).clone(),
+                    watcher.path.clone(),
+                    *clipmap_config,
+                    LoadingProgress::default(),
+                );
+                watcher.rebuild = Some(task_pool.spawn(build));
+            }
+        }
+        watcher.loaded = version;
+    }
+
+    watcher.seen = version;
+}
+
+/// Swaps in the rebuilt heightmap once it is ready. Textures are replaced in place, so that
+/// everything using them is updated. Who owns the land is kept, unless the map has changed size, in
+/// which case it no longer lines up and is rebuilt from the political map.
+#[allow(clippy::too_many_arguments)]
+pub fn swap_in_heightmap(
+    commands: &mut Commands,
+    mut watcher: ResMut<HeightmapWatcher>,
+    assets: Res<RomeAssets>,
+    mut materials: ResMut<Assets<MapMaterial>>,
+    mut textures: ResMut<Assets<Texture>>,
+    mut minimap: ResMut<Minimap>,
+    mut ownership: ResMut<Ownership>,
+    political_maps: Res<Assets<PoliticalMap>>,
+) {
+    let built = match watcher.rebuild.as_mut().and_then(|task| future::block_on(future::poll_once(task))) {
+        Some(built) => built,
+        None => return,
+    };
+    watcher.rebuild = None;
+
+    let built = match built {
+        Ok(built) => built,
+        Err(err) => return eprintln!("Couldn't reload the heightmap from {}: {}", watcher.path.0.display(), err),
+    };
+
+    if let Some(material) = materials.get_mut(&assets.map_material) {
+        let mut replace = |handle: &Handle<Texture>, texture: Texture| {
+            if let Some(old) = textures.get_mut(handle) {
+                *old = texture;
+            }
+        };
+
+        replace(&material.heightmap, built.heightmap);
+        replace(&material.mipmap, built.mipmap);
+        replace(&material.water, built.water);
+
+        material.sea_level = built.height_encoding.sea_level();
+        material.metres_per_level = 1.0 / built.height_encoding.levels_per_metre();
+    }
+
+    let (width, height) = built.size;
+    if !ownership.covers(width, height) {
+        if let Some(political_map) = political_maps.get(&assets.political_map) {
+            // The overlay texture and the minimap are redrawn when the ownership changes
+            *ownership = political_map.ownership(width, height);
+        }
+    }
+
+    minimap.replace_terrain(built.minimap);
+    commands.insert_resource(built.terrain);
+}
@@ -0,0 +1,137 @@
+//! The loading screen, which shows the progress of each loading stage, and the error screen shown
+//! in its place if loading fails
+use bevy::prelude::*;
+use crate::loading::progress::{LoadingProgress, LoadingStage};
+
+const BACKGROUND: Color = Color::rgb_linear(0.01, 0.008, 0.006);
+const TEXT: Color = Color::rgb_linear(0.8, 0.7, 0.5);
+const ERROR: Color = Color::rgb_linear(0.8, 0.1, 0.05);
+const BAR_WIDTH: f32 = 400.0;
+
+/// Marks the root node of the loading or error screen
+pub struct LoadingScreen;
+
+/// Text showing the progress of a stage
+pub struct StageText(LoadingStage);
+
+/// The filled part of the progress bar
+pub struct ProgressBar;
+
+fn root_node(materials: &mut Assets<ColorMaterial>) -> NodeBundle {
+    NodeBundle {
+        style: Style {
+            size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+            // UI is laid out from the bottom up, so this puts the first child at the top
+            flex_direction: FlexDirection::ColumnReverse,
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..Default::default()
+        },
+        material: materials.add(BACKGROUND.into()),
+        ..Default::default()
+    }
+}
+
+fn text(value: String, font: Handle<Font>, font_size: f32, color: Color) -> TextBundle {
+    TextBundle {
+        style: Style {
+            margin: Rect::all(Val::Px(4.0)),
+            ..Default::default()
+        },
+        text: Text {
+            value,
+            font,
+            style: TextStyle { font_size, color, alignment: TextAlignment::default() },
+        },
+        ..Default::default()
+    }
+}
+
+pub fn spawn_loading_screen(
+    commands: &mut Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let font = asset_server.load("fonts/FiraSans-Regular.ttf");
+    let bar_background = materials.add(Color::rgb_linear(0.05, 0.04, 0.03).into());
+    let bar_fill = materials.add(TEXT.into());
+
+    commands
+        .spawn(root_node(&mut materials))
+        .with(LoadingScreen)
+        .with_children(|screen| {
+            screen.spawn(text("Loading".to_string(), font.clone(), 48.0, TEXT));
+
+            for &stage in LoadingStage::ALL.iter() {
+                screen
+                    .spawn(text(stage.description().to_string(), font.clone(), 20.0, TEXT))
+                    .with(StageText(stage));
+            }
+
+            screen
+                .spawn(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Px(BAR_WIDTH), Val::Px(8.0)),
+                        margin: Rect::all(Val::Px(16.0)),
+                        ..Default::default()
+                    },
+                    material: bar_background,
+                    ..Default::default()
+                })
+                .with_children(|bar| {
+                    bar.spawn(NodeBundle {
+                        style: Style {
+                            size: Size::new(Val::Percent(0.0), Val::Percent(100.0)),
+                            ..Default::default()
+                        },
+                        material: bar_fill,
+                        ..Default::default()
+                    })
+                    .with(ProgressBar);
+                });
+        });
+}
+
+pub fn update_loading_screen(
+    progress: Res<LoadingProgress>,
+    mut stages: Query<(&mut Text, &StageText)>,
+    mut bars: Query<&mut Style, With<ProgressBar>>,
+) {
+    for (mut text, StageText(stage)) in stages.iter_mut() {
+        let value = format!("{} - {:.0}%", stage.description(), progress.stage(*stage) * 100.0);
+
+        // Changing text re-lays it out, so only do it when it actually changes
+        if text.value != value {
+            text.value = value;
+        }
+    }
+
+    for mut style in bars.iter_mut() {
+        style.size.width = Val::Percent(progress.total() * 100.0);
+    }
+}
+
+pub fn despawn_loading_screen(commands: &mut Commands, screens: Query<Entity, With<LoadingScreen>>) {
+    for screen in screens.iter() {
+        commands.despawn_recursive(screen);
+    }
+}
+
+pub fn spawn_error_screen(
+    commands: &mut Commands,
+    asset_server: Res<AssetServer>,
+    progress: Res<LoadingProgress>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let font = asset_server.load("fonts/FiraSans-Regular.ttf");
+    let error = progress.error().unwrap_or_else(|| "Unknown error".to_string());
+
+    commands
+        .spawn(root_node(&mut materials))
+        .with(LoadingScreen)
+        .with_children(|screen| {
+            screen.spawn(text("The game couldn't be loaded".to_string(), font.clone(), 48.0, ERROR));
+            screen.spawn(text(error, font.clone(), 20.0, TEXT));
+            screen.spawn(text("Check that the game's assets are complete and try again.".to_string(), font, 20.0, TEXT));
+        });
+}
@@ -1,62 +1,55 @@
 use bevy::prelude::*;
-use bevy::window::WindowMode;
-use bevy::diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin};
-use crate::map::shader::MapMaterial;
-use crate::loading::LoadRomeAssets;
-use crate::map::{RomeMapPlugin, LIGHT_POS, LatLong};
-use goshawk::{RtsCamera, ZoomSettings, PanSettings, TurnSettings};
-use bevy::prelude::shape::Cube;
+use bevy::render::camera::PerspectiveProjection;
+use rome::{AppState, RomeAssets, STATE_STAGE};
+use rome::loading::LoadRomeAssets;
+use rome::camera::CameraPlugin;
+use rome::map::RomeMapPlugin;
+use rome::map::terrain::SnapToTerrain;
+use rome::map::lighting::Sun;
+use rome::map::shader::{TimeNode, ViewNode};
+use rome::map::sky::SkyDome;
+use rome::map::mesh::ClipmapConfig;
+use rome::map::XYZ_SCALE;
+use rome::game_time::GameTimePlugin;
+use rome::labels::LabelsPlugin;
+use rome::settlements::SettlementsPlugin;
+use rome::minimap::MinimapPlugin;
+use rome::settings::{Settings, SettingsPlugin};
+use rome::input::ActionsPlugin;
+use rome::console::ConsolePlugin;
+use goshawk::RtsCamera;
+use bevy::prelude::shape::{Cube, Icosphere};
 use itertools::Itertools;
 
-mod loading;
-mod map;
-
-const STATE_STAGE: &'static str = "rome_app_state_stage";
-
 fn main() {
+    let settings = Settings::load();
     let mut builder = App::build();
 
     builder
-        .add_resource(Msaa { samples: 8 })
-        .add_resource(WindowDescriptor {
-            vsync: false,
-            resizable: false,
-            mode: WindowMode::BorderlessFullscreen,
-            ..Default::default()
-        })
+        .add_resource(Msaa { samples: settings.graphics.msaa_samples })
+        .add_resource(settings.window.descriptor())
+        .add_resource(settings)
         .add_resource(State::new(AppState::Loading))
         .add_stage_after(stage::UPDATE, STATE_STAGE, StateStage::<AppState>::default())
         .add_plugins(DefaultPlugins)
-        .add_plugin(FrameTimeDiagnosticsPlugin::default())
+        .add_plugin(SettingsPlugin)
+        .add_plugin(ActionsPlugin)
+        .add_plugin(ConsolePlugin)
         .add_plugin(LoadRomeAssets)
+        .add_plugin(CameraPlugin)
         .add_plugin(RomeMapPlugin)
-        .add_system(fps_counter_text_update.system())
+        .add_plugin(GameTimePlugin)
+        .add_plugin(LabelsPlugin)
+        .add_plugin(SettlementsPlugin)
+        .add_plugin(MinimapPlugin)
+        .add_startup_system(spawn_ui_camera.system())
         .on_state_enter(STATE_STAGE, AppState::InGame, start_game.system())
-        .on_state_update(STATE_STAGE, AppState::InGame, goshawk::rts_camera_system.system())
         .run();
 }
 
-#[derive(Clone)]
-enum AppState {
-    Loading,
-    InGame,
-}
-
-pub struct RomeAssets {
-    map_material: Handle<MapMaterial>,
-    clipmap_mesh: Handle<Mesh>,
-}
-
-fn fps_counter_text_update(diagnostics: Res<Diagnostics>, mut query: Query<&mut Text>, query2: Query<&goshawk::RtsCamera>) {
-    let xyz = query2.iter().next().map(|opt| opt.looking_at);
-    let dist = query2.iter().next().map(|opt| opt.zoom_distance);
-    for mut text in query.iter_mut() {
-        if let (Some(fps), Some(frame_time)) = (diagnostics.get(FrameTimeDiagnosticsPlugin::FPS), diagnostics.get(FrameTimeDiagnosticsPlugin::FRAME_TIME)) {
-            if let (Some(average_fps), Some(average_frame_time), Some(xyz), Some(dist)) = (fps.average(), frame_time.average(), xyz, dist) {
-                text.value = format!("FPS: {:.0}. Frame time: {:.2}ms. XZ: ({:.2}; {:.2}). Zoom: {:.2}.", average_fps.round(), average_frame_time * 1000.0, xyz.x, xyz.z, dist).into();
-            }
-        }
-    }
+/// The UI camera is spawned at startup so that the loading screen can be drawn
+fn spawn_ui_camera(commands: &mut Commands) {
+    commands.spawn(CameraUiBundle::default());
 }
 
 fn start_game(
@@ -64,16 +57,14 @@ fn start_game(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     assets: Res<RomeAssets>,
-    asset_server: ResMut<AssetServer>
+    sun: Res<Sun>,
+    clipmap_config: Res<ClipmapConfig>,
+    settings: Res<Settings>,
 ) {
     let italy = Vec3::new(745.0, 0.0, 535.0);
-    let rome = LatLong {
-        latitude: 41.9,
-        longitude: 12.49
-    }.to_tile_coord().to_world_space_0y();
 
-    let font_handle = asset_server.load("fonts/FiraMono-Medium.ttf");
-    let light_pos: Vec3 = LIGHT_POS.into();
+    let (mut zoom, mut pan, mut turn) = Default::default();
+    settings.camera.apply(&mut zoom, &mut pan, &mut turn);
 
     let mesh = meshes.add(Mesh::from(Cube::new(5.0)));
     let material = materials.add(StandardMaterial {
@@ -81,83 +72,57 @@ fn start_game(
         ..Default::default()
     });
 
-    let (mesh_clone, material_clone) = (mesh.clone(), material.clone());
-
     let intervals = (0..=1000).step_by(50);
 
     commands.spawn_batch(
         intervals
             .clone()
             .cartesian_product(intervals)
-            .map(move |(x, z)| PbrBundle {
-                mesh: mesh.clone(),
-                material: material.clone(),
-                transform: Transform::from_translation(Vec3::new(x as f32, 0.0, z as f32)),
-                ..Default::default()
-            })
+            .map(move |(x, z)| (
+                PbrBundle {
+                    mesh: mesh.clone(),
+                    material: material.clone(),
+                    transform: Transform::from_translation(Vec3::new(x as f32, 0.0, z as f32)),
+                    ..Default::default()
+                },
+                SnapToTerrain { offset: 2.5 },
+            ))
     );
 
-    commands.spawn(Camera3dBundle::default())
+    commands.spawn(Camera3dBundle {
+            // Far enough that the fog hides the edge of the map before it is clipped
+            perspective_projection: PerspectiveProjection {
+                far: clipmap_config.visible_radius() as f32 * XYZ_SCALE * 2.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
         .with(RtsCamera {
             looking_at: italy,
             zoom_distance: 175.0,
             ..Default::default()
         })
-        .with(ZoomSettings {
-            angle_range: 0.9103..=1.237539,
-            scroll_accel: 25.0,
-            max_velocity: 70.0,
-            idle_deceleration: 200.0,
-            angle_change_zone: 80.0..=130.0,
-            distance_range: 75.0..=200.0,
-            ..Default::default()
-        })
-        .with(PanSettings {
-            mouse_accel: 200.0,
-            keyboard_accel: 160.0,
-            idle_deceleration: 200.0,
-            max_speed: 10.0,
-            pan_speed_zoom_factor_range: 1.0..=6.0,
-            ..Default::default()
-        })
-        .with(TurnSettings {
-            mouse_turn_margin: 0.0,
-            mouse_accel: 0.0,
-            keyboard_accel: 0.0,
-            ..Default::default()
-        })
+        .with(zoom)
+        .with(pan)
+        .with(turn)
         .spawn(MeshBundle {
             mesh: assets.clipmap_mesh.clone(),
-            render_pipelines: map::shader::render_pipelines(),
+            render_pipelines: rome::map::shader::render_pipelines(),
             transform: Transform::from_translation(Vec3::splat(0.5)),
             ..Default::default()
         })
         .with(assets.map_material.clone())
-        .spawn(PbrBundle {
-            mesh: mesh_clone,
-            material: material_clone,
-            transform: Transform::from_translation(rome),
+        .with(TimeNode::default())
+        .with(ViewNode::default())
+        .spawn(MeshBundle {
+            mesh: meshes.add(Mesh::from(Icosphere { radius: 10.0, subdivisions: 3 })),
+            render_pipelines: rome::map::sky::render_pipelines(),
             ..Default::default()
         })
+        .with(assets.sky_material.clone())
+        .with(SkyDome)
         .spawn(LightBundle {
-            transform: Transform::from_translation(light_pos * 1_000_000.0),
-            ..Default::default()
-        })
-        .spawn(CameraUiBundle::default())
-        .spawn(TextBundle {
-            style: Style {
-                align_self: AlignSelf::FlexEnd,
-                ..Default::default()
-            },
-            text: Text {
-                value: "FPS:".to_string(),
-                font: font_handle,
-                style: TextStyle {
-                    font_size: 40.0,
-                    alignment: TextAlignment::default(),
-                    color: Color::WHITE,
-                },
-            },
+            transform: Transform::from_translation(sun.light_position()),
             ..Default::default()
         });
 }
@@ -5,7 +5,7 @@
 //!
 //! The license of the original source follows:
 //!
-//! ```
+//! ```text
 //! MIT License
 //!
 //! Copyright (c) 2016 morgan3d
@@ -29,43 +29,142 @@
 //! SOFTWARE.
 //! ```
 use crate::{AppState, STATE_STAGE};
+use crate::console::AddConsoleCommand;
 use bevy::app::{AppBuilder, Plugin};
-use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
 use bevy::prelude::*;
-use bevy::reflect::TypeUuid;
-use bevy::tasks::AsyncComputeTaskPool;
-use std::future::Future;
-use std::io::Read;
-use std::pin::Pin;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 use bevy::render::texture::{Extent3d, TextureFormat, AddressMode, SamplerDescriptor, TextureDimension};
-use itertools::Itertools;
-use byteorder::WriteBytesExt;
-use std::cmp;
-use ordered_float::OrderedFloat;
-use crate::map::shader::MapMaterial;
+use rayon::prelude::*;
+use crate::map::shader::{MapMaterial, MapDebugSettings};
+use crate::map::sky::SkyMaterial;
+use crate::map::paper::PaperMap;
+use crate::map::overlay::{MapMode, Realms};
 use crate::map::mipmap::HeightmapMipMap;
-use crate::loading::time;
+use crate::map::mesh::ClipmapConfig;
+use crate::map::lighting::Sun;
+use rome_map::Height;
+use rome_map::raw::MapView;
+use serde::{Deserialize, Serialize};
 
 pub mod mesh;
 pub mod shader;
 pub mod mipmap;
+pub mod lighting;
+pub mod water;
+pub mod splat;
+pub mod sky;
+pub mod paper;
+pub mod overlay;
+pub mod terrain;
 
 pub struct RomeMapPlugin;
 
 impl Plugin for RomeMapPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_asset::<HeightMap>()
-            .add_asset::<MapMaterial>()
+        app.add_asset::<MapMaterial>()
+            .add_asset::<SkyMaterial>()
+            .add_resource(MapDebugSettings::default())
+            .add_resource(ClipmapConfig::default())
+            .add_resource(Sun::default())
+            .add_resource(PaperMap::default())
+            .add_resource(MapMode::default())
+            .add_resource(Realms::default())
+            .add_console_command(
+                "mapmode",
+                "mapmode <terrain|political|diplomatic|economic>",
+                "Switches what the map shows",
+            )
+            .add_console_command("wireframe", "wireframe", "Toggles drawing the edges of the map's triangles")
+            .add_console_command("lodcolors", "lodcolors", "Toggles tinting each clipmap LOD a different colour")
             .add_startup_system(shader::setup.system())
+            .add_startup_system(sky::setup.system())
             .on_state_update(
                 STATE_STAGE,
                 AppState::InGame,
                 shader::update_time.system(),
             )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                shader::update_view.system(),
+            )
             .on_state_update(
                 STATE_STAGE,
                 AppState::InGame,
                 translate_meshes.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                sky::follow_camera.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                terrain::snap_to_terrain.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                paper::update_paper_map.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                paper::update_material.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                overlay::switch_map_mode.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                overlay::update_map_mode.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                overlay::update_ownership.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                overlay::update_realms.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                shader::toggle_lod_colours.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                shader::debug_commands.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                overlay::map_mode_command.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                shader::update_debug_settings.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                lighting::update_sun_from_time.system(),
+            )
+            .on_state_update(
+                STATE_STAGE,
+                AppState::InGame,
+                lighting::update_sun.system(),
             );
     }
 }
@@ -78,176 +177,290 @@ fn translate_meshes(camera: Query<&goshawk::RtsCamera>, mut meshes: Query<(&mut
     }
 }
 
-type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+/// Where the heightmap, made by `rome_preprocessor`, is on disk. It is memory mapped rather than
+/// loaded by the asset server, but it is kept in the same [asset folder](crate::asset_folder). As it
+/// is memory mapped, it has to be replaced by moving a new file over it,
+/// rather than written to in place.
+#[derive(Clone, Debug)]
+pub struct HeightmapPath(pub PathBuf);
+
+impl HeightmapPath {
+    /// Path of the heightmap within the asset folder
+    const ASSET_PATH: &'static str = "map/heightmap/map.heightmap";
 
-pub struct HeightMapLoader {
-    pub task_pool: AsyncComputeTaskPool,
+    pub fn new(asset_folder: &Path) -> HeightmapPath {
+        HeightmapPath(asset_folder.join(Self::ASSET_PATH))
+    }
 }
 
-impl AssetLoader for HeightMapLoader {
-    fn load<'a>(
-        &'a self,
-        bytes: &'a [u8],
-        ctx: &'a mut LoadContext,
-    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
-        Box::pin(async move {
-            // TODO no block
-            let asset = self.task_pool.scope(|scope| {
-                scope.spawn(async {
-                    time("Loading heightmap", || {
-                        println!("Unzipping");
-
-                        let mut decoder = zstd::Decoder::new(&*bytes).unwrap();
-                        let mut unzipped = Vec::new();
-                        decoder.read_to_end(&mut unzipped).unwrap();
-
-                        println!("Deserializing");
-                        let map: rome_map::Map = bincode::deserialize(&unzipped).unwrap();
-
-                        dbg!(map.height, map.width);
-                        println!("Done loading heightmap");
-
-                        HeightMap(map)
-                    })
-                })
-            });
-
-            ctx.set_default_asset(LoadedAsset::new(asset.into_iter().next().unwrap()));
-            Ok(())
-        })
+/// The map's heights and water, in the [raw layout](rome_map::raw) so that they are read in place
+/// rather than copied
+pub struct HeightMap(Backing);
+
+enum Backing {
+    Mapped(Mmap),
+    /// `u16`s so that the heights are aligned like those of a mapped file
+    Owned(Vec<u16>, usize),
+}
+
+impl HeightMap {
+    /// Memory maps a heightmap file
+    pub fn open(path: impl AsRef<Path>) -> Result<HeightMap, anyhow::Error> {
+        let file = File::open(path)?;
+        // SAFETY: the map is only read. It mustn't be written to while it is mapped, so heightmaps
+        // are replaced by moving a new file over them, which leaves the mapped file as it was, and
+        // ones written in place aren't reloaded (see `loading::reload`).
+        let map = HeightMap(Backing::Mapped(unsafe { Mmap::map(&file)? }));
+        MapView::parse(map.bytes())?;
+        Ok(map)
     }
 
-    fn extensions(&self) -> &[&str] {
-        &["mapdat"]
+    /// Memory maps a heightmap file, first converting it from the `.mapdat` file next to it if it
+    /// doesn't exist yet. Maps were compressed into `.mapdat` files before they were memory mapped.
+    pub fn open_or_convert(path: &Path) -> Result<HeightMap, anyhow::Error> {
+        let mapdat = path.with_extension("mapdat");
+
+        if !path.exists() && mapdat.exists() {
+            let mut reader = BufReader::new(File::open(&mapdat)?);
+            if reader.fill_buf()?.starts_with(b"version https://git-lfs") {
+                anyhow::bail!("{} hasn't been downloaded from Git LFS. Run `git lfs pull` first.", mapdat.display());
+            }
+
+            eprintln!("Converting {} to {}", mapdat.display(), path.display());
+            rome_map::Map::read_mapdat(reader)?.save_raw(path)?;
+        }
+
+        HeightMap::open(path)
+    }
+
+    /// Heightmap of a map that is already in memory
+    pub fn from_map(map: &rome_map::Map) -> HeightMap {
+        let mut bytes = Vec::new();
+        map.write_raw(&mut bytes).unwrap();
+
+        let words = bytes
+            .chunks(2)
+            .map(|pair| u16::from_ne_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+            .collect();
+
+        HeightMap(Backing::Owned(words, bytes.len()))
     }
-}
 
-#[derive(TypeUuid, Clone)]
-#[uuid = "7b7c08b3-986e-49d8-85da-107024f177f1"]
-pub struct HeightMap(pub rome_map::Map);
+    fn bytes(&self) -> &[u8] {
+        match &self.0 {
+            Backing::Mapped(mmap) => mmap,
+            // SAFETY: the bytes are all within the Vec, and u8s have no alignment requirement
+            Backing::Owned(words, len) => unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, *len) },
+        }
+    }
 
-fn clamp(a: i32, max: u32) -> u32 {
-    cmp::max(cmp::min(a, max as i32), 0) as u32
+    pub fn view(&self) -> MapView<'_> {
+        MapView::parse(self.bytes()).expect("heightmap is checked when it is created")
+    }
 }
 
 const Y_SCALE: f32 = 0.2;
-const XYZ_SCALE: f32 = 1.0 / 8.0;
-pub const LIGHT_POS: [f32; 3] = [-1.0, 0.2, -0.3];
+pub const XYZ_SCALE: f32 = 1.0 / 8.0;
+const HEIGHT_BITS: u8 = 8;
+
+/// How signed heights (in metres) are quantised into the height channels of the heightmap and
+/// mipmap textures. The lowest height maps to level 0 and the highest to the top level, so sea level
+/// ends up at [`HeightEncoding::sea_level`], which the vertex shader subtracts to get back heights
+/// relative to the sea.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeightEncoding {
+    pub min: i16,
+    pub max: i16,
+}
 
-impl HeightMap {
-    fn sample_height_water(&self, x: i32, y: i32) -> (u16, bool) {
-        let px = self.0.get(clamp(x, self.0.width as u32), clamp(y, self.0.height as u32));
-        if px.is_water {
-            (0, true)
-        } else {
-            (i16::max(0, px.height.0) as u16, false)
-        }
+impl HeightEncoding {
+    /// Encoding covering all of the given heights, as well as sea level
+    pub fn covering(heights: impl IntoIterator<Item = Height>) -> HeightEncoding {
+        heights.into_iter().fold(HeightEncoding { min: 0, max: 0 }, |range, height| HeightEncoding {
+            min: range.min.min(height.0),
+            max: range.max.max(height.0),
+        })
     }
 
-    fn sample_water(&self, x: i32, y: i32) -> bool {
-        let px = self.0.get(clamp(x, self.0.width as u32), clamp(y, self.0.height as u32));
-        px.is_water
+    pub fn levels_per_metre(&self) -> f32 {
+        let range = i32::max(self.max as i32 - self.min as i32, 1);
+        ((1u32 << HEIGHT_BITS) - 1) as f32 / range as f32
     }
 
-    fn sample_vec3(&self, x: i32, y: i32, height_factor: f32) -> Vec3 {
-        Vec3::new(x as f32, self.sample_height_water(x, y).0 as f32 * height_factor * Y_SCALE * XYZ_SCALE, y as f32)
+    pub fn encode(&self, height: Height) -> u8 {
+        ((height.0 as i32 - self.min as i32) as f32 * self.levels_per_metre()).round() as u8
     }
 
-    fn sample_normal(&self, x: i32, y: i32, height_factor: f32) -> Vec3 {
-        let top_left = self.sample_vec3(x, y, height_factor);
-        let bottom_left = self.sample_vec3(x, y + 1, height_factor);
-        let bottom_right = self.sample_vec3(x + 1, y + 1, height_factor);
+    /// Encoding covering the heights covered by both encodings
+    pub fn union(self, other: HeightEncoding) -> HeightEncoding {
+        HeightEncoding { min: self.min.min(other.min), max: self.max.max(other.max) }
+    }
 
-        (bottom_right - bottom_left).cross(top_left - bottom_left).normalize()
+    /// Level that a height of zero metres is encoded as
+    pub fn sea_level(&self) -> f32 {
+        -(self.min as f32) * self.levels_per_metre()
     }
 }
 
-impl Into<(Texture, u16)> for &HeightMap {
-    fn into(self) -> (Texture, u16) {
-        const HEIGHT_BITS: u8 = 8;
-        const LIGHT_BITS: u8 = 8;
-        const MAX_LIGHT_LEVEL: u8 = ((1u16 << LIGHT_BITS) - 1) as u8;
-        const AMBIENT_LIGHT_STRENGTH: OrderedFloat<f32> = OrderedFloat(0.1);
-
-        let light_pos: Vec3 = LIGHT_POS.into();
-        let light_pos = light_pos.normalize();
-
-        let mut max = 0;
-        for (y, x) in (0..self.0.height).cartesian_product(0..self.0.width) {
-            let h = self.0.get(x as u32, y as u32).height.0;
-            if h > max {
-                max = h;
-            }
+/// Height of the visible surface of the pixel at `index`, in row-major order. Water is drawn at sea
+/// level - its depth is kept in the [water texture](water::to_texture) instead. Land keeps its
+/// height, even if it is below sea level.
+#[inline(always)]
+pub fn surface_height(map: MapView, index: usize) -> Height {
+    if map.is_water()[index] {
+        Height(0)
+    } else {
+        map.heights()[index]
+    }
+}
+
+impl HeightMap {
+    /// Encoding covering the [surface heights](surface_height) of the map, found one row at a time,
+    /// in parallel
+    pub fn height_encoding(&self) -> HeightEncoding {
+        let map = self.view();
+        (0..map.height)
+            .into_par_iter()
+            .map(|y| HeightEncoding::covering((y * map.width..(y + 1) * map.width).map(|i| surface_height(map, i))))
+            .reduce(|| HeightEncoding { min: 0, max: 0 }, HeightEncoding::union)
+    }
+}
+
+/// Texel of the heightmap texture. The normal always points upwards, so only its x and z
+/// components are kept, and its y component is recovered in the shader.
+fn heightmap_texel(height: u8, normal: Vec3, terrain_type: u8) -> [u8; 4] {
+    const NORMAL_BITS: u8 = 8;
+    const MAX_NORMAL_LEVEL: f32 = ((1u16 << NORMAL_BITS) - 1) as f32;
+
+    // Maps a normal component from -1..=1 to 0..=MAX_NORMAL_LEVEL
+    fn normal_level(component: f32) -> u8 {
+        ((component * 0.5 + 0.5) * MAX_NORMAL_LEVEL).round() as u8
+    }
+
+    // R = height, G = normal x, B = terrain type, A = normal z
+    [height, normal_level(normal.x), terrain_type, normal_level(normal.z)]
+}
+
+/// One row of the heightmap and its neighbouring rows, which are clamped to the edge of the map.
+/// The rows are read in place from the map.
+struct HeightmapRows<'a> {
+    map: MapView<'a>,
+    y: usize,
+    /// Index of the first pixel of the row above, this row and the row below
+    above: usize,
+    row: usize,
+    below: usize,
+    encoding: HeightEncoding,
+    levels_per_metre: f32,
+}
+
+impl<'a> HeightmapRows<'a> {
+    fn new(map: MapView<'a>, y: usize, encoding: HeightEncoding) -> HeightmapRows<'a> {
+        let start = |y: usize| y.min(map.height - 1) * map.width;
+
+        HeightmapRows {
+            map,
+            y,
+            above: start(y.saturating_sub(1)),
+            row: start(y),
+            below: start(y + 1),
+            encoding,
+            levels_per_metre: encoding.levels_per_metre(),
         }
+    }
+
+    /// Position of a pixel in world space, as it will be drawn by the shader
+    fn position(&self, x: usize, y: usize, height: Height) -> Vec3 {
+        let height = height.0 as f32 * self.levels_per_metre * Y_SCALE;
+        Vec3::new(x as f32, height, y as f32) * XYZ_SCALE
+    }
+
+    /// Texel at `x`, given the pixels to its left and right (which are clamped to the map)
+    #[inline(always)]
+    fn texel(&self, x: usize, left: usize, right: usize) -> [u8; 4] {
+        let (map, y) = (self.map, self.y);
+        let water = |row: usize, x: usize| map.is_water()[row + x];
+
+        let surface = surface_height(map, self.row + x);
+        let top_left = self.position(x, y, surface);
+        let bottom_left = self.position(x, y + 1, surface_height(map, self.below + x));
+        let bottom_right = self.position(x + 1, y + 1, surface_height(map, self.below + right));
+        let normal = (bottom_right - bottom_left).cross(top_left - bottom_left).normalize();
+
+        let terrain_type = if water(self.row, x) {
+            1
+        } else if water(self.row, left) || water(self.row, right)
+            || water(self.above, left) || water(self.above, x) || water(self.above, right)
+            || water(self.below, left) || water(self.below, x) || water(self.below, right)
+        {
+            // Coast
+            2
+        } else {
+            0
+        };
+
+        heightmap_texel(self.encoding.encode(surface), normal, terrain_type)
+    }
 
-        let factor = ((1 << HEIGHT_BITS) - 1) as f32 / max as f32;
-
-        let mut bytes = Vec::with_capacity(self.0.height * self.0.width * 2);
-
-        for (y, x) in (0..(self.0.height as i32)).cartesian_product(0..(self.0.width as i32)) {
-            let normal = self.sample_normal(x, y, factor);
-
-            let diffuse = cmp::max(OrderedFloat(normal.dot(light_pos)), OrderedFloat(0.0));
-            let brightness = cmp::min(OrderedFloat(1.0), diffuse + AMBIENT_LIGHT_STRENGTH);
-
-            let brightness_level = (brightness.0 as f32 * MAX_LIGHT_LEVEL as f32).round() as u8;
-            let (height, water) = self.sample_height_water(x, y);
-            let height = (height as f32 * factor).round() as u8;
-
-            let terrain_type = if !water {
-                // TODO do better
-                let adjacent_water = self.sample_water(x + 1, y) ||
-                    self.sample_water(x - 1, y) ||
-                    self.sample_water(x, y + 1) ||
-                    self.sample_water(x, y - 1) ||
-                    self.sample_water(x + 1, y + 1) ||
-                    self.sample_water(x + 1, y - 1) ||
-                    self.sample_water(x - 1, y + 1) ||
-                    self.sample_water(x - 1, y - 1);
-
-                if adjacent_water {
-                    2
-                } else {
-                    0
-                }
-            } else {
-                1
-            };
-
-
-            bytes.write_u8(height).unwrap(); // R channel = height
-            bytes.write_u8(brightness_level).unwrap(); // G channel = brightness level
-            bytes.write_u8(terrain_type).unwrap(); // B channel = terrain type
-            bytes.write_u8(0).unwrap(); // A channel is unused
+    fn write(&self, out: &mut [u8]) {
+        let width = self.map.width;
+        let mut write = |x: usize, texel: [u8; 4]| out[x * 4..x * 4 + 4].copy_from_slice(&texel);
+
+        // Only the pixels at the edges need their neighbours clamped to the map
+        write(0, self.texel(0, 0, 1.min(width - 1)));
+        for x in 1..width.saturating_sub(1) {
+            write(x, self.texel(x, x - 1, x + 1));
+        }
+        if width > 1 {
+            write(width - 1, self.texel(width - 1, width - 2, width - 1));
         }
+    }
+}
+
+impl From<&HeightMap> for (Texture, HeightEncoding) {
+    fn from(map: &HeightMap) -> (Texture, HeightEncoding) {
+        let encoding = map.height_encoding();
+        (map.to_texture(encoding), encoding)
+    }
+}
+
+impl HeightMap {
+    /// Builds the heightmap texture one row at a time, in parallel, reading the map in place, given
+    /// an encoding covering its [surface heights](surface_height)
+    pub fn to_texture(&self, encoding: HeightEncoding) -> Texture {
+        let map = self.view();
+        let (width, height) = (map.width, map.height);
 
-        let texture = Texture {
+        let mut bytes = vec![0; width * height * 4];
+        bytes.par_chunks_mut(width * 4).enumerate().for_each(|(y, out)| {
+            HeightmapRows::new(map, y, encoding).write(out);
+        });
+
+        Texture {
             data: bytes,
-            size: Extent3d::new(self.0.width as u32, self.0.height as u32, 1),
+            size: Extent3d::new(width as u32, height as u32, 1),
             format: TextureFormat::Rgba8Uint,
             dimension: TextureDimension::D2,
             sampler: SamplerDescriptor {
-                address_mode_u: AddressMode::Repeat,
-                address_mode_v: AddressMode::Repeat,
-                address_mode_w: AddressMode::Repeat,
+                address_mode_u: AddressMode::ClampToEdge,
+                address_mode_v: AddressMode::ClampToEdge,
+                address_mode_w: AddressMode::ClampToEdge,
                 ..Default::default()
             },
-        };
-
-        (texture, max as u16)
+        }
     }
 }
 
 impl HeightmapMipMap {
-    pub fn to_texture(&self, max_y: u16) -> Texture {
-        const HEIGHT_BITS: u8 = 8;
+    /// Builds the mipmap texture one row at a time, in parallel
+    pub fn to_texture(&self, encoding: HeightEncoding) -> Texture {
+        let mut bytes = vec![0; self.width * self.height];
 
-        let factor = ((1 << HEIGHT_BITS) - 1) as f32 / max_y as f32;
-        let mut bytes = Vec::with_capacity(self.height * self.width);
-
-        for (y, x) in (0..self.height).cartesian_product(0..self.width) {
-            bytes.write_u8((self.get(x as u32, y as u32).0 as f32 * factor) as u8).unwrap();
-        }
+        bytes.par_chunks_mut(self.width).enumerate().for_each(|(y, row)| {
+            for (x, texel) in row.iter_mut().enumerate() {
+                *texel = encoding.encode(self.get(x as u32, y as u32));
+            }
+        });
 
         Texture {
             data: bytes,
@@ -255,9 +468,9 @@ impl HeightmapMipMap {
             format: TextureFormat::R8Uint,
             dimension: TextureDimension::D2,
             sampler: SamplerDescriptor {
-                address_mode_u: AddressMode::Repeat,
-                address_mode_v: AddressMode::Repeat,
-                address_mode_w: AddressMode::Repeat,
+                address_mode_u: AddressMode::ClampToEdge,
+                address_mode_v: AddressMode::ClampToEdge,
+                address_mode_w: AddressMode::ClampToEdge,
                 ..Default::default()
             },
         }
@@ -265,12 +478,13 @@ impl HeightmapMipMap {
 }
 
 const ZOOM: u8 = 3;
-const THREE_POW_ZOOM: f32 = 3.0 * 3.0 * 3.0;
+const THREE_POW_ZOOM: f32 = 3u32.pow(ZOOM as u32) as f32;
 
 pub const TOP_LEFT_TILE: TileCoord = TileCoord { x: 23.0, y: 3.0 };
+#[allow(dead_code)]
 pub const TOP_LEFT_LAT_LONG: LatLong = LatLong { latitude: 70.0, longitude: -26.6666 };
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct LatLong {
     pub latitude: f32,
     pub longitude: f32,
@@ -299,6 +513,13 @@ impl TileCoord {
         }
     }
 
+    pub fn from_world_space(position: Vec2) -> TileCoord {
+        TileCoord {
+            x: position.x / (1000.0 * XYZ_SCALE) + TOP_LEFT_TILE.x,
+            y: position.y / (1000.0 * XYZ_SCALE) + TOP_LEFT_TILE.y,
+        }
+    }
+
     pub fn to_world_space(self) -> Vec2 {
         Vec2 {
             x: (self.x - TOP_LEFT_TILE.x) * 1000.0 * XYZ_SCALE,
@@ -312,3 +533,52 @@ impl TileCoord {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heightmap_texture_marks_water_and_coast() {
+        // Water in the top left corner and nowhere else
+        let heights = vec![Height(-10), Height(50), Height(50), Height(50), Height(50), Height(50)];
+        let is_water = heights.iter().map(|height| height.0 < 0).collect();
+        let map = HeightMap::from_map(&rome_map::Map { width: 3, height: 2, height_map: heights, is_water });
+
+        let (texture, encoding) = <(Texture, HeightEncoding)>::from(&map);
+        let terrain_types: Vec<u8> = texture.data.chunks(4).map(|texel| texel[2]).collect();
+
+        assert_eq!(encoding, HeightEncoding { min: 0, max: 50 });
+        assert_eq!(terrain_types, vec![1, 2, 0, 2, 2, 0]);
+    }
+
+    /// 3 by 3 map rising by 10 metres a row, with water in the middle
+    fn slope() -> HeightMap {
+        let heights = (0..9).map(|i| Height(i / 3 * 10)).collect();
+        let is_water = (0..9).map(|i| i == 4).collect();
+        HeightMap::from_map(&rome_map::Map { width: 3, height: 3, height_map: heights, is_water })
+    }
+
+    #[test]
+    fn first_row_texels_clamp_the_row_above() {
+        let map = slope();
+        let rows = HeightmapRows::new(map.view(), 0, map.height_encoding());
+
+        // The row above is the row itself, so only the water below makes these coast
+        assert_eq!([rows.texel(0, 0, 1)[2], rows.texel(1, 0, 2)[2], rows.texel(2, 1, 2)[2]], [2, 2, 2]);
+        assert_eq!(rows.texel(1, 0, 2)[0], 0);
+        // The row below is higher, so the normal leans back towards the top of the map
+        assert!(rows.texel(2, 1, 2)[3] < 128);
+    }
+
+    #[test]
+    fn last_row_texels_clamp_the_row_below() {
+        let map = slope();
+        let rows = HeightmapRows::new(map.view(), 2, map.height_encoding());
+
+        assert_eq!([rows.texel(0, 0, 1)[2], rows.texel(1, 0, 2)[2], rows.texel(2, 1, 2)[2]], [2, 2, 2]);
+        assert_eq!(rows.texel(1, 0, 2)[0], u8::MAX);
+        // The row below is the row itself, so the slope ends level
+        assert_eq!(rows.texel(1, 0, 2)[1..], [128, 2, 128]);
+    }
+}
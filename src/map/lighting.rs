@@ -0,0 +1,156 @@
+use bevy::prelude::*;
+use std::f32::consts::PI;
+use crate::map::shader::MapMaterial;
+use crate::map::sky::SkyMaterial;
+use crate::game_time::GameTime;
+use crate::RomeAssets;
+
+/// How far away the point light standing in for the sun is placed, for the PBR entities on the map
+const SUN_LIGHT_DISTANCE: f32 = 1_000_000.0;
+/// Latitude that the sun's position is calculated for (Rome's)
+const REFERENCE_LATITUDE: f32 = 41.9;
+/// Tilt of the earth's axis, in degrees
+const AXIAL_TILT: f32 = 23.44;
+
+const DAY_SKY: Color = Color::rgb_linear(0.45, 0.65, 0.9);
+const TWILIGHT_SKY: Color = Color::rgb_linear(0.85, 0.5, 0.3);
+const NIGHT_SKY: Color = Color::rgb_linear(0.01, 0.01, 0.04);
+
+const DAY_ZENITH: Color = Color::rgb_linear(0.12, 0.3, 0.75);
+const TWILIGHT_ZENITH: Color = Color::rgb_linear(0.15, 0.18, 0.4);
+const NIGHT_ZENITH: Color = Color::rgb_linear(0.0, 0.0, 0.01);
+
+const DAY_LIGHT: Color = Color::rgb_linear(1.0, 0.98, 0.92);
+const TWILIGHT_LIGHT: Color = Color::rgb_linear(1.0, 0.6, 0.35);
+const NIGHT_LIGHT: Color = Color::rgb_linear(0.0, 0.0, 0.0);
+
+const DAY_AMBIENT: f32 = 0.1;
+const NIGHT_AMBIENT: f32 = 0.04;
+
+/// The sun, which lights the map
+pub struct Sun {
+    /// Unit vector pointing from the map towards the sun
+    pub direction: Vec3,
+    /// Colour of the light falling on the parts of the map facing the sun
+    pub colour: Color,
+    /// How brightly lit the parts of the map facing away from the sun are, from 0 to 1
+    pub ambient_strength: f32,
+}
+
+impl Default for Sun {
+    fn default() -> Self {
+        Sun {
+            direction: Vec3::new(-1.0, 0.2, -0.3).normalize(),
+            colour: DAY_LIGHT,
+            ambient_strength: DAY_AMBIENT,
+        }
+    }
+}
+
+impl Sun {
+    /// The sun as seen from [`REFERENCE_LATITUDE`] at the given time
+    pub fn at(time: &GameTime) -> Sun {
+        let latitude = REFERENCE_LATITUDE.to_radians();
+        // Furthest north on the summer solstice, around the 21st of June
+        let declination = -AXIAL_TILT.to_radians() * (2.0 * PI * (time.time_of_year() + 10.0 / 365.0)).cos();
+        // Zero at midday, negative in the morning
+        let hour_angle = (time.time_of_day() - 0.5) * 2.0 * PI;
+
+        let east = -declination.cos() * hour_angle.sin();
+        let north = declination.sin() * latitude.cos() - declination.cos() * hour_angle.cos() * latitude.sin();
+        let up = declination.sin() * latitude.sin() + declination.cos() * hour_angle.cos() * latitude.cos();
+
+        // The map's x axis points east and its z axis points south
+        let direction = Vec3::new(east, up, -north).normalize();
+        let elevation = up.asin().to_degrees();
+
+        Sun {
+            direction,
+            colour: sky_gradient(elevation, NIGHT_LIGHT, TWILIGHT_LIGHT, DAY_LIGHT),
+            ambient_strength: lerp(NIGHT_AMBIENT, DAY_AMBIENT, smoothstep(-6.0, 6.0, elevation)),
+        }
+    }
+
+    /// Colour of the sky at the horizon when the sun is at its current position. The map's fog is
+    /// this colour too.
+    pub fn horizon_colour(&self) -> Color {
+        sky_gradient(self.elevation(), NIGHT_SKY, TWILIGHT_SKY, DAY_SKY)
+    }
+
+    /// Colour of the sky straight up when the sun is at its current position
+    pub fn zenith_colour(&self) -> Color {
+        sky_gradient(self.elevation(), NIGHT_ZENITH, TWILIGHT_ZENITH, DAY_ZENITH)
+    }
+
+    /// Angle of the sun above the horizon, in degrees
+    fn elevation(&self) -> f32 {
+        self.direction.y.asin().to_degrees()
+    }
+
+    pub fn sky_material(&self) -> SkyMaterial {
+        SkyMaterial {
+            sun_direction: self.direction,
+            sun_colour: self.colour,
+            horizon_colour: self.horizon_colour(),
+            zenith_colour: self.zenith_colour(),
+        }
+    }
+
+    /// Position of the point light standing in for the sun
+    pub fn light_position(&self) -> Vec3 {
+        self.direction * SUN_LIGHT_DISTANCE
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn lerp_colour(a: Color, b: Color, t: f32) -> Color {
+    Color::rgb_linear(lerp(a.r(), b.r(), t), lerp(a.g(), b.g(), t), lerp(a.b(), b.b(), t))
+}
+
+/// Blends from night, through twilight around the horizon, to day as the sun rises (elevation is
+/// in degrees)
+fn sky_gradient(elevation: f32, night: Color, twilight: Color, day: Color) -> Color {
+    if elevation < 0.0 {
+        lerp_colour(night, twilight, smoothstep(-12.0, 0.0, elevation))
+    } else {
+        lerp_colour(twilight, day, smoothstep(0.0, 15.0, elevation))
+    }
+}
+
+pub fn update_sun_from_time(time: ChangedRes<GameTime>, mut sun: ResMut<Sun>) {
+    *sun = Sun::at(&time);
+}
+
+pub fn update_sun(
+    sun: ChangedRes<Sun>,
+    assets: Res<RomeAssets>,
+    mut clear_colour: ResMut<ClearColor>,
+    mut materials: ResMut<Assets<MapMaterial>>,
+    mut sky_materials: ResMut<Assets<SkyMaterial>>,
+    mut lights: Query<&mut Transform, With<Light>>,
+) {
+    if let Some(material) = materials.get_mut(&assets.map_material) {
+        material.sun_direction = sun.direction;
+        material.sun_colour = sun.colour;
+        material.ambient_strength = sun.ambient_strength;
+        material.fog_colour = sun.horizon_colour();
+    }
+
+    if let Some(material) = sky_materials.get_mut(&assets.sky_material) {
+        *material = sun.sky_material();
+    }
+
+    clear_colour.0 = sun.horizon_colour();
+
+    for mut transform in lights.iter_mut() {
+        transform.translation = sun.light_position();
+    }
+}
@@ -1,203 +1,356 @@
 use bevy::render::mesh::{Indices, Mesh};
 use bevy::render::pipeline::PrimitiveTopology;
-use std::cmp;
-use std::collections::HashMap;
 
-#[derive(Default)]
-struct MeshBuilder {
-    indexer: Indexer<u32>,
-    triangle_indices: Vec<u32>,
+/// Shape of the clipmap mesh
+#[derive(Copy, Clone, Debug)]
+pub struct ClipmapConfig {
+    /// How many grid cells to use most-detailed LOD for, divided by two. Must be even.
+    pub half_extent: u32,
+    /// How many cells each LOD overlaps the next coarsest one by. Each LOD is snapped to its own
+    /// grid in the vertex shader, so LOD `n` can be offset from LOD `n + 1` by up to one and a half of
+    /// its cells. Two cells of overlap make sure that there is never a gap between them. Only the
+    /// coarser LOD is drawn where they overlap (see `map.frag`), and the finer one is geomorphed to
+    /// the coarser one's triangles before then, so that the seam is invisible.
+    pub pad: u32,
+    pub lod_levels: u8,
 }
 
-impl MeshBuilder {
-    fn push_triangle(&mut self, points: [[f32; 3]; 3]) {
-        for point in &points {
-            self.triangle_indices.push(self.indexer.index(*point));
+impl Default for ClipmapConfig {
+    fn default() -> Self {
+        ClipmapConfig {
+            half_extent: 512 / 2,
+            pad: 2,
+            lod_levels: 6,
         }
     }
+}
 
-    fn build(self) -> Mesh {
-        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-        mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, self.indexer.into_positions());
-        mesh.set_indices(Some(Indices::U32(self.triangle_indices)));
-        mesh
-    }
+/// Vertices and indices of a single LOD ring.
+///
+/// Vertices are laid out on a lattice with a spacing of one cell for LOD 0, and half a cell for the
+/// others (so that each of their cells can have a centre vertex). `lattice_radius` is how many
+/// lattice points there are from the centre of the ring to its outer edge.
+struct Ring {
+    lod_level: u8,
+    lattice_radius: i32,
+    /// Lattice points strictly inside this radius are covered by finer LODs
+    hole_radius: i32,
+    /// Index of each lattice point, or `u32::MAX` if it is unused
+    lattice: Vec<u32>,
 }
 
-// Adapted from https://github.com/morgan3d/misc/blob/master/terrain/Terrain.cpp
-#[rustfmt::skip]
-pub fn build_mesh(lod_levels: u8) -> Mesh {
-    let mut builder = MeshBuilder::default();
-
-    // How many grid cells to use most-detailed LOD for, divided by two
-    let g = 512 / 2; // TODO ?
-    let pad = 1; // TODO
-
-    for lod_level in 0..lod_levels {
-        let step: usize = 1 << (lod_level as usize);
-        let half_step: isize = (step >> 1) as isize;
-        // Radius that the LOD takes up
-        let lod_radius: isize = step as isize * (g + pad);
-
-        for z in (-lod_radius..lod_radius).step_by(step) {
-            for x in (-lod_radius..lod_radius).step_by(step) {
-                // Don't draw inside of other LOD's areas
-                if cmp::max((x + half_step).abs(), (z + half_step).abs()) >= g * half_step {
-
-                    let (x, z, l, s) = (x as f32, z as f32, lod_level as f32, step as f32);
-
-                    // y is set to LOD level (`l`) for use in the shader
-                    //        x       y   z
-                    let a = [ x,      l,  z     ];
-                    let c = [ x + s,  l,  z     ];
-                    let g = [ x,      l,  z + s ];
-                    let i = [ x + s,  l,  z + s ];
-
-                    if lod_level > 0 {
-                        // Tessellate the square as such:
-                        //   A-----B-----C   ^     ^
-                        //   | \   |   / |   |   half-step
-                        //   |   \ | /   |         |
-                        //   D-----E-----F  step   v
-                        //   |   / | \   |
-                        //   | /   |   \ |   |
-                        //   G-----H-----I   v
-                        //   <-   step  ->
-
-                        let hs = s / 2.0; // half-step
-
-                        // y is set to LOD level (`l`) for use in the shader
-                        //        x        y   z
-                        let b = [ x + hs,  l,  z      ];
-                        let d = [ x,       l,  z + hs ];
-                        let e = [ x + hs,  l,  z + hs ];
-                        let f = [ x + s,   l,  z + hs ];
-                        let h = [ x + hs,  l,  z + s  ];
-
-                        let (x, z) = (x as isize, z as isize);
-
-                        // Stitch the border into the next level
-                        if x == -lod_radius {
-                            //   A-----B-----C
-                            //   | \   |   / |
-                            //   |   \ | /   |
-                            //   |     E-----F
-                            //   |   / | \   |
-                            //   | /   |   \ |
-                            //   G-----H-----I
-                            builder.push_triangle([e, a, g]);
-                        } else {
-                            builder.push_triangle([e, a, d]);
-                            builder.push_triangle([e, d, g]);
-                        }
-
-                        if z == lod_radius - step as isize {
-                            builder.push_triangle([e, g, i]);
-                        } else {
-                            builder.push_triangle([e, g, h]);
-                            builder.push_triangle([e, h, i]);
-                        }
-
-                        if x == lod_radius - step as isize {
-                            builder.push_triangle([e, i, c]);
-                        } else {
-                            builder.push_triangle([e, i, f]);
-                            builder.push_triangle([e, f, c]);
-                        }
-
-                        if z == -lod_radius {
-                            builder.push_triangle([e, c, a]);
-                        } else {
-                            builder.push_triangle([e, c, b]);
-                            builder.push_triangle([e, b, a]);
-                        }
-                    } else {
-                        builder.push_triangle([i, a, g]);
-                        builder.push_triangle([i, c, a]);
-
-                        // TODO LOD stitch
-                    }
+impl Ring {
+    fn new(config: &ClipmapConfig, lod_level: u8, positions: &mut Vec<[f32; 3]>) -> Ring {
+        let (g, pad) = (config.half_extent as i32, config.pad as i32);
+
+        // Distance between two lattice points, in heightmap texels
+        let (spacing, lattice_radius, hole_radius) = if lod_level == 0 {
+            (1.0, g + pad, 0)
+        } else {
+            ((1u32 << (lod_level - 1)) as f32, 2 * (g + pad), g)
+        };
+
+        let width = (lattice_radius * 2 + 1) as usize;
+        let mut ring = Ring {
+            lod_level,
+            lattice_radius,
+            hole_radius,
+            lattice: vec![u32::MAX; width * width],
+        };
+
+        for z in -lattice_radius..=lattice_radius {
+            for x in -lattice_radius..=lattice_radius {
+                if ring.is_used(x, z) {
+                    let idx = ring.lattice_index(x, z);
+                    ring.lattice[idx] = positions.len() as u32;
+
+                    // y is set to LOD level for use in the shader
+                    positions.push([x as f32 * spacing, lod_level as f32, z as f32 * spacing]);
                 }
             }
         }
+
+        ring
     }
 
-    builder.build()
-}
+    fn is_used(&self, x: i32, z: i32) -> bool {
+        let r = self.lattice_radius;
 
-type HashF32 = ordered_float::OrderedFloat<f32>;
+        if self.lod_level == 0 {
+            return true;
+        }
 
-fn hash_f32(f: f32) -> HashF32 {
-    ordered_float::OrderedFloat(f)
-}
+        // Covered by the finer LODs
+        if x.abs() < self.hole_radius && z.abs() < self.hole_radius {
+            return false;
+        }
 
-trait Index: Copy {
-    fn zero() -> Self;
-    fn increment(&mut self);
-    fn as_usize(&self) -> usize;
-}
+        // Midpoints on the outer edge are skipped so that it can be stitched into the next LOD
+        let on_vertical_edge = x.abs() == r && z % 2 != 0;
+        let on_horizontal_edge = z.abs() == r && x % 2 != 0;
+        !(on_vertical_edge || on_horizontal_edge)
+    }
 
-impl Index for u16 {
-    fn zero() -> Self {
-        0
+    fn lattice_index(&self, x: i32, z: i32) -> usize {
+        let width = self.lattice_radius * 2 + 1;
+        ((x + self.lattice_radius) + (z + self.lattice_radius) * width) as usize
     }
 
-    fn increment(&mut self) {
-        *self += 1;
+    fn vertex(&self, x: i32, z: i32) -> u32 {
+        let idx = self.lattice[self.lattice_index(x, z)];
+        debug_assert_ne!(idx, u32::MAX, "Lattice point ({}, {}) is unused", x, z);
+        idx
     }
 
-    fn as_usize(&self) -> usize {
-        *self as usize
+    #[rustfmt::skip]
+    fn push_triangles(&self, indices: &mut Vec<u32>) {
+        let r = self.lattice_radius;
+        let mut push_triangle = |points: [(i32, i32); 3]| {
+            for &(x, z) in &points {
+                indices.push(self.vertex(x, z));
+            }
+        };
+
+        if self.lod_level == 0 {
+            for z in -r..r {
+                for x in -r..r {
+                    let a = (x,      z    );
+                    let c = (x + 1,  z    );
+                    let g = (x,      z + 1);
+                    let i = (x + 1,  z + 1);
+
+                    // LOD 1's inner edge has the same vertex spacing as LOD 0, so there is nothing
+                    // to stitch here - the vertex shader geomorphs the border instead.
+                    push_triangle([i, a, g]);
+                    push_triangle([i, c, a]);
+                }
+            }
+
+            return;
+        }
+
+        for z in (-r..r).step_by(2) {
+            for x in (-r..r).step_by(2) {
+                // Don't draw inside of other LOD's areas
+                if x >= -self.hole_radius && x < self.hole_radius && z >= -self.hole_radius && z < self.hole_radius {
+                    continue;
+                }
+
+                // Tessellate the square as such:
+                //   A-----B-----C   ^     ^
+                //   | \   |   / |   |   half-step
+                //   |   \ | /   |         |
+                //   D-----E-----F  step   v
+                //   |   / | \   |
+                //   | /   |   \ |   |
+                //   G-----H-----I   v
+                //   <-   step  ->
+                let a = (x,      z    );
+                let b = (x + 1,  z    );
+                let c = (x + 2,  z    );
+                let d = (x,      z + 1);
+                let e = (x + 1,  z + 1);
+                let f = (x + 2,  z + 1);
+                let g = (x,      z + 2);
+                let h = (x + 1,  z + 2);
+                let i = (x + 2,  z + 2);
+
+                // Stitch the border into the next level
+                if x == -r {
+                    //   A-----B-----C
+                    //   | \   |   / |
+                    //   |   \ | /   |
+                    //   |     E-----F
+                    //   |   / | \   |
+                    //   | /   |   \ |
+                    //   G-----H-----I
+                    push_triangle([e, a, g]);
+                } else {
+                    push_triangle([e, a, d]);
+                    push_triangle([e, d, g]);
+                }
+
+                if z == r - 2 {
+                    push_triangle([e, g, i]);
+                } else {
+                    push_triangle([e, g, h]);
+                    push_triangle([e, h, i]);
+                }
+
+                if x == r - 2 {
+                    push_triangle([e, i, c]);
+                } else {
+                    push_triangle([e, i, f]);
+                    push_triangle([e, f, c]);
+                }
+
+                if z == -r {
+                    push_triangle([e, c, a]);
+                } else {
+                    push_triangle([e, c, b]);
+                    push_triangle([e, b, a]);
+                }
+            }
+        }
     }
 }
 
-impl Index for u32 {
-    fn zero() -> Self {
-        0
+impl ClipmapConfig {
+    /// Distance from the centre of the clipmap to its outer edge, in heightmap texels
+    pub fn outer_radius(&self) -> u32 {
+        (self.half_extent + self.pad) << (self.lod_levels - 1)
     }
 
-    fn increment(&mut self) {
-        *self += 1;
+    /// Distance from the camera that the clipmap always covers, in heightmap texels. This is less
+    /// than [`ClipmapConfig::outer_radius`] since the coarsest LOD is snapped to its own grid.
+    pub fn visible_radius(&self) -> u32 {
+        self.outer_radius() - (1 << (self.lod_levels - 1))
     }
+}
 
-    fn as_usize(&self) -> usize {
-        *self as usize
+// Adapted from https://github.com/morgan3d/misc/blob/master/terrain/Terrain.cpp
+pub fn build_mesh(config: &ClipmapConfig) -> Mesh {
+    assert!(config.lod_levels > 0, "Clipmap LOD levels must be greater than zero!");
+    assert!(
+        config.half_extent > 0 && config.half_extent.is_multiple_of(2),
+        "Clipmap half extent must be even and greater than zero!"
+    );
+
+    let mut positions = Vec::new();
+    let mut indices = Vec::new();
+
+    for lod_level in 0..config.lod_levels {
+        let ring = Ring::new(config, lod_level, &mut positions);
+        ring.push_triangles(&mut indices);
     }
-}
 
-struct Indexer<I: Index> {
-    hash: HashMap<[HashF32; 3], I>,
-    n: I,
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
 }
 
-impl<I: Index> Default for Indexer<I> {
-    fn default() -> Self {
-        Indexer {
-            hash: HashMap::new(),
-            n: I::zero(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::render::mesh::VertexAttributeValues;
+    use std::collections::HashSet;
+
+    fn positions(mesh: &Mesh) -> &[[f32; 3]] {
+        match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float3(positions)) => positions,
+            _ => panic!("Mesh has no positions"),
         }
     }
-}
 
-impl<I: Index> Indexer<I> {
-    fn index(&mut self, i: [f32; 3]) -> I {
-        let n = &mut self.n;
-        *self
-            .hash
-            .entry([hash_f32(i[0]), hash_f32(i[1]), hash_f32(i[2])])
-            .or_insert_with(|| {
-                let old = *n;
-                n.increment();
-                old
-            })
-    }
-
-    fn into_positions(self) -> Vec<[f32; 3]> {
-        let mut vec = vec![[0.0, 0.0, 0.0]; self.n.as_usize()];
-        for (pos, idx) in self.hash.into_iter() {
-            vec[idx.as_usize()] = [pos[0].0, pos[1].0, pos[2].0];
+    fn triangles(mesh: &Mesh) -> Vec<[[i32; 3]; 3]> {
+        let positions = positions(mesh);
+        let indices = match mesh.indices() {
+            Some(Indices::U32(indices)) => indices,
+            _ => panic!("Mesh has no u32 indices"),
+        };
+
+        let vertex = |idx: u32| {
+            let [x, y, z] = positions[idx as usize];
+            [x as i32, y as i32, z as i32]
+        };
+
+        indices
+            .chunks(3)
+            .map(|tri| [vertex(tri[0]), vertex(tri[1]), vertex(tri[2])])
+            .collect()
+    }
+
+    fn expected_triangles(config: &ClipmapConfig, lod_level: u8) -> usize {
+        let (g, pad) = (config.half_extent as usize, config.pad as usize);
+
+        if lod_level == 0 {
+            (2 * (g + pad)).pow(2) * 2
+        } else {
+            let cells = (2 * (g + pad)).pow(2) - g.pow(2);
+            // Each outer edge cell has one triangle fewer due to stitching
+            let outer_cells = 4 * 2 * (g + pad);
+            cells * 8 - outer_cells
         }
+    }
+
+    #[test]
+    fn triangle_counts() {
+        let configs = [
+            ClipmapConfig::default(),
+            ClipmapConfig { half_extent: 8, pad: 1, lod_levels: 3 },
+            ClipmapConfig { half_extent: 2, pad: 0, lod_levels: 1 },
+        ];
+
+        for config in &configs {
+            let triangles = triangles(&build_mesh(config));
+
+            for lod_level in 0..config.lod_levels {
+                let count = triangles.iter().filter(|t| t[0][1] == lod_level as i32).count();
+                assert_eq!(count, expected_triangles(config, lod_level), "LOD {} of {:?}", lod_level, config);
+            }
+
+            let total: usize = (0..config.lod_levels).map(|l| expected_triangles(config, l)).sum();
+            assert_eq!(triangles.len(), total);
+        }
+    }
 
-        vec
+    #[test]
+    fn no_unused_vertices() {
+        let mesh = build_mesh(&ClipmapConfig { half_extent: 8, pad: 2, lod_levels: 4 });
+        let indices = match mesh.indices() {
+            Some(Indices::U32(indices)) => indices,
+            _ => panic!("Mesh has no u32 indices"),
+        };
+
+        let used: HashSet<u32> = indices.iter().copied().collect();
+        assert_eq!(used.len(), positions(&mesh).len());
+    }
+
+    fn gcd(a: i32, b: i32) -> i32 {
+        if b == 0 { a.abs() } else { gcd(b, a % b) }
+    }
+
+    #[test]
+    fn no_t_junctions() {
+        let config = ClipmapConfig { half_extent: 8, pad: 2, lod_levels: 4 };
+        let triangles = triangles(&build_mesh(&config));
+
+        let vertices: HashSet<[i32; 3]> = triangles.iter().flatten().copied().collect();
+
+        for tri in &triangles {
+            for (p, q) in [(tri[0], tri[1]), (tri[1], tri[2]), (tri[2], tri[0])].iter() {
+                let (dx, dz) = (q[0] - p[0], q[2] - p[2]);
+                let n = gcd(dx, dz);
+
+                for k in 1..n {
+                    let between = [p[0] + dx / n * k, p[1], p[2] + dz / n * k];
+                    assert!(!vertices.contains(&between), "T-junction at {:?} on edge {:?}-{:?}", between, p, q);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn outer_edges_match_next_lod() {
+        let config = ClipmapConfig { half_extent: 8, pad: 2, lod_levels: 4 };
+        let triangles = triangles(&build_mesh(&config));
+
+        for lod_level in 0..config.lod_levels - 1 {
+            let outer_radius = (1 << lod_level) * (config.half_extent + config.pad) as i32;
+            // Vertex spacing of the next LOD
+            let coarse_spacing = 1 << lod_level;
+
+            let outer_vertices = triangles
+                .iter()
+                .flatten()
+                .filter(|v| v[1] == lod_level as i32)
+                .filter(|v| v[0].abs() == outer_radius || v[2].abs() == outer_radius);
+
+            for v in outer_vertices {
+                assert_eq!(v[0] % coarse_spacing, 0, "{:?} is between LOD {} vertices", v, lod_level + 1);
+                assert_eq!(v[2] % coarse_spacing, 0, "{:?} is between LOD {} vertices", v, lod_level + 1);
+            }
+        }
     }
 }
@@ -1,4 +1,6 @@
-use rome_map::{Height, Map};
+use rome_map::Height;
+use rome_map::raw::MapView;
+use crate::map::surface_height;
 
 pub struct HeightmapMipMap {
     pub width: usize,
@@ -12,39 +14,34 @@ impl HeightmapMipMap {
     }
 }
 
-pub fn generate_mipmaps(map: &Map, levels: usize) -> Vec<HeightmapMipMap> {
+/// Generates mipmaps of the map's [surface heights](surface_height), reading the map in place
+pub fn generate_mipmaps(map: MapView, levels: usize) -> Vec<HeightmapMipMap> {
     assert!(levels > 0, "Mipmap levels must be greater than zero!");
     let mut mipmaps = Vec::with_capacity(levels);
-    mipmaps.push(generate_mipmap((map.width, map.height), &map.height_map));
-
-    let (mut current_heightmap, mut width, mut height) = {
-        let m = &mipmaps[mipmaps.len() - 1];
-        (&m.height_map, m.width, m.height)
-    };
+    mipmaps.push(generate_mipmap((map.width, map.height), |i| surface_height(map, i)));
 
     for _ in 0..levels - 1 {
-        let current_mipmap = generate_mipmap((width, height), current_heightmap);
-        mipmaps.push(current_mipmap);
         let m = &mipmaps[mipmaps.len() - 1];
-        current_heightmap = &m.height_map;
-        width = m.width;
-        height = m.height
+        let current_mipmap = generate_mipmap((m.width, m.height), |i| m.height_map[i]);
+        mipmaps.push(current_mipmap);
     }
 
     mipmaps
 }
 
-fn generate_mipmap((orig_width, orig_height): (usize, usize), orig_map: &[Height]) -> HeightmapMipMap {
+/// Halves a heightmap, whose heights are read by index in row-major order
+fn generate_mipmap((orig_width, orig_height): (usize, usize), orig_map: impl Fn(usize) -> Height) -> HeightmapMipMap {
     let (target_width, target_height) = (orig_width / 2, orig_height / 2);
     let mut mipmap = Vec::with_capacity(target_width * target_height);
+    let sample = |x: usize, y: usize| orig_map(x + y * orig_width).0 as i64;
 
     for target_z in 0..target_height {
         for target_x in 0..target_width {
             let (orig_x, orig_z) = (target_x * 2, target_z * 2);
-            let height_sum = sample((orig_x, orig_z), orig_width, orig_map) +
-                sample((orig_x + 1, orig_z), orig_width, orig_map) +
-                sample((orig_x, orig_z + 1), orig_width, orig_map) +
-                sample((orig_x + 1, orig_z + 1), orig_width, orig_map);
+            let height_sum = sample(orig_x, orig_z) +
+                sample(orig_x + 1, orig_z) +
+                sample(orig_x, orig_z + 1) +
+                sample(orig_x + 1, orig_z + 1);
             mipmap.push(Height((height_sum / 4) as i16));
         }
     }
@@ -55,7 +52,3 @@ fn generate_mipmap((orig_width, orig_height): (usize, usize), orig_map: &[Height
         height_map: mipmap,
     }
 }
-
-fn sample((x, y): (usize, usize), width: usize, map: &[Height]) -> i64 {
-    map[x + y * width].0 as i64
-}
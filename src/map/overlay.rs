@@ -0,0 +1,374 @@
+//! Map modes, which tint the map by who owns each part of it: by owner in the political mode, or by
+//! a heatmap of some value of the owner in the others. Borders between owners are drawn in every
+//! mode but the terrain one. The realms and the land that each owns are loaded from a data file
+//! (`assets/map/world.realms`).
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::core::Byteable;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::texture::{AddressMode, Extent3d, SamplerDescriptor, TextureDimension, TextureFormat};
+use bevy::utils::BoxedFuture;
+use byteorder::{LittleEndian, WriteBytesExt};
+use serde::Deserialize;
+use crate::console::{Console, ConsoleCommand};
+use crate::input::{Action, Actions};
+use crate::loading::progress::LoadingProgress;
+use crate::map::shader::MapMaterial;
+use crate::map::{LatLong, XYZ_SCALE};
+use crate::RomeAssets;
+
+/// How many heightmap texels wide and high each pixel of the ownership raster is
+pub const OWNERSHIP_SCALE: u32 = 4;
+
+/// What the map shows
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum MapMode {
+    #[default]
+    Terrain,
+    /// Tinted by owner
+    Political,
+    /// Tinted from red to green by each owner's [relation](Realm::relation) to the player
+    Diplomatic,
+    /// Tinted by each owner's [wealth](Realm::wealth)
+    Economic,
+}
+
+impl MapMode {
+    pub const ALL: [MapMode; 4] = [MapMode::Terrain, MapMode::Political, MapMode::Diplomatic, MapMode::Economic];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MapMode::Terrain => "terrain",
+            MapMode::Political => "political",
+            MapMode::Diplomatic => "diplomatic",
+            MapMode::Economic => "economic",
+        }
+    }
+
+    /// Value of `map_mode` in `map.frag`
+    fn shader_id(self) -> u32 {
+        match self {
+            MapMode::Terrain => 0,
+            MapMode::Political => 1,
+            MapMode::Diplomatic => 2,
+            MapMode::Economic => 3,
+        }
+    }
+}
+
+/// Something that can own parts of the map
+#[derive(Clone, Debug)]
+pub struct Realm {
+    pub name: String,
+    pub colour: Color,
+    /// From -1 (at war) to 1 (allied)
+    pub relation: f32,
+    /// From 0 to 1
+    pub wealth: f32,
+}
+
+/// Every realm, indexed by the ids in [`Ownership`]. Realm 0 stands for unowned land and is never
+/// drawn.
+pub struct Realms(pub Vec<Realm>);
+
+impl Default for Realms {
+    fn default() -> Self {
+        Realms(vec![Realm {
+            name: "Unowned".to_string(),
+            colour: Color::NONE,
+            relation: 0.0,
+            wealth: 0.0,
+        }])
+    }
+}
+
+/// A realm as laid out in the `MapMaterial_realms` storage buffer in `map.frag`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RealmUniform {
+    colour: [f32; 4],
+    /// Relation and wealth
+    values: [f32; 4],
+}
+
+unsafe impl Byteable for RealmUniform {}
+
+impl Realms {
+    /// The realms as the shader reads them, with their colours in linear space like the rest of its
+    /// lighting
+    pub fn to_uniforms(&self) -> Vec<RealmUniform> {
+        self.0
+            .iter()
+            .map(|realm| RealmUniform {
+                colour: [
+                    realm.colour.r_linear(),
+                    realm.colour.g_linear(),
+                    realm.colour.b_linear(),
+                    realm.colour.a(),
+                ],
+                values: [realm.relation, realm.wealth, 0.0, 0.0],
+            })
+            .collect()
+    }
+}
+
+/// Which realm owns each part of the map, at [`OWNERSHIP_SCALE`] heightmap texels per pixel
+pub struct Ownership {
+    pub width: usize,
+    pub height: usize,
+    owners: Vec<u16>,
+}
+
+impl Ownership {
+    /// Unowned raster covering a heightmap of the given size
+    pub fn unowned(map_width: usize, map_height: usize) -> Ownership {
+        let (width, height) = Ownership::raster_size(map_width, map_height);
+
+        Ownership {
+            width,
+            height,
+            owners: vec![0; width * height],
+        }
+    }
+
+    fn raster_size(map_width: usize, map_height: usize) -> (usize, usize) {
+        let scale = OWNERSHIP_SCALE as usize;
+        (map_width.div_ceil(scale), map_height.div_ceil(scale))
+    }
+
+    /// Whether the raster lines up with a heightmap of the given size
+    pub fn covers(&self, map_width: usize, map_height: usize) -> bool {
+        (self.width, self.height) == Ownership::raster_size(map_width, map_height)
+    }
+
+    pub fn owner(&self, x: usize, y: usize) -> u16 {
+        self.owners[x + y * self.width]
+    }
+
+    pub fn set_owner(&mut self, x: usize, y: usize, owner: u16) {
+        self.owners[x + y * self.width] = owner;
+    }
+
+    /// Gives every pixel whose centre is inside an outline to a realm. The outline's points are in
+    /// pixels of the raster.
+    pub fn fill(&mut self, outline: &[Vec2], owner: u16) {
+        let top = outline.iter().map(|point| point.y).fold(f32::MAX, f32::min);
+        let bottom = outline.iter().map(|point| point.y).fold(f32::MIN, f32::max);
+        let rows = (top - 0.5).ceil().max(0.0) as usize..((bottom - 0.5).ceil().max(0.0) as usize).min(self.height);
+
+        for y in rows {
+            let centre = y as f32 + 0.5;
+
+            // Where the outline's edges cross the row, which alternate between going into and out
+            // of the outline
+            let mut crossings: Vec<f32> = outline
+                .iter()
+                .zip(outline.iter().cycle().skip(1))
+                .filter(|(a, b)| (a.y <= centre) != (b.y <= centre))
+                .map(|(a, b)| a.x + (centre - a.y) / (b.y - a.y) * (b.x - a.x))
+                .collect();
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+            for span in crossings.chunks_exact(2) {
+                let start = (span[0] - 0.5).ceil().max(0.0) as usize;
+                let end = ((span[1] - 0.5).ceil().max(0.0) as usize).min(self.width);
+                for x in start..end {
+                    self.set_owner(x, y, owner);
+                }
+            }
+        }
+    }
+
+    pub fn to_texture(&self) -> Texture {
+        let mut bytes = Vec::with_capacity(self.owners.len() * 2);
+
+        for &owner in &self.owners {
+            bytes.write_u16::<LittleEndian>(owner).unwrap();
+        }
+
+        Texture {
+            data: bytes,
+            size: Extent3d::new(self.width as u32, self.height as u32, 1),
+            format: TextureFormat::R16Uint,
+            dimension: TextureDimension::D2,
+            sampler: SamplerDescriptor {
+                address_mode_u: AddressMode::ClampToEdge,
+                address_mode_v: AddressMode::ClampToEdge,
+                address_mode_w: AddressMode::ClampToEdge,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The realms and the land that each owns, loaded from a `.realms` file
+#[derive(Deserialize, TypeUuid, Clone, Debug)]
+#[uuid = "7a3e5c19-0b6d-4f28-9e41-c8d2a6f0b357"]
+pub struct PoliticalMap {
+    pub realms: Vec<RealmTerritory>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RealmTerritory {
+    pub name: String,
+    /// sRGB colour
+    pub colour: [f32; 3],
+    /// From -1 (at war) to 1 (allied)
+    pub relation: f32,
+    /// From 0 to 1
+    pub wealth: f32,
+    /// Outlines of the land that the realm owns. Where the outlines of realms overlap, the realm
+    /// listed last owns the land.
+    pub territory: Vec<Vec<LatLong>>,
+}
+
+impl PoliticalMap {
+    /// Every realm, with their ids in the order that they are listed, after the unowned realm 0
+    pub fn realms(&self) -> Realms {
+        let mut realms = Realms::default();
+        realms.0.extend(self.realms.iter().map(|realm| Realm {
+            name: realm.name.clone(),
+            colour: Color::rgb(realm.colour[0], realm.colour[1], realm.colour[2]),
+            relation: realm.relation,
+            wealth: realm.wealth,
+        }));
+        realms
+    }
+
+    /// Which realm owns each part of a heightmap of the given size
+    pub fn ownership(&self, map_width: usize, map_height: usize) -> Ownership {
+        let mut ownership = Ownership::unowned(map_width, map_height);
+
+        for (owner, realm) in (1..).zip(&self.realms) {
+            for outline in &realm.territory {
+                let pixels: Vec<Vec2> = outline
+                    .iter()
+                    .map(|location| location.to_tile_coord().to_world_space() / XYZ_SCALE / OWNERSHIP_SCALE as f32)
+                    .collect();
+                ownership.fill(&pixels, owner);
+            }
+        }
+
+        ownership
+    }
+}
+
+fn parse_political_map(bytes: &[u8]) -> Result<PoliticalMap, anyhow::Error> {
+    let map: PoliticalMap = ron::de::from_bytes(bytes)?;
+
+    if map.realms.len() >= u16::MAX as usize {
+        anyhow::bail!("There can be at most {} realms", u16::MAX - 1);
+    }
+
+    Ok(map)
+}
+
+pub struct PoliticalMapLoader {
+    pub progress: LoadingProgress,
+}
+
+impl AssetLoader for PoliticalMapLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], ctx: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let map = self.progress.report(ctx, parse_political_map(bytes))?;
+            ctx.set_default_asset(LoadedAsset::new(map));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["realms"]
+    }
+}
+
+pub fn switch_map_mode(actions: Res<Actions>, mut mode: ResMut<MapMode>) {
+    let modes = [
+        (Action::TerrainMapMode, MapMode::Terrain),
+        (Action::PoliticalMapMode, MapMode::Political),
+        (Action::DiplomaticMapMode, MapMode::Diplomatic),
+        (Action::EconomicMapMode, MapMode::Economic),
+    ];
+
+    for &(action, new_mode) in modes.iter() {
+        if actions.just_pressed(action) {
+            *mode = new_mode;
+        }
+    }
+}
+
+pub fn map_mode_command(
+    mut reader: Local<EventReader<ConsoleCommand>>,
+    commands: Res<Events<ConsoleCommand>>,
+    mut console: ResMut<Console>,
+    mut mode: ResMut<MapMode>,
+) {
+    for command in reader.iter(&commands).filter(|command| command.name == "mapmode") {
+        let name = command.args.first().map(|name| name.to_lowercase());
+
+        match MapMode::ALL.iter().find(|new_mode| Some(new_mode.name()) == name.as_deref()) {
+            Some(&new_mode) => *mode = new_mode,
+            None => console.print("Usage: mapmode <terrain|political|diplomatic|economic>"),
+        }
+    }
+}
+
+pub fn update_map_mode(mode: ChangedRes<MapMode>, assets: Res<RomeAssets>, mut materials: ResMut<Assets<MapMaterial>>) {
+    if let Some(material) = materials.get_mut(&assets.map_material) {
+        material.map_mode = mode.shader_id();
+    }
+}
+
+pub fn update_ownership(
+    ownership: ChangedRes<Ownership>,
+    assets: Res<RomeAssets>,
+    materials: Res<Assets<MapMaterial>>,
+    mut textures: ResMut<Assets<Texture>>,
+) {
+    if let Some(texture) = materials.get(&assets.map_material).and_then(|m| textures.get_mut(&m.overlay)) {
+        *texture = ownership.to_texture();
+    }
+}
+
+pub fn update_realms(realms: ChangedRes<Realms>, assets: Res<RomeAssets>, mut materials: ResMut<Assets<MapMaterial>>) {
+    if let Some(material) = materials.get_mut(&assets.map_material) {
+        material.realms = realms.to_uniforms();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outlines_are_filled_and_encoded_in_the_overlay() {
+        // 4 by 3 pixels, with a realm owning the middle two pixels of the middle row
+        let mut ownership = Ownership::unowned(16, 12);
+        let outline = [Vec2::new(1.0, 1.0), Vec2::new(3.0, 1.0), Vec2::new(3.0, 2.0), Vec2::new(1.0, 2.0)];
+        ownership.fill(&outline, 0x0102);
+
+        let texture = ownership.to_texture();
+        let owners: Vec<u16> = texture.data.chunks(2).map(|texel| u16::from_le_bytes([texel[0], texel[1]])).collect();
+
+        assert_eq!(texture.size, Extent3d::new(4, 3, 1));
+        assert_eq!(texture.format, TextureFormat::R16Uint);
+        assert_eq!(owners, vec![0, 0, 0, 0, 0, 0x0102, 0x0102, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn realms_are_packed_after_the_unowned_realm() {
+        use bevy::render::colorspace::SrgbColorSpace;
+
+        let map = parse_political_map(include_bytes!("../../assets/map/world.realms")).unwrap();
+        let realms = map.realms();
+        let uniforms = realms.to_uniforms();
+
+        assert_eq!(uniforms.len(), map.realms.len() + 1);
+        assert_eq!(uniforms[0].colour[3], 0.0);
+        for (uniform, realm) in uniforms[1..].iter().zip(&map.realms) {
+            // Realm colours are sRGB, and the shader works in linear space
+            assert!((uniform.colour[0] - realm.colour[0].nonlinear_to_linear_srgb()).abs() < 1.0e-4);
+            assert_eq!(uniform.colour[3], 1.0);
+            assert_eq!(uniform.values, [realm.relation, realm.wealth, 0.0, 0.0]);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! The strategic "paper map": when zoomed far out, the 3D terrain flattens and fades into a
+//! stylised parchment map with inked coastlines.
+use bevy::prelude::*;
+use std::ops::RangeInclusive;
+use crate::map::shader::MapMaterial;
+use crate::RomeAssets;
+
+/// Camera zoom distances over which the terrain fades into the paper map
+pub const FADE_DISTANCE: RangeInclusive<f32> = 300.0..=500.0;
+
+/// How far the map has faded into the paper map, from 0 (3D terrain) to 1 (paper map). Other
+/// overlays that only make sense at one zoom level can fade with this.
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
+pub struct PaperMap {
+    pub fade: f32,
+}
+
+impl PaperMap {
+    pub fn at_zoom_distance(zoom_distance: f32) -> PaperMap {
+        let (start, end) = (*FADE_DISTANCE.start(), *FADE_DISTANCE.end());
+        let t = ((zoom_distance - start) / (end - start)).clamp(0.0, 1.0);
+        PaperMap { fade: t * t * (3.0 - 2.0 * t) }
+    }
+}
+
+pub fn update_paper_map(cameras: Query<&goshawk::RtsCamera>, mut paper_map: ResMut<PaperMap>) {
+    if let Some(camera) = cameras.iter().next() {
+        let new = PaperMap::at_zoom_distance(camera.zoom_distance);
+
+        // Only write when it changes, so that the material isn't re-uploaded every frame
+        if *paper_map != new {
+            *paper_map = new;
+        }
+    }
+}
+
+pub fn update_material(
+    paper_map: ChangedRes<PaperMap>,
+    assets: Res<RomeAssets>,
+    mut materials: ResMut<Assets<MapMaterial>>,
+) {
+    if let Some(material) = materials.get_mut(&assets.map_material) {
+        material.paper_map = paper_map.fade;
+    }
+}
@@ -3,36 +3,97 @@ use bevy::reflect::TypeUuid;
 use bevy::render::pipeline::*;
 use bevy::render::render_graph::*;
 use bevy::render::renderer::RenderResources;
-use bevy::render::shader::{ShaderStage, ShaderStages};
+use bevy::render::shader::ShaderStages;
 use once_cell::sync::OnceCell;
-
-pub const VERTEX_SHADER: &str = include_str!("../../assets/map/shader/map.vert");
-pub const FRAGMENT_SHADER: &str = include_str!("../../assets/map/shader/map.frag");
+use crate::RomeAssets;
+use crate::console::{Console, ConsoleCommand};
+use crate::input::{Action, Actions};
+use crate::map::splat::TerrainLayerUniform;
+use crate::map::overlay::RealmUniform;
 
 static PIPELINE: OnceCell<Handle<PipelineDescriptor>> = OnceCell::new();
 
 #[derive(RenderResources, Default, TypeUuid)]
 #[uuid = "4cb07614-40a2-45d6-ae3f-20ca7e800f1f"]
 pub struct MapMaterial {
-    pub forest: Handle<Texture>,
-    pub sand: Handle<Texture>,
+    /// See [`TerrainLayers::build_atlas`](crate::map::splat::TerrainLayers::build_atlas)
+    pub layer_atlas: Handle<Texture>,
+    #[render_resources(buffer)]
+    pub terrain_layers: Vec<TerrainLayerUniform>,
     pub heightmap: Handle<Texture>,
     pub mipmap: Handle<Texture>,
+    /// See [`water::to_texture`](crate::map::water::to_texture)
+    pub water: Handle<Texture>,
+    /// Non-zero if each clipmap LOD should be tinted a different colour (see [`MapDebugSettings`])
+    pub lod_colours: u32,
+    /// Non-zero if the edges of the clipmap mesh's triangles should be drawn (see
+    /// [`MapDebugSettings`])
+    pub wireframe: u32,
+    /// [`ClipmapConfig::half_extent`](crate::map::mesh::ClipmapConfig::half_extent) of the mesh
+    /// that this material is drawn on
+    pub clipmap_half_extent: f32,
+    /// [`ClipmapConfig::lod_levels`](crate::map::mesh::ClipmapConfig::lod_levels) of the mesh
+    pub clipmap_lod_levels: i32,
+    /// Level of sea level in the heightmap and mipmap textures (see
+    /// [`HeightEncoding::sea_level`](crate::map::HeightEncoding::sea_level))
+    pub sea_level: f32,
+    /// See [`HeightEncoding::levels_per_metre`](crate::map::HeightEncoding::levels_per_metre)
+    pub metres_per_level: f32,
+    /// See [`splat::latitude_mapping`](crate::map::splat::latitude_mapping)
+    pub latitude: Vec2,
+    /// See [`Sun`](crate::map::lighting::Sun)
+    pub sun_direction: Vec3,
+    pub sun_colour: Color,
+    pub ambient_strength: f32,
+    /// See [`Sun::horizon_colour`](crate::map::lighting::Sun::horizon_colour)
+    pub fog_colour: Color,
+    /// Distance from the centre of the map mesh, in world units, at which the fog completely hides
+    /// the map. This should be just inside of the clipmap's edge.
+    pub fog_distance: f32,
+    /// See [`PaperMap::fade`](crate::map::paper::PaperMap::fade)
+    pub paper_map: f32,
+    /// See [`MapMode`](crate::map::overlay::MapMode)
+    pub map_mode: u32,
+    /// See [`Ownership::to_texture`](crate::map::overlay::Ownership::to_texture)
+    pub overlay: Handle<Texture>,
+    /// See [`OWNERSHIP_SCALE`](crate::map::overlay::OWNERSHIP_SCALE)
+    pub overlay_scale: f32,
+    #[render_resources(buffer)]
+    pub realms: Vec<RealmUniform>,
+}
+
+/// Debugging aids for the map renderer
+#[derive(Default)]
+pub struct MapDebugSettings {
+    /// Tint each clipmap LOD a different colour, blending between them where they geomorph
+    pub lod_colours: bool,
+    /// Draw the edges of the clipmap mesh's triangles over the map
+    pub wireframe: bool,
 }
 
+/// Seconds since startup, for animating the map. This is attached to the map entity.
 #[derive(RenderResources, Default)]
 pub struct TimeNode {
     time: f32,
 }
 
+/// World space position of the camera, for view dependent shading of the map. This is attached to
+/// the map entity.
+#[derive(RenderResources, Default)]
+pub struct ViewNode {
+    camera_position: Vec3,
+}
+
+/// Sets up the map's pipeline. Its shaders are loaded as assets, so that they are reloaded when they
+/// are edited.
 pub fn setup(
     mut pipelines: ResMut<Assets<PipelineDescriptor>>,
-    mut shaders: ResMut<Assets<Shader>>,
+    asset_server: Res<AssetServer>,
     mut render_graph: ResMut<RenderGraph>,
 ) {
     let pipeline_handle = pipelines.add(PipelineDescriptor::default_config(ShaderStages {
-        vertex: shaders.add(Shader::from_glsl(ShaderStage::Vertex, VERTEX_SHADER)),
-        fragment: Some(shaders.add(Shader::from_glsl(ShaderStage::Fragment, FRAGMENT_SHADER))),
+        vertex: asset_server.load("map/shader/map.vert"),
+        fragment: Some(asset_server.load("map/shader/map.frag")),
     }));
 
     render_graph.add_system_node(
@@ -44,21 +105,85 @@ pub fn setup(
         .add_node_edge("map_material", base::node::MAIN_PASS)
         .unwrap();
 
-    // render_graph.add_system_node("time", RenderResourcesNode::<TimeNode>::new(true));
+    render_graph.add_system_node("time", RenderResourcesNode::<TimeNode>::new(true));
+
+    render_graph
+        .add_node_edge("time", base::node::MAIN_PASS)
+        .unwrap();
+
+    render_graph.add_system_node("view", RenderResourcesNode::<ViewNode>::new(true));
+
+    render_graph
+        .add_node_edge("view", base::node::MAIN_PASS)
+        .unwrap();
 
     PIPELINE.set(pipeline_handle).unwrap();
 }
 
+/// Whether the shaders of a pipeline have loaded. A pipeline can't be drawn with until they have.
+pub fn shaders_loaded(pipeline: &Handle<PipelineDescriptor>, pipelines: &Assets<PipelineDescriptor>, shaders: &Assets<Shader>) -> bool {
+    let stages = match pipelines.get(pipeline) {
+        Some(pipeline) => &pipeline.shader_stages,
+        None => return false,
+    };
+
+    shaders.get(&stages.vertex).is_some() && stages.fragment.iter().all(|fragment| shaders.get(fragment).is_some())
+}
+
+pub fn pipeline() -> &'static Handle<PipelineDescriptor> {
+    PIPELINE.get().expect("map::shader::setup must be called first!")
+}
+
 pub fn update_time(time: Res<Time>, mut nodes: Query<&mut TimeNode>) {
     for mut node in nodes.iter_mut() {
         node.time = time.seconds_since_startup() as f32;
     }
 }
 
+pub fn update_view(cameras: Query<&GlobalTransform, With<goshawk::RtsCamera>>, mut nodes: Query<&mut ViewNode>) {
+    if let Some(camera) = cameras.iter().next() {
+        for mut node in nodes.iter_mut() {
+            node.camera_position = camera.translation;
+        }
+    }
+}
+
+pub fn toggle_lod_colours(actions: Res<Actions>, mut settings: ResMut<MapDebugSettings>) {
+    if actions.just_pressed(Action::ToggleLodColours) {
+        settings.lod_colours = !settings.lod_colours;
+    }
+}
+
+/// Runs the `wireframe` and `lodcolors` console commands, which toggle [`MapDebugSettings`]
+pub fn debug_commands(
+    mut reader: Local<EventReader<ConsoleCommand>>,
+    commands: Res<Events<ConsoleCommand>>,
+    mut console: ResMut<Console>,
+    mut settings: ResMut<MapDebugSettings>,
+) {
+    for command in reader.iter(&commands) {
+        let (setting, name) = match command.name.as_str() {
+            "wireframe" => (&mut settings.wireframe, "Wireframe"),
+            "lodcolors" => (&mut settings.lod_colours, "LOD colours"),
+            _ => continue,
+        };
+
+        *setting = !*setting;
+        console.print(format!("{} {}", name, if *setting { "on" } else { "off" }));
+    }
+}
+
+pub fn update_debug_settings(
+    settings: ChangedRes<MapDebugSettings>,
+    assets: Res<RomeAssets>,
+    mut materials: ResMut<Assets<MapMaterial>>,
+) {
+    if let Some(material) = materials.get_mut(&assets.map_material) {
+        material.lod_colours = settings.lod_colours as u32;
+        material.wireframe = settings.wireframe as u32;
+    }
+}
+
 pub fn render_pipelines() -> RenderPipelines {
-    let handle = PIPELINE
-        .get()
-        .expect("map::shader::init must be called first!")
-        .clone();
-    RenderPipelines::from_pipelines(vec![RenderPipeline::new(handle)])
+    RenderPipelines::from_pipelines(vec![RenderPipeline::new(pipeline().clone())])
 }
@@ -0,0 +1,73 @@
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::pipeline::*;
+use bevy::render::render_graph::*;
+use bevy::render::renderer::RenderResources;
+use bevy::render::shader::ShaderStages;
+use once_cell::sync::OnceCell;
+
+static PIPELINE: OnceCell<Handle<PipelineDescriptor>> = OnceCell::new();
+
+/// Colours of the sky, which are set from the [`Sun`](crate::map::lighting::Sun)
+#[derive(RenderResources, Default, TypeUuid)]
+#[uuid = "1f0e8c1a-4bd3-4c59-9a36-8e2b5c3f7d21"]
+pub struct SkyMaterial {
+    pub sun_direction: Vec3,
+    pub sun_colour: Color,
+    /// This is also the colour of the map's fog, so that the map fades into the sky
+    pub horizon_colour: Color,
+    pub zenith_colour: Color,
+}
+
+/// Marks the sky dome, which is kept centred on the camera
+pub struct SkyDome;
+
+pub fn setup(
+    mut pipelines: ResMut<Assets<PipelineDescriptor>>,
+    asset_server: Res<AssetServer>,
+    mut render_graph: ResMut<RenderGraph>,
+) {
+    let mut pipeline = PipelineDescriptor::default_config(ShaderStages {
+        vertex: asset_server.load("map/shader/sky.vert"),
+        fragment: Some(asset_server.load("map/shader/sky.frag")),
+    });
+
+    // The dome is seen from the inside, and drawn on the far plane where the depth buffer is cleared
+    // to
+    pipeline.rasterization_state.as_mut().unwrap().cull_mode = CullMode::None;
+    let depth_stencil_state = pipeline.depth_stencil_state.as_mut().unwrap();
+    depth_stencil_state.depth_compare = CompareFunction::LessEqual;
+    depth_stencil_state.depth_write_enabled = false;
+
+    let pipeline_handle = pipelines.add(pipeline);
+
+    render_graph.add_system_node(
+        "sky_material",
+        AssetRenderResourcesNode::<SkyMaterial>::new(true),
+    );
+
+    render_graph
+        .add_node_edge("sky_material", base::node::MAIN_PASS)
+        .unwrap();
+
+    PIPELINE.set(pipeline_handle).unwrap();
+}
+
+pub fn follow_camera(
+    cameras: Query<&GlobalTransform, With<goshawk::RtsCamera>>,
+    mut domes: Query<&mut Transform, With<SkyDome>>,
+) {
+    if let Some(camera) = cameras.iter().next() {
+        for mut dome in domes.iter_mut() {
+            dome.translation = camera.translation;
+        }
+    }
+}
+
+pub fn pipeline() -> &'static Handle<PipelineDescriptor> {
+    PIPELINE.get().expect("map::sky::setup must be called first!")
+}
+
+pub fn render_pipelines() -> RenderPipelines {
+    RenderPipelines::from_pipelines(vec![RenderPipeline::new(pipeline().clone())])
+}
@@ -0,0 +1,315 @@
+//! Texture splatting: the map is textured by blending together a set of terrain layers (grass,
+//! forest, rock, etc), each weighted by rules on the height, slope and biome of the terrain. The
+//! layers are described in a data file (`assets/map/terrain.layers`), so they can be changed without
+//! touching the shader.
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::core::Byteable;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::colorspace::SrgbColorSpace;
+use bevy::render::texture::{AddressMode, Extent3d, SamplerDescriptor, TextureDimension, TextureFormat};
+use bevy::utils::BoxedFuture;
+use image::imageops::FilterType;
+use image::RgbaImage;
+use serde::Deserialize;
+use crate::loading::progress::LoadingProgress;
+use crate::map::{TileCoord, TOP_LEFT_TILE};
+
+/// The terrain layers that the map is textured with, loaded from a `.layers` file
+#[derive(Deserialize, TypeUuid, Clone, Debug)]
+#[uuid = "9d4c6f0e-32b5-4d7c-8a53-0c1c1e4c5b7a"]
+pub struct TerrainLayers {
+    /// Width and height that each layer's texture is resized to in the layer atlas
+    pub texture_size: u32,
+    pub layers: Vec<TerrainLayer>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct TerrainLayer {
+    pub name: String,
+    /// Asset path of the layer's texture
+    pub texture: String,
+    /// Linear colour that the texture is multiplied by, so that one texture can be reused by
+    /// several layers. The texture is converted to linear colour to be tinted.
+    #[serde(default = "TerrainLayer::default_tint")]
+    pub tint: [f32; 3],
+    /// How many times the texture repeats per heightmap texel
+    pub scale: f32,
+    /// Weight of the layer where all of its rules are fully met
+    #[serde(default = "TerrainLayer::default_strength")]
+    pub strength: f32,
+    /// Height above sea level, in metres
+    #[serde(default)]
+    pub height: Option<Rule>,
+    /// Steepness of the terrain, from 0 (flat) to 1 (vertical)
+    #[serde(default)]
+    pub slope: Option<Rule>,
+    /// Distance to the coast, in heightmap texels
+    #[serde(default)]
+    pub coast_distance: Option<Rule>,
+    /// Latitude in degrees, standing in for climate until the map has biome data
+    #[serde(default)]
+    pub latitude: Option<Rule>,
+}
+
+impl TerrainLayer {
+    fn default_tint() -> [f32; 3] {
+        [1.0, 1.0, 1.0]
+    }
+
+    fn default_strength() -> f32 {
+        1.0
+    }
+}
+
+/// A range of values that a layer appears in. Its weight fades in and out over `blend` either side
+/// of `min` and `max`, and either bound can be left out.
+#[derive(Deserialize, Copy, Clone, Debug)]
+pub struct Rule {
+    #[serde(default = "Rule::unbounded_min")]
+    pub min: f32,
+    #[serde(default = "Rule::unbounded_max")]
+    pub max: f32,
+    pub blend: f32,
+}
+
+impl Rule {
+    const UNBOUNDED: f32 = 1.0e9;
+
+    fn unbounded_min() -> f32 {
+        -Rule::UNBOUNDED
+    }
+
+    fn unbounded_max() -> f32 {
+        Rule::UNBOUNDED
+    }
+
+    fn to_uniform(rule: Option<Rule>) -> [f32; 4] {
+        let rule = rule.unwrap_or(Rule { min: Rule::unbounded_min(), max: Rule::unbounded_max(), blend: 1.0 });
+        // A blend of zero would divide by zero in the shader's smoothstep
+        [rule.min, rule.max, rule.blend.max(f32::EPSILON), 0.0]
+    }
+}
+
+/// A terrain layer as laid out in the `MapMaterial_terrain_layers` storage buffer in `map.frag`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TerrainLayerUniform {
+    /// `min`, `max` and `blend` of each of the layer's [rules](Rule)
+    height: [f32; 4],
+    slope: [f32; 4],
+    coast_distance: [f32; 4],
+    latitude: [f32; 4],
+    /// Tile of the layer atlas, texture scale and strength
+    texture: [f32; 4],
+}
+
+unsafe impl Byteable for TerrainLayerUniform {}
+
+impl TerrainLayers {
+    pub fn to_uniforms(&self) -> Vec<TerrainLayerUniform> {
+        self.layers
+            .iter()
+            .enumerate()
+            .map(|(tile, layer)| TerrainLayerUniform {
+                height: Rule::to_uniform(layer.height),
+                slope: Rule::to_uniform(layer.slope),
+                coast_distance: Rule::to_uniform(layer.coast_distance),
+                latitude: Rule::to_uniform(layer.latitude),
+                texture: [tile as f32, layer.scale, layer.strength, 0.0],
+            })
+            .collect()
+    }
+
+    /// Packs each layer's texture, tinted, into one tile of a vertically stacked sRGB atlas. Textures
+    /// should be given in the same order as the layers.
+    pub fn build_atlas(&self, textures: &[&Texture]) -> Result<Texture, anyhow::Error> {
+        let size = self.texture_size;
+        let mut atlas = RgbaImage::new(size, size * self.layers.len() as u32);
+
+        for (tile, (layer, texture)) in self.layers.iter().zip(textures).enumerate() {
+            if texture.format != TextureFormat::Rgba8UnormSrgb && texture.format != TextureFormat::Rgba8Unorm {
+                anyhow::bail!("Texture of terrain layer {} is {:?}, not RGBA8", layer.name, texture.format);
+            }
+
+            let image = RgbaImage::from_raw(texture.size.width, texture.size.height, texture.data.clone())
+                .ok_or_else(|| anyhow::anyhow!("Texture of terrain layer {} has the wrong size", layer.name))?;
+            let mut image = image::imageops::resize(&image, size, size, FilterType::Triangle);
+
+            // Tints are linear, so sRGB textures are tinted in linear space. Either way, the tinted
+            // colour is stored in the atlas as sRGB.
+            let srgb = texture.format == TextureFormat::Rgba8UnormSrgb;
+            let to_linear: Vec<f32> = (0..=u8::MAX)
+                .map(|level| level as f32 / u8::MAX as f32)
+                .map(|value| if srgb { value.nonlinear_to_linear_srgb() } else { value })
+                .collect();
+
+            for pixel in image.pixels_mut() {
+                for (channel, tint) in pixel.0.iter_mut().zip(&layer.tint) {
+                    let linear = to_linear[*channel as usize] * tint;
+                    *channel = (linear.linear_to_nonlinear_srgb().min(1.0) * u8::MAX as f32).round() as u8;
+                }
+            }
+
+            image::imageops::replace(&mut atlas, &image, 0, tile as u32 * size);
+        }
+
+        Ok(Texture {
+            data: atlas.into_raw(),
+            size: Extent3d::new(size, size * self.layers.len() as u32, 1),
+            format: TextureFormat::Rgba8UnormSrgb,
+            dimension: TextureDimension::D2,
+            sampler: SamplerDescriptor {
+                address_mode_u: AddressMode::Repeat,
+                address_mode_v: AddressMode::Repeat,
+                address_mode_w: AddressMode::Repeat,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// Latitude of the first row of heightmap texels and the (negative) change in latitude per row, for
+/// the shader's latitude rules
+pub fn latitude_mapping() -> Vec2 {
+    let first_row = TOP_LEFT_TILE.to_lat_long().latitude;
+    let second_row = TileCoord { x: TOP_LEFT_TILE.x, y: TOP_LEFT_TILE.y + 1.0 / 1000.0 }
+        .to_lat_long()
+        .latitude;
+    Vec2::new(first_row, second_row - first_row)
+}
+
+fn parse_layers(bytes: &[u8]) -> Result<TerrainLayers, anyhow::Error> {
+    let layers: TerrainLayers = ron::de::from_bytes(bytes)?;
+
+    if layers.layers.is_empty() {
+        anyhow::bail!("There must be at least one terrain layer");
+    }
+
+    Ok(layers)
+}
+
+pub struct TerrainLayersLoader {
+    pub progress: LoadingProgress,
+}
+
+impl AssetLoader for TerrainLayersLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], ctx: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let layers = self.progress.report(ctx, parse_layers(bytes))?;
+            ctx.set_default_asset(LoadedAsset::new(layers));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["layers"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_layers() -> TerrainLayers {
+        ron::de::from_str(include_str!("../../assets/map/terrain.layers")).unwrap()
+    }
+
+    /// Weight of each layer where the terrain has the given height, slope, coast distance and
+    /// latitude, worked out as `map.frag` does
+    fn weights(layers: &TerrainLayers, terrain: [f32; 4]) -> Vec<f32> {
+        fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+            let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+            t * t * (3.0 - 2.0 * t)
+        }
+
+        fn rule_weight(rule: [f32; 4], value: f32) -> f32 {
+            smoothstep(rule[0] - rule[2], rule[0] + rule[2], value)
+                * (1.0 - smoothstep(rule[1] - rule[2], rule[1] + rule[2], value))
+        }
+
+        layers
+            .to_uniforms()
+            .iter()
+            .map(|layer| {
+                let rules = [layer.height, layer.slope, layer.coast_distance, layer.latitude];
+                layer.texture[2] * rules.iter().zip(&terrain).map(|(&rule, &value)| rule_weight(rule, value)).product::<f32>()
+            })
+            .collect()
+    }
+
+    /// Name of the layer with the most weight
+    fn strongest(layers: &TerrainLayers, terrain: [f32; 4]) -> &str {
+        let weights = weights(layers, terrain);
+        let (strongest, _) = weights.iter().enumerate().max_by(|a, b| a.1.partial_cmp(b.1).unwrap()).unwrap();
+        &layers.layers[strongest].name
+    }
+
+    #[test]
+    fn default_layers_parse() {
+        let layers = default_layers();
+        let uniforms = layers.to_uniforms();
+
+        assert_eq!(uniforms.len(), layers.layers.len());
+        for (tile, uniform) in uniforms.iter().enumerate() {
+            assert_eq!(uniform.texture[0], tile as f32);
+        }
+    }
+
+    #[test]
+    fn default_layers_cover_the_terrain() {
+        let layers = default_layers();
+
+        // Height, slope, coast distance and latitude
+        assert_eq!(strongest(&layers, [1000.0, 0.1, 20.0, 50.0]), "forest");
+        // Farmland mixes with grass on flat lowland away from the coast
+        let farmland = layers.layers.iter().position(|layer| layer.name == "farmland").unwrap();
+        assert!(weights(&layers, [100.0, 0.02, 10.0, 42.0])[farmland] > 0.5);
+        assert_eq!(weights(&layers, [100.0, 0.3, 10.0, 42.0])[farmland], 0.0);
+        assert_eq!(strongest(&layers, [1000.0, 0.6, 20.0, 45.0]), "rock");
+        assert_eq!(strongest(&layers, [3000.0, 0.1, 20.0, 45.0]), "snow");
+        assert_eq!(strongest(&layers, [5.0, 0.02, 0.0, 42.0]), "sand");
+
+        // Some layer shows everywhere, so the blend never divides by zero
+        for &terrain in &[[-50.0, 0.0, 0.0, 30.0], [5000.0, 1.0, 100.0, 70.0], [900.0, 0.35, 3.0, 46.0]] {
+            assert!(weights(&layers, terrain).iter().sum::<f32>() > 0.0, "{:?}", terrain);
+        }
+    }
+
+    #[test]
+    fn atlas_tiles_are_tinted_in_linear_space() {
+        let layer = |name: &str, tint| TerrainLayer {
+            name: name.to_string(),
+            texture: String::new(),
+            tint,
+            scale: 1.0,
+            strength: 1.0,
+            height: None,
+            slope: None,
+            coast_distance: None,
+            latitude: None,
+        };
+        let layers = TerrainLayers {
+            texture_size: 2,
+            layers: vec![layer("plain", [1.0, 1.0, 1.0]), layer("tinted", [0.5, 1.0, 0.0])],
+        };
+        let texture = |pixel: [u8; 4]| Texture {
+            data: pixel.iter().copied().cycle().take(4 * 4 * 4).collect(),
+            size: Extent3d::new(4, 4, 1),
+            format: TextureFormat::Rgba8UnormSrgb,
+            ..Default::default()
+        };
+
+        let atlas = layers.build_atlas(&[&texture([10, 128, 250, 255]), &texture([255, 128, 255, 200])]).unwrap();
+        assert_eq!(atlas.size, Extent3d::new(2, 4, 1));
+
+        let texel = |x: usize, y: usize| &atlas.data[(y * 2 + x) * 4..(y * 2 + x) * 4 + 4];
+        for y in 0..2 {
+            assert_eq!(texel(1, y), [10, 128, 250, 255]);
+        }
+        for y in 2..4 {
+            // Half of sRGB white is 188 in sRGB, not 128
+            assert_eq!(texel(0, y), [188, 128, 0, 200]);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! Heights of the terrain as it is drawn, for placing things on the map. This mirrors `map.vert`:
+//! heights come from the same quantised levels as the height textures, each vertex is geomorphed
+//! between LODs in the same way, and heights between vertices are interpolated across the same
+//! triangles as the clipmap mesh.
+use bevy::prelude::*;
+use rome_map::raw::MapView;
+use crate::map::mesh::ClipmapConfig;
+use crate::map::mipmap::HeightmapMipMap;
+use crate::map::paper::PaperMap;
+use crate::map::{surface_height, HeightEncoding, XYZ_SCALE, Y_SCALE};
+
+/// How much longer each step of a [raycast](Terrain::raycast) is than the distance already marched
+const RAYCAST_STEP_GROWTH: f32 = 0.01;
+/// How many times a raycast halves the step that it hit the terrain in
+const RAYCAST_BISECTIONS: usize = 12;
+
+/// Heights of the terrain, as stored in the heightmap and mipmap textures
+pub struct Terrain {
+    width: usize,
+    height: usize,
+    /// Full resolution height levels, in row-major order
+    levels: Vec<u8>,
+    mip_width: usize,
+    mip_height: usize,
+    /// Half resolution height levels, in row-major order
+    mip_levels: Vec<u8>,
+    sea_level: f32,
+    metres_per_level: f32,
+    clipmap: ClipmapConfig,
+}
+
+/// Keeps an entity's transform on the surface of the terrain as it, the camera or the terrain moves
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SnapToTerrain {
+    /// Height above the terrain, in world units
+    pub offset: f32,
+}
+
+impl Terrain {
+    /// Terrain of a map's [surface heights](crate::map::surface_height) and their mipmap
+    pub fn new(
+        map: MapView,
+        mipmap: &HeightmapMipMap,
+        encoding: HeightEncoding,
+        clipmap: ClipmapConfig,
+    ) -> Terrain {
+        Terrain {
+            width: map.width,
+            height: map.height,
+            levels: (0..map.width * map.height).map(|i| encoding.encode(surface_height(map, i))).collect(),
+            mip_width: mipmap.width,
+            mip_height: mipmap.height,
+            mip_levels: mipmap.height_map.iter().map(|&h| encoding.encode(h)).collect(),
+            sea_level: encoding.sea_level(),
+            metres_per_level: 1.0 / encoding.levels_per_metre(),
+            clipmap,
+        }
+    }
+
+    /// Width and height of the map in world space. The map starts at the origin.
+    pub fn world_size(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32) * XYZ_SCALE
+    }
+
+    /// Height of the terrain at a world space position, in world units, as drawn when the map is
+    /// centred on `centre` (the point that the camera is looking at) and faded into the paper map by
+    /// `paper_map`
+    pub fn height_at(&self, position: Vec2, centre: Vec3, paper_map: &PaperMap) -> f32 {
+        let texel = position / XYZ_SCALE;
+        let centre = Vec2::new(centre.x, centre.z) / XYZ_SCALE;
+        self.surface_height(texel, centre) * (1.0 - paper_map.fade) * Y_SCALE * XYZ_SCALE
+    }
+
+    /// Height of the terrain at a world space position, in world units, at full detail. This doesn't
+    /// depend on the camera, but can differ from what is drawn far from it.
+    pub fn detailed_height_at(&self, position: Vec2) -> f32 {
+        let texel = position / XYZ_SCALE;
+        let (cell, f) = (texel.floor(), texel - texel.floor());
+        let corner = |x: f32, y: f32| self.fetch_fullres_height(cell + Vec2::new(x, y));
+        let corners = [corner(0.0, 0.0), corner(1.0, 0.0), corner(0.0, 1.0), corner(1.0, 1.0)];
+        interpolate_triangles(corners, f, true) * Y_SCALE * XYZ_SCALE
+    }
+
+    /// Height above sea level of the heightmap texel at a world space position, in metres, as stored
+    /// in the height textures
+    pub fn elevation_at(&self, position: Vec2) -> f32 {
+        self.fetch_fullres_height(position / XYZ_SCALE) * self.metres_per_level
+    }
+
+    /// Finest clipmap LOD drawn at a world space position when the map is centred on `centre`
+    pub fn lod_at_position(&self, position: Vec2, centre: Vec3) -> u8 {
+        self.lod_at(position / XYZ_SCALE, Vec2::new(centre.x, centre.z) / XYZ_SCALE)
+    }
+
+    /// Where a ray first hits the terrain as drawn (see [`Terrain::height_at`]), if it does within
+    /// `max_distance` world units. The ray is marched in steps that lengthen with distance, so thin
+    /// peaks far away can be missed.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32, centre: Vec3, paper_map: &PaperMap) -> Option<Vec3> {
+        let direction = direction.normalize();
+        let point = |t: f32| origin + direction * t;
+        let above = |t: f32| {
+            let p = point(t);
+            p.y - self.height_at(Vec2::new(p.x, p.z), centre, paper_map)
+        };
+
+        if above(0.0) <= 0.0 {
+            return Some(origin);
+        }
+
+        let (mut near, mut far) = (0.0, 0.0);
+        while above(far) > 0.0 {
+            near = far;
+            far += (far * RAYCAST_STEP_GROWTH).max(XYZ_SCALE);
+
+            if far > max_distance {
+                return None;
+            }
+        }
+
+        for _ in 0..RAYCAST_BISECTIONS {
+            let mid = (near + far) / 2.0;
+            if above(mid) > 0.0 {
+                near = mid;
+            } else {
+                far = mid;
+            }
+        }
+
+        Some(point(far))
+    }
+
+    /// Height in levels relative to sea level of the surface drawn at a texel position
+    fn surface_height(&self, texel: Vec2, centre: Vec2) -> f32 {
+        let lod = self.lod_at(texel, centre);
+        interpolate_lod(lod, texel, |vertex| self.vertex_height(lod, vertex, centre))
+    }
+
+    /// Finest LOD drawn at a texel position. Each LOD is drawn up to the inner edge of the next
+    /// coarsest one, which moves as that LOD is snapped to its grid (see `map.frag`).
+    fn lod_at(&self, texel: Vec2, centre: Vec2) -> u8 {
+        let half_extent = self.clipmap.half_extent as f32;
+
+        (0..self.clipmap.lod_levels - 1)
+            .find(|&lod| {
+                let coarser_grid_size = (2u32 << lod) as f32;
+                let coarser_centre = (centre / coarser_grid_size).round() * coarser_grid_size;
+                (texel - coarser_centre).abs().max_element() <= half_extent * (1u32 << lod) as f32
+            })
+            .unwrap_or(self.clipmap.lod_levels - 1)
+    }
+
+    /// Height of a vertex of the given LOD, geomorphed towards the coarser LOD near its outer edge
+    /// like in `map.vert`
+    fn vertex_height(&self, lod: u8, position: Vec2, centre: Vec2) -> f32 {
+        let grid_size = (1u32 << lod) as f32;
+        let half_extent = self.clipmap.half_extent as f32;
+        let morph_cells = half_extent / 4.0;
+        let lod_distance = ((position - centre).abs() / grid_size).max_element();
+        let morph = ((lod_distance - (half_extent - 1.0 - morph_cells)) / morph_cells).clamp(0.0, 1.0);
+
+        let height = self.sample_lod_height(lod, position);
+
+        if morph > 0.0 {
+            lerp(height, self.sample_coarser_lod_height(lod, position), morph)
+        } else {
+            height
+        }
+    }
+
+    fn sample_lod_height(&self, lod: u8, position: Vec2) -> f32 {
+        if lod == 0 {
+            self.fetch_fullres_height(position)
+        } else {
+            self.sample_mipmap_height(position)
+        }
+    }
+
+    /// Height as drawn by the LOD coarser than the given one, like in `map.vert`
+    fn sample_coarser_lod_height(&self, lod: u8, position: Vec2) -> f32 {
+        interpolate_lod(lod + 1, position, |vertex| self.sample_lod_height(lod + 1, vertex))
+    }
+
+    fn sample_mipmap_height(&self, position: Vec2) -> f32 {
+        let mip_position = position / 2.0;
+        let ipos = mip_position.floor();
+        let f = mip_position - ipos;
+        let fetch = |x: f32, y: f32| self.fetch_mipmap_height(ipos + Vec2::new(x, y));
+
+        let top = lerp(fetch(0.0, 0.0), fetch(1.0, 0.0), f.x);
+        let bottom = lerp(fetch(0.0, 1.0), fetch(1.0, 1.0), f.x);
+        lerp(top, bottom, f.y)
+    }
+
+    fn fetch_fullres_height(&self, position: Vec2) -> f32 {
+        fetch(&self.levels, self.width, self.height, position) - self.sea_level
+    }
+
+    fn fetch_mipmap_height(&self, position: Vec2) -> f32 {
+        fetch(&self.mip_levels, self.mip_width, self.mip_height, position) - self.sea_level
+    }
+}
+
+/// Level at a texel of a row-major texture, clamped to its edges like in the shaders
+fn fetch(levels: &[u8], width: usize, height: usize, position: Vec2) -> f32 {
+    let x = (position.x.max(0.0) as usize).min(width - 1);
+    let y = (position.y.max(0.0) as usize).min(height - 1);
+    levels[x + y * width] as f32
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Interpolates between the heights of the vertices of an LOD's triangles (see `mesh::Ring`) at a
+/// texel position
+fn interpolate_lod(lod: u8, texel: Vec2, vertex_height: impl Fn(Vec2) -> f32) -> f32 {
+    if lod == 0 {
+        let (cell, f) = (texel.floor(), texel - texel.floor());
+        let corner = |x: f32, y: f32| vertex_height(cell + Vec2::new(x, y));
+        let corners = [corner(0.0, 0.0), corner(1.0, 0.0), corner(0.0, 1.0), corner(1.0, 1.0)];
+        return interpolate_triangles(corners, f, true);
+    }
+
+    // Coarser LODs are made of squares of a grid cell each, with a vertex in the centre and each
+    // quarter split into two triangles along the diagonal through the centre
+    let half_cell = (1u32 << (lod - 1)) as f32;
+    let quarter = (texel / half_cell).floor();
+    let f = texel / half_cell - quarter;
+    let corner = |x: f32, y: f32| vertex_height((quarter + Vec2::new(x, y)) * half_cell);
+    let corners = [corner(0.0, 0.0), corner(1.0, 0.0), corner(0.0, 1.0), corner(1.0, 1.0)];
+
+    // The centre is at the top left of the bottom right quarter and vice versa, which are split
+    // along the leading diagonal, while the other two quarters are split along the other one
+    let is_odd = |q: f32| q.rem_euclid(2.0) >= 1.0;
+    interpolate_triangles(corners, f, is_odd(quarter.x) == is_odd(quarter.y))
+}
+
+/// Interpolates across a square split into two triangles, along the leading diagonal (top left to
+/// bottom right) or the other one. Corners are top left, top right, bottom left then bottom right.
+fn interpolate_triangles([tl, tr, bl, br]: [f32; 4], f: Vec2, leading_diagonal: bool) -> f32 {
+    if leading_diagonal {
+        if f.x >= f.y {
+            tl + (tr - tl) * f.x + (br - tr) * f.y
+        } else {
+            tl + (bl - tl) * f.y + (br - bl) * f.x
+        }
+    } else if f.x + f.y <= 1.0 {
+        tl + (tr - tl) * f.x + (bl - tl) * f.y
+    } else {
+        br + (bl - br) * (1.0 - f.x) + (tr - br) * (1.0 - f.y)
+    }
+}
+
+pub fn snap_to_terrain(
+    terrain: Res<Terrain>,
+    paper_map: Res<PaperMap>,
+    cameras: Query<&goshawk::RtsCamera>,
+    mut entities: Query<(&mut Transform, &SnapToTerrain)>,
+) {
+    let centre = match cameras.iter().next() {
+        Some(camera) => camera.looking_at,
+        None => return,
+    };
+
+    for (mut transform, snap) in entities.iter_mut() {
+        let position = Vec2::new(transform.translation.x, transform.translation.z);
+        transform.translation.y = terrain.height_at(position, centre, &paper_map) + snap.offset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::HeightMap;
+    use rome_map::Height;
+    use crate::map::mipmap::generate_mipmaps;
+    use crate::map::mesh::build_mesh;
+    use bevy::render::mesh::{Indices, Mesh, VertexAttributeValues};
+
+    /// A slope rising by one metre per texel in x
+    fn slope() -> Terrain {
+        let (width, height) = (64, 64);
+        let height_map = (0..width * height).map(|i| Height((i % width) as i16)).collect();
+        let is_water = std::iter::repeat_n(false, width * height).collect();
+        let map = HeightMap::from_map(&rome_map::Map { width, height, height_map, is_water });
+        let mipmap = generate_mipmaps(map.view(), 1).remove(0);
+        let encoding = HeightEncoding { min: 0, max: 255 };
+        let clipmap = ClipmapConfig { half_extent: 8, pad: 2, lod_levels: 3 };
+
+        Terrain::new(map.view(), &mipmap, encoding, clipmap)
+    }
+
+    #[test]
+    fn heights_match_vertices_near_camera() {
+        let terrain = slope();
+        let centre = Vec3::new(32.0, 0.0, 32.0) * XYZ_SCALE;
+
+        for x in 28..36 {
+            let position = Vec2::new(x as f32, 30.0) * XYZ_SCALE;
+            let expected = x as f32 * Y_SCALE * XYZ_SCALE;
+            assert!((terrain.height_at(position, centre, &PaperMap::default()) - expected).abs() < 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn heights_are_interpolated_between_vertices() {
+        let terrain = slope();
+        let centre = Vec3::new(32.0, 0.0, 32.0) * XYZ_SCALE;
+        let position = Vec2::new(30.25, 31.5) * XYZ_SCALE;
+        let expected = 30.25 * Y_SCALE * XYZ_SCALE;
+
+        assert!((terrain.height_at(position, centre, &PaperMap::default()) - expected).abs() < 1.0e-5);
+        assert!((terrain.detailed_height_at(position) - expected).abs() < 1.0e-5);
+    }
+
+    #[test]
+    fn rays_hit_the_drawn_surface() {
+        let terrain = slope();
+        let centre = Vec3::new(32.0, 0.0, 32.0) * XYZ_SCALE;
+        let origin = Vec3::new(30.0, 100.0, 33.0) * XYZ_SCALE;
+        let paper_map = PaperMap::default();
+
+        let hit = terrain.raycast(origin, Vec3::new(0.5, -1.0, 0.0), 100.0, centre, &paper_map).unwrap();
+        let surface = terrain.height_at(Vec2::new(hit.x, hit.z), centre, &paper_map);
+        assert!((hit.y - surface).abs() < 1.0e-3);
+
+        assert_eq!(terrain.raycast(origin, Vec3::unit_y(), 100.0, centre, &paper_map), None);
+    }
+
+    /// Bumpy terrain, which no LOD can draw exactly
+    fn hills() -> Terrain {
+        let (width, height) = (64, 64);
+        let height_map = (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                Height(((x * 7 + y * 13) % 17 * 3 + x * x % 11) as i16)
+            })
+            .collect();
+        let is_water = std::iter::repeat_n(false, width * height).collect();
+        let map = HeightMap::from_map(&rome_map::Map { width, height, height_map, is_water });
+        let mipmap = generate_mipmaps(map.view(), 1).remove(0);
+        let encoding = HeightEncoding { min: 0, max: 255 };
+        let clipmap = ClipmapConfig { half_extent: 8, pad: 2, lod_levels: 3 };
+
+        Terrain::new(map.view(), &mipmap, encoding, clipmap)
+    }
+
+    #[test]
+    fn finer_lods_meet_coarser_lods_triangles() {
+        let terrain = hills();
+        let clipmap = terrain.clipmap;
+        let mesh = build_mesh(&clipmap);
+        let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float3(positions)) => positions,
+            _ => panic!("Mesh has no positions"),
+        };
+        let triangles = match mesh.indices() {
+            Some(Indices::U32(indices)) => indices.chunks(3).map(|t| t.iter().map(|&i| positions[i as usize]).collect::<Vec<_>>()),
+            _ => panic!("Mesh has no u32 indices"),
+        };
+        let triangles: Vec<_> = triangles.collect();
+        let half_extent = clipmap.half_extent as f32;
+
+        for &centre in &[Vec2::new(32.0, 32.0), Vec2::new(30.6, 33.3), Vec2::new(33.9, 31.2)] {
+            for lod in 0..clipmap.lod_levels - 1 {
+                let (grid_size, coarser_grid_size) = ((1u32 << lod) as f32, (2u32 << lod) as f32);
+                let origin = (centre / grid_size).round() * grid_size;
+                let coarser_origin = (centre / coarser_grid_size).round() * coarser_grid_size;
+
+                // The finer LOD reaches the coarser one's inner edge, where it stops being drawn
+                let reach = (half_extent + clipmap.pad as f32) * grid_size - (origin - coarser_origin).abs().max_element();
+                assert!(reach >= half_extent * grid_size, "LOD {} stops short of LOD {}", lod, lod + 1);
+
+                // Where the finer LOD's vertices have finished morphing, they are on the coarser
+                // LOD's triangles, so the LODs meet without a seam
+                let spacing = if lod == 0 { 1.0 } else { grid_size / 2.0 };
+                let mut checked = 0;
+                for z in -40..=40 {
+                    for x in -40..=40 {
+                        let vertex = origin + Vec2::new(x as f32, z as f32) * spacing;
+                        let distance = ((vertex - centre).abs() / grid_size).max_element();
+                        if distance < half_extent - 1.0 || distance > half_extent + clipmap.pad as f32 {
+                            continue;
+                        }
+
+                        let coarser = triangles.iter().filter(|t| t[0][1] as u8 == lod + 1).find_map(|t| {
+                            let corner = |i: usize| Vec2::new(t[i][0], t[i][2]) + coarser_origin;
+                            let (a, b, c) = (corner(0), corner(1), corner(2));
+                            let cross = |u: Vec2, v: Vec2| u.x * v.y - u.y * v.x;
+                            let area = cross(b - a, c - a);
+                            let (u, v) = (cross(vertex - a, c - a) / area, cross(b - a, vertex - a) / area);
+                            if u < -1.0e-5 || v < -1.0e-5 || u + v > 1.0 + 1.0e-5 {
+                                return None;
+                            }
+
+                            let height = |p: Vec2| terrain.sample_lod_height(lod + 1, p);
+                            Some(height(a) + (height(b) - height(a)) * u + (height(c) - height(a)) * v)
+                        });
+
+                        if let Some(coarser) = coarser {
+                            let morphed = terrain.vertex_height(lod, vertex, centre);
+                            assert!((morphed - coarser).abs() < 1.0e-3, "LOD {} vertex at {:?}: {} vs {}", lod, vertex, morphed, coarser);
+                            checked += 1;
+                        }
+                    }
+                }
+                assert!(checked > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn paper_map_is_flat() {
+        let terrain = slope();
+        let centre = Vec3::new(32.0, 0.0, 32.0) * XYZ_SCALE;
+        let position = Vec2::new(40.0, 32.0) * XYZ_SCALE;
+
+        assert_eq!(terrain.height_at(position, centre, &PaperMap { fade: 1.0 }), 0.0);
+    }
+}
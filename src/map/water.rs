@@ -0,0 +1,135 @@
+use bevy::prelude::*;
+use bevy::render::texture::{Extent3d, TextureFormat, AddressMode, SamplerDescriptor, TextureDimension};
+use byteorder::WriteBytesExt;
+use rome_map::raw::MapView;
+
+/// Metres of water depth per level of the water texture's depth channel. This must match
+/// `DEPTH_SCALE` in `map.frag`.
+pub const DEPTH_SCALE: f32 = 20.0;
+
+/// Chamfer distance weights for orthogonal and diagonal steps - 3 and 4 approximate 1 and √2
+const ORTHOGONAL_STEP: u16 = 3;
+const DIAGONAL_STEP: u16 = 4;
+
+/// Approximate distance of each pixel to the nearest coastline, in pixels. Both land and water pixels
+/// have a distance, and pixels which border the other kind are on the coastline.
+pub fn coast_distances(map: MapView) -> Vec<u8> {
+    let (width, height) = (map.width, map.height);
+    let is_water = map.is_water();
+    let water = |x: usize, y: usize| is_water[x + y * width];
+
+    let mut distances = vec![u16::MAX; width * height];
+
+    for y in 0..height {
+        for x in 0..width {
+            let is_water = water(x, y);
+            let on_coast = (x > 0 && water(x - 1, y) != is_water)
+                || (x + 1 < width && water(x + 1, y) != is_water)
+                || (y > 0 && water(x, y - 1) != is_water)
+                || (y + 1 < height && water(x, y + 1) != is_water);
+
+            if on_coast {
+                distances[x + y * width] = 0;
+            }
+        }
+    }
+
+    // Two pass chamfer distance transform - first from the top left, then from the bottom right
+    let relax = |distances: &mut Vec<u16>, x: usize, y: usize, nx: isize, ny: isize, step: u16| {
+        let (nx, ny) = (x as isize + nx, y as isize + ny);
+        if nx >= 0 && ny >= 0 && (nx as usize) < width && (ny as usize) < height {
+            let neighbour = distances[nx as usize + ny as usize * width].saturating_add(step);
+            let current = &mut distances[x + y * width];
+            *current = (*current).min(neighbour);
+        }
+    };
+
+    for y in 0..height {
+        for x in 0..width {
+            relax(&mut distances, x, y, -1, 0, ORTHOGONAL_STEP);
+            relax(&mut distances, x, y, 0, -1, ORTHOGONAL_STEP);
+            relax(&mut distances, x, y, -1, -1, DIAGONAL_STEP);
+            relax(&mut distances, x, y, 1, -1, DIAGONAL_STEP);
+        }
+    }
+
+    for y in (0..height).rev() {
+        for x in (0..width).rev() {
+            relax(&mut distances, x, y, 1, 0, ORTHOGONAL_STEP);
+            relax(&mut distances, x, y, 0, 1, ORTHOGONAL_STEP);
+            relax(&mut distances, x, y, 1, 1, DIAGONAL_STEP);
+            relax(&mut distances, x, y, -1, 1, DIAGONAL_STEP);
+        }
+    }
+
+    distances
+        .into_iter()
+        .map(|d| (d / ORTHOGONAL_STEP).min(u8::MAX as u16) as u8)
+        .collect()
+}
+
+/// Builds the texture used to shade water: the R channel is depth (in units of [`DEPTH_SCALE`]),
+/// and the G channel is distance to the coast in pixels (see [`coast_distances`]).
+pub fn to_texture(map: MapView) -> Texture {
+    let coast_distances = coast_distances(map);
+    let mut bytes = Vec::with_capacity(map.width * map.height * 2);
+
+    // The heights and water are read in place, in step with the distances
+    let pixels = map.heights().iter().zip(map.is_water().iter()).zip(coast_distances);
+    for ((height, is_water), coast_distance) in pixels {
+        let depth = if *is_water { (-(height.0 as i32)).max(0) as f32 } else { 0.0 };
+
+        bytes.write_u8((depth / DEPTH_SCALE).round().min(u8::MAX as f32) as u8).unwrap(); // R channel = depth
+        bytes.write_u8(coast_distance).unwrap(); // G channel = distance to coast
+    }
+
+    Texture {
+        data: bytes,
+        size: Extent3d::new(map.width as u32, map.height as u32, 1),
+        format: TextureFormat::Rg8Uint,
+        dimension: TextureDimension::D2,
+        sampler: SamplerDescriptor {
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            ..Default::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rome_map::Height;
+    use crate::map::HeightMap;
+
+    /// Distances to the coast of a map drawn as rows of `~` for water and `#` for land
+    fn distances(rows: &[&str]) -> Vec<Vec<u8>> {
+        let width = rows[0].len();
+        let is_water = rows.iter().flat_map(|row| row.chars().map(|c| c == '~')).collect();
+        let height_map = vec![Height(0); width * rows.len()];
+        let map = HeightMap::from_map(&rome_map::Map { width, height: rows.len(), height_map, is_water });
+
+        coast_distances(map.view()).chunks(width).map(<[u8]>::to_vec).collect()
+    }
+
+    #[test]
+    fn coast_is_where_land_meets_water() {
+        let rows = ["~~####", "~~####", "~~####"];
+
+        // Edges of the map aren't coast, so the distance keeps growing up to them
+        assert_eq!(distances(&rows), vec![vec![1, 0, 0, 1, 2, 3]; 3]);
+    }
+
+    #[test]
+    fn diagonal_distances_are_approximated() {
+        let rows = ["~###", "####", "####", "####"];
+
+        assert_eq!(distances(&rows), vec![
+            vec![0, 0, 1, 2],
+            vec![0, 1, 1, 2],
+            vec![1, 1, 2, 2],
+            vec![2, 2, 2, 3],
+        ]);
+    }
+}
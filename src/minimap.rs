@@ -0,0 +1,293 @@
+//! A minimap of the whole map in the corner of the screen, drawn from the heightmap on the CPU as a
+//! hillshade with the ownership overlay on top. The part of the map in view is marked on it, and
+//! clicking or dragging on it (with the select action) moves the camera there.
+use bevy::prelude::*;
+use bevy::render::camera::Camera;
+use bevy::render::texture::{Extent3d, TextureDimension, TextureFormat};
+use bevy::ui::FocusPolicy;
+use goshawk::RtsCamera;
+use crate::input::{Action, Actions};
+use crate::map::mipmap::generate_mipmaps;
+use crate::map::overlay::{Ownership, Realms};
+use crate::map::{HeightMap, XYZ_SCALE};
+use crate::{AppState, STATE_STAGE};
+
+/// The minimap is halved in size until it is at most this many pixels wide
+const MAX_WIDTH: usize = 256;
+/// How much the difference in height between neighbouring pixels (in metres) darkens or lightens
+/// the hillshade
+const HILLSHADE_STRENGTH: f32 = 1.0 / 600.0;
+/// Opacity of realm colours over the hillshade
+const OVERLAY_OPACITY: f32 = 0.5;
+/// Gap between the minimap and the corner of the screen, in pixels
+const MARGIN: f32 = 10.0;
+
+const WATER: [f32; 3] = [0.16, 0.3, 0.48];
+const LOWLAND: [f32; 3] = [0.42, 0.52, 0.3];
+const HIGHLAND: [f32; 3] = [0.55, 0.46, 0.33];
+const MOUNTAIN: [f32; 3] = [0.92, 0.92, 0.9];
+
+pub struct MinimapPlugin;
+
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.on_state_enter(STATE_STAGE, AppState::InGame, spawn_minimap.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, click_minimap.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, update_view_marker.system())
+            .on_state_update(STATE_STAGE, AppState::InGame, update_minimap.system());
+    }
+}
+
+/// The shaded terrain of the minimap, before the overlay is drawn on it
+pub struct Minimap {
+    width: usize,
+    height: usize,
+    /// How many heightmap texels wide and high each pixel of the minimap is
+    scale: usize,
+    /// Linear colours, in row-major order
+    terrain: Vec<[f32; 3]>,
+    texture: Option<Handle<Texture>>,
+}
+
+/// Marks the minimap's UI node
+struct MinimapPanel;
+
+/// Marks the rectangle showing the part of the map in view
+struct ViewMarker;
+
+impl Minimap {
+    /// Draws the minimap of a heightmap
+    pub fn new(map: &HeightMap) -> Minimap {
+        let map = map.view();
+        let mut levels = 1;
+        while map.width >> levels > MAX_WIDTH {
+            levels += 1;
+        }
+
+        let mipmap = generate_mipmaps(map, levels).pop().unwrap();
+        let scale = 1 << levels;
+        let height_at = |x: usize, y: usize| {
+            mipmap.get(x.min(mipmap.width - 1) as u32, y.min(mipmap.height - 1) as u32).0 as f32
+        };
+
+        let mut terrain = Vec::with_capacity(mipmap.width * mipmap.height);
+
+        for y in 0..mipmap.height {
+            for x in 0..mipmap.width {
+                let centre = (x * scale + scale / 2, y * scale + scale / 2);
+                if map.get(centre.0 as u32, centre.1 as u32).is_water {
+                    terrain.push(WATER);
+                    continue;
+                }
+
+                let height = height_at(x, y);
+                let colour = if height < 1000.0 {
+                    mix(LOWLAND, HIGHLAND, height / 1000.0)
+                } else {
+                    mix(HIGHLAND, MOUNTAIN, ((height - 1000.0) / 1500.0).min(1.0))
+                };
+
+                // Lit from the north west
+                let slope = height_at(x.saturating_sub(1), y.saturating_sub(1)) - height_at(x + 1, y + 1);
+                let shade = (1.0 - slope * HILLSHADE_STRENGTH).clamp(0.5, 1.3);
+                terrain.push([colour[0] * shade, colour[1] * shade, colour[2] * shade]);
+            }
+        }
+
+        Minimap { width: mipmap.width, height: mipmap.height, scale, terrain, texture: None }
+    }
+
+    /// Replaces the terrain with that of another minimap, e.g. when the heightmap is reloaded, keeping
+    /// the texture that it is drawn to
+    pub fn replace_terrain(&mut self, minimap: Minimap) {
+        *self = Minimap { texture: self.texture.take(), ..minimap };
+    }
+
+    /// The minimap with each realm's land tinted in its colour
+    fn to_texture(&self, ownership: &Ownership, realms: &Realms) -> Texture {
+        let mut data = Vec::with_capacity(self.width * self.height * 4);
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let mut colour = self.terrain[x + y * self.width];
+
+                let owner_x = (x * ownership.width / self.width).min(ownership.width - 1);
+                let owner_y = (y * ownership.height / self.height).min(ownership.height - 1);
+                if let Some(realm) = realms.0.get(ownership.owner(owner_x, owner_y) as usize) {
+                    let tint = realm.colour;
+                    let realm_colour = [tint.r_linear(), tint.g_linear(), tint.b_linear()];
+                    colour = mix(colour, realm_colour, tint.a() * OVERLAY_OPACITY);
+                }
+
+                let srgb = Color::rgb_linear(colour[0], colour[1], colour[2]);
+                data.extend_from_slice(&[to_u8(srgb.r()), to_u8(srgb.g()), to_u8(srgb.b()), u8::MAX]);
+            }
+        }
+
+        Texture::new(
+            Extent3d::new(self.width as u32, self.height as u32, 1),
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+        )
+    }
+
+    /// Minimap pixel, from the top left, of a world space position
+    fn to_pixel(&self, world: Vec2) -> Vec2 {
+        world / XYZ_SCALE / self.scale as f32
+    }
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    let t = t.clamp(0.0, 1.0);
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * u8::MAX as f32).round() as u8
+}
+
+fn spawn_minimap(
+    commands: &mut Commands,
+    mut minimap: ResMut<Minimap>,
+    ownership: Res<Ownership>,
+    realms: Res<Realms>,
+    mut textures: ResMut<Assets<Texture>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let texture = textures.add(minimap.to_texture(&ownership, &realms));
+    minimap.texture = Some(texture.clone());
+
+    commands
+        .spawn(ImageBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    right: Val::Px(MARGIN),
+                    bottom: Val::Px(MARGIN),
+                    ..Default::default()
+                },
+                size: Size::new(Val::Px(minimap.width as f32), Val::Px(minimap.height as f32)),
+                ..Default::default()
+            },
+            material: materials.add(texture.into()),
+            ..Default::default()
+        })
+        .with(MinimapPanel)
+        .with(Interaction::default())
+        .with(FocusPolicy::Block)
+        .with_children(|panel| {
+            panel
+                .spawn(NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        ..Default::default()
+                    },
+                    material: materials.add(Color::rgba(1.0, 1.0, 1.0, 0.3).into()),
+                    ..Default::default()
+                })
+                .with(ViewMarker);
+        });
+}
+
+/// Moves the camera to where the minimap is clicked with the select action, following the cursor
+/// while it is held
+fn click_minimap(
+    windows: Res<Windows>,
+    actions: Res<Actions>,
+    minimap: Res<Minimap>,
+    mut dragging: Local<bool>,
+    panels: Query<(&Interaction, &Node, &GlobalTransform), With<MinimapPanel>>,
+    mut cameras: Query<&mut RtsCamera>,
+) {
+    let cursor = match windows.get_primary().and_then(|window| window.cursor_position()) {
+        Some(cursor) => cursor,
+        None => return,
+    };
+
+    for (interaction, node, transform) in panels.iter() {
+        if actions.just_pressed(Action::Select) {
+            *dragging = *interaction != Interaction::None;
+        }
+        if !*dragging || !actions.pressed(Action::Select) {
+            *dragging = false;
+            continue;
+        }
+
+        // UI positions are from the bottom left, while the map's rows go from the top down
+        let top_left = Vec2::new(transform.translation.x - node.size.x / 2.0, transform.translation.y + node.size.y / 2.0);
+        let fraction = Vec2::new(cursor.x - top_left.x, top_left.y - cursor.y) / node.size;
+        let fraction = fraction.max(Vec2::zero()).min(Vec2::one());
+        let pixel = fraction * Vec2::new(minimap.width as f32, minimap.height as f32);
+        let world = pixel * minimap.scale as f32 * XYZ_SCALE;
+
+        for mut camera in cameras.iter_mut() {
+            camera.looking_at.x = world.x;
+            camera.looking_at.z = world.y;
+        }
+    }
+}
+
+/// Marks the bounds of where the corners of the screen meet the ground
+fn update_view_marker(
+    minimap: Res<Minimap>,
+    cameras: Query<(&Camera, &GlobalTransform, &RtsCamera)>,
+    mut markers: Query<&mut Style, With<ViewMarker>>,
+) {
+    let (camera, transform, rts_camera) = match cameras.iter().next() {
+        Some(camera) => camera,
+        None => return,
+    };
+
+    let eye = transform.translation;
+    let inverse_view_proj = (camera.projection_matrix * transform.compute_matrix().inverse()).inverse();
+    let ground = rts_camera.looking_at.y;
+    let corners = [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)];
+
+    let (mut min, mut max) = (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN));
+    for &(x, y) in corners.iter() {
+        let far = inverse_view_proj * Vec4::new(x, y, 1.0, 1.0);
+        let far = far.truncate() / far.w;
+        let direction = far - eye;
+
+        // Corners above the horizon never meet the ground, so they are cut off at the far plane
+        let point = if direction.y < 0.0 {
+            eye + direction * ((ground - eye.y) / direction.y).min(1.0)
+        } else {
+            far
+        };
+
+        let pixel = minimap.to_pixel(Vec2::new(point.x, point.z));
+        min = min.min(pixel);
+        max = max.max(pixel);
+    }
+
+    let size = Vec2::new(minimap.width as f32, minimap.height as f32);
+    let (min, max) = (min.max(Vec2::zero()).min(size), max.max(Vec2::zero()).min(size));
+
+    for mut style in markers.iter_mut() {
+        style.position = Rect {
+            left: Val::Px(min.x),
+            bottom: Val::Px(size.y - max.y),
+            ..Default::default()
+        };
+        style.size = Size::new(Val::Px(max.x - min.x), Val::Px(max.y - min.y));
+    }
+}
+
+/// Whether who owns the land, the realms or the terrain under them has changed
+type MinimapChanged<'a> =
+    Or<(Option<ChangedRes<'a, Ownership>>, Option<ChangedRes<'a, Realms>>, Option<ChangedRes<'a, Minimap>>)>;
+
+/// Redraws the minimap when anything drawn on it changes
+fn update_minimap(
+    _changed: MinimapChanged,
+    ownership: Res<Ownership>,
+    realms: Res<Realms>,
+    minimap: Res<Minimap>,
+    mut textures: ResMut<Assets<Texture>>,
+) {
+    if let Some(texture) = minimap.texture.as_ref().and_then(|handle| textures.get_mut(handle)) {
+        *texture = minimap.to_texture(&ownership, &realms);
+    }
+}
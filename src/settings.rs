@@ -0,0 +1,422 @@
+//! User settings for the window, graphics, camera and input bindings, kept in `settings.ron` in the
+//! user's config directory (e.g. `~/.config/rome/settings.ron` on Linux). Settings missing from the
+//! file take their defaults, and a file with the defaults is written if there isn't one yet. The
+//! file is watched while the game runs, and changes are applied live where Bevy allows it.
+use bevy::prelude::*;
+use bevy::window::WindowMode;
+use goshawk::{PanSettings, TurnSettings, ZoomSettings};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use crate::input::InputBindings;
+
+/// How often the settings file is checked for changes, in seconds
+const POLL_INTERVAL: f32 = 1.0;
+const MIN_RESOLUTION: (f32, f32) = (640.0, 480.0);
+
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(SettingsWatcher::default())
+            .add_system(watch_settings.system())
+            .add_system(apply_window_settings.system())
+            .add_system(apply_camera_settings.system());
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub window: WindowSettings,
+    pub graphics: GraphicsSettings,
+    pub camera: CameraSettings,
+    pub input: InputBindings,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct WindowSettings {
+    pub mode: WindowModeSetting,
+    /// Size of the window when it is windowed, in pixels
+    pub width: f32,
+    pub height: f32,
+    pub resizable: bool,
+    pub vsync: bool,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum WindowModeSetting {
+    Windowed,
+    BorderlessFullscreen,
+    /// Exclusive fullscreen, at the monitor's resolution
+    Fullscreen,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct GraphicsSettings {
+    /// Multisample anti-aliasing samples: 1 (off), 2, 4 or 8. This only takes effect on restart.
+    pub msaa_samples: u32,
+}
+
+/// The settings of the RTS camera's movement. See goshawk's [`ZoomSettings`], [`PanSettings`] and
+/// [`TurnSettings`] for what each means.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct CameraSettings {
+    pub zoom_angle_range: (f32, f32),
+    pub zoom_angle_change_zone: (f32, f32),
+    pub zoom_distance_range: (f32, f32),
+    pub zoom_scroll_accel: f32,
+    /// Idle deceleration works against zooming with the keyboard, as in goshawk, so this has to be
+    /// more than `zoom_idle_deceleration` for the keys to zoom at all
+    pub zoom_keyboard_accel: f32,
+    pub zoom_max_velocity: f32,
+    pub zoom_idle_deceleration: f32,
+    pub pan_mouse_accel: f32,
+    pub pan_keyboard_accel: f32,
+    pub pan_idle_deceleration: f32,
+    pub pan_max_speed: f32,
+    pub pan_speed_zoom_factor_range: (f32, f32),
+    pub turn_mouse_margin: f32,
+    pub turn_mouse_accel: f32,
+    pub turn_keyboard_accel: f32,
+}
+
+impl Default for WindowSettings {
+    fn default() -> Self {
+        WindowSettings {
+            mode: WindowModeSetting::BorderlessFullscreen,
+            width: 1280.0,
+            height: 720.0,
+            resizable: false,
+            vsync: false,
+        }
+    }
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        GraphicsSettings { msaa_samples: 8 }
+    }
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        CameraSettings {
+            zoom_angle_range: (0.9103, 1.237539),
+            zoom_angle_change_zone: (80.0, 130.0),
+            // Far enough out to see the whole empire on the paper map
+            zoom_distance_range: (75.0, 1200.0),
+            zoom_scroll_accel: 40.0,
+            zoom_keyboard_accel: 400.0,
+            zoom_max_velocity: 250.0,
+            zoom_idle_deceleration: 200.0,
+            pan_mouse_accel: 200.0,
+            pan_keyboard_accel: 160.0,
+            pan_idle_deceleration: 200.0,
+            pan_max_speed: 10.0,
+            pan_speed_zoom_factor_range: (1.0, 6.0),
+            turn_mouse_margin: 0.0,
+            turn_mouse_accel: 0.0,
+            turn_keyboard_accel: 0.0,
+        }
+    }
+}
+
+impl WindowModeSetting {
+    fn to_window_mode(self) -> WindowMode {
+        match self {
+            WindowModeSetting::Windowed => WindowMode::Windowed,
+            WindowModeSetting::BorderlessFullscreen => WindowMode::BorderlessFullscreen,
+            WindowModeSetting::Fullscreen => WindowMode::Fullscreen { use_size: false },
+        }
+    }
+}
+
+impl WindowSettings {
+    pub fn descriptor(&self) -> WindowDescriptor {
+        WindowDescriptor {
+            title: "Rome".to_string(),
+            width: self.width,
+            height: self.height,
+            vsync: self.vsync,
+            resizable: self.resizable,
+            mode: self.mode.to_window_mode(),
+            ..Default::default()
+        }
+    }
+}
+
+impl CameraSettings {
+    pub fn apply(&self, zoom: &mut ZoomSettings, pan: &mut PanSettings, turn: &mut TurnSettings) {
+        let range = |(start, end): (f32, f32)| start..=end;
+
+        zoom.angle_range = range(self.zoom_angle_range);
+        zoom.angle_change_zone = range(self.zoom_angle_change_zone);
+        zoom.distance_range = range(self.zoom_distance_range);
+        zoom.scroll_accel = self.zoom_scroll_accel;
+        zoom.keyboard_accel = self.zoom_keyboard_accel;
+        zoom.max_velocity = self.zoom_max_velocity;
+        zoom.idle_deceleration = self.zoom_idle_deceleration;
+
+        pan.mouse_accel = self.pan_mouse_accel;
+        pan.keyboard_accel = self.pan_keyboard_accel;
+        pan.idle_deceleration = self.pan_idle_deceleration;
+        pan.max_speed = self.pan_max_speed;
+        pan.pan_speed_zoom_factor_range = range(self.pan_speed_zoom_factor_range);
+
+        turn.mouse_turn_margin = self.turn_mouse_margin;
+        turn.mouse_accel = self.turn_mouse_accel;
+        turn.keyboard_accel = self.turn_keyboard_accel;
+    }
+}
+
+impl Settings {
+    /// The player's config directory, which the settings file and other files saved by the game
+    /// are kept in
+    pub fn config_dir() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rome"))
+    }
+
+    pub fn path() -> Option<PathBuf> {
+        Settings::config_dir().map(|dir| dir.join("settings.ron"))
+    }
+
+    /// Settings from the settings file. If there is no file, one is written with the default
+    /// settings, and if it can't be read, the defaults are used.
+    pub fn load() -> Settings {
+        let path = match Settings::path() {
+            Some(path) => path,
+            None => {
+                eprintln!("Couldn't find the config directory, so the default settings are used");
+                return Settings::default();
+            }
+        };
+
+        if !path.exists() {
+            let settings = Settings::default();
+            if let Err(err) = settings.save() {
+                eprintln!("Couldn't write the default settings to {}: {}", path.display(), err);
+            }
+            return settings;
+        }
+
+        match Settings::read(&path) {
+            Ok(settings) => settings,
+            Err(err) => {
+                eprintln!("Couldn't load settings from {}, so the defaults are used: {}", path.display(), err);
+                Settings::default()
+            }
+        }
+    }
+
+    fn read(path: &std::path::Path) -> Result<Settings, anyhow::Error> {
+        let mut settings: Settings = ron::de::from_str(&std::fs::read_to_string(path)?)?;
+
+        for problem in settings.validate() {
+            eprintln!("Invalid setting in {}: {}", path.display(), problem);
+        }
+
+        Ok(settings)
+    }
+
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        let path = Settings::path().ok_or_else(|| anyhow::anyhow!("no config directory"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let pretty = ron::ser::PrettyConfig::new();
+        std::fs::write(path, ron::ser::to_string_pretty(self, pretty)?)?;
+        Ok(())
+    }
+
+    /// Fixes settings that are out of range, returning what was wrong with them
+    pub fn validate(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let samples = &mut self.graphics.msaa_samples;
+        if ![1, 2, 4, 8].contains(samples) {
+            problems.push(format!("msaa_samples must be 1, 2, 4 or 8, not {}", samples));
+            *samples = match *samples {
+                0 => 1,
+                samples => 1 << (31 - samples.leading_zeros()).min(3),
+            };
+        }
+
+        let window = &mut self.window;
+        if window.width < MIN_RESOLUTION.0 || window.height < MIN_RESOLUTION.1 {
+            problems.push(format!(
+                "the window must be at least {}x{}, not {}x{}",
+                MIN_RESOLUTION.0, MIN_RESOLUTION.1, window.width, window.height,
+            ));
+            window.width = window.width.max(MIN_RESOLUTION.0);
+            window.height = window.height.max(MIN_RESOLUTION.1);
+        }
+
+        let camera = &mut self.camera;
+        let defaults = CameraSettings::default();
+        let mut ranges = [
+            ("zoom_angle_range", &mut camera.zoom_angle_range, defaults.zoom_angle_range),
+            ("zoom_angle_change_zone", &mut camera.zoom_angle_change_zone, defaults.zoom_angle_change_zone),
+            ("zoom_distance_range", &mut camera.zoom_distance_range, defaults.zoom_distance_range),
+            (
+                "pan_speed_zoom_factor_range",
+                &mut camera.pan_speed_zoom_factor_range,
+                defaults.pan_speed_zoom_factor_range,
+            ),
+        ];
+        for (name, range, default) in ranges.iter_mut() {
+            if !range.0.is_finite() || !range.1.is_finite() {
+                problems.push(format!("{} must be finite, not {:?}", name, range));
+                **range = *default;
+            } else if range.0 > range.1 {
+                problems.push(format!("{} must start before it ends, not {:?}", name, range));
+                **range = (range.1, range.0);
+            }
+        }
+
+        let mut speeds = [
+            ("zoom_scroll_accel", &mut camera.zoom_scroll_accel),
+            ("zoom_keyboard_accel", &mut camera.zoom_keyboard_accel),
+            ("zoom_max_velocity", &mut camera.zoom_max_velocity),
+            ("zoom_idle_deceleration", &mut camera.zoom_idle_deceleration),
+            ("pan_mouse_accel", &mut camera.pan_mouse_accel),
+            ("pan_keyboard_accel", &mut camera.pan_keyboard_accel),
+            ("pan_idle_deceleration", &mut camera.pan_idle_deceleration),
+            ("pan_max_speed", &mut camera.pan_max_speed),
+            ("turn_mouse_margin", &mut camera.turn_mouse_margin),
+            ("turn_mouse_accel", &mut camera.turn_mouse_accel),
+            ("turn_keyboard_accel", &mut camera.turn_keyboard_accel),
+        ];
+        for (name, speed) in speeds.iter_mut() {
+            if speed.is_nan() || **speed < 0.0 {
+                problems.push(format!("{} can't be negative, but is {}", name, speed));
+                **speed = 0.0;
+            }
+        }
+
+        problems.extend(self.input.validate());
+        problems
+    }
+}
+
+struct SettingsWatcher {
+    timer: Timer,
+    modified: Option<SystemTime>,
+}
+
+impl Default for SettingsWatcher {
+    fn default() -> Self {
+        SettingsWatcher { timer: Timer::from_seconds(POLL_INTERVAL, true), modified: modified() }
+    }
+}
+
+fn modified() -> Option<SystemTime> {
+    let path = Settings::path()?;
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Reloads the settings when the settings file changes
+fn watch_settings(time: Res<Time>, mut watcher: ResMut<SettingsWatcher>, mut settings: ResMut<Settings>) {
+    if !watcher.timer.tick(time.delta_seconds()).just_finished() {
+        return;
+    }
+
+    let modified = modified();
+    if modified == watcher.modified {
+        return;
+    }
+    watcher.modified = modified;
+
+    let path = match Settings::path() {
+        Some(path) => path,
+        None => return,
+    };
+
+    match Settings::read(&path) {
+        Ok(new) if new != *settings => {
+            if new.graphics != settings.graphics {
+                eprintln!("Graphics settings take effect when the game is restarted");
+            }
+            *settings = new;
+        }
+        Ok(_) => {}
+        Err(err) => eprintln!("Couldn't reload settings from {}: {}", path.display(), err),
+    }
+}
+
+fn apply_window_settings(settings: ChangedRes<Settings>, mut windows: ResMut<Windows>) {
+    let window = match windows.get_primary_mut() {
+        Some(window) => window,
+        None => return,
+    };
+    let new = &settings.window;
+
+    if std::mem::discriminant(&window.mode()) != std::mem::discriminant(&new.mode.to_window_mode()) {
+        window.set_mode(new.mode.to_window_mode());
+    }
+    if new.mode == WindowModeSetting::Windowed && (window.width() != new.width || window.height() != new.height) {
+        window.set_resolution(new.width, new.height);
+    }
+    if window.vsync() != new.vsync {
+        window.set_vsync(new.vsync);
+    }
+    if window.resizable() != new.resizable {
+        window.set_resizable(new.resizable);
+    }
+}
+
+fn apply_camera_settings(
+    settings: ChangedRes<Settings>,
+    mut cameras: Query<(&mut ZoomSettings, &mut PanSettings, &mut TurnSettings)>,
+) {
+    for (mut zoom, mut pan, mut turn) in cameras.iter_mut() {
+        settings.camera.apply(&mut zoom, &mut pan, &mut turn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let settings: Settings = ron::de::from_str("(window: (mode: Windowed), graphics: (msaa_samples: 2))").unwrap();
+
+        assert_eq!(settings.window.mode, WindowModeSetting::Windowed);
+        assert_eq!(settings.window.width, WindowSettings::default().width);
+        assert_eq!(settings.graphics.msaa_samples, 2);
+        assert_eq!(settings.camera, CameraSettings::default());
+        assert_eq!(settings.input, InputBindings::default());
+    }
+
+    #[test]
+    fn settings_round_trip_through_ron() {
+        let settings = Settings::default();
+        let ron = ron::ser::to_string_pretty(&settings, ron::ser::PrettyConfig::new()).unwrap();
+
+        assert_eq!(ron::de::from_str::<Settings>(&ron).unwrap(), settings);
+    }
+
+    #[test]
+    fn invalid_settings_are_fixed() {
+        let mut settings = Settings::default();
+        assert!(settings.validate().is_empty());
+
+        settings.graphics.msaa_samples = 6;
+        settings.window.width = 100.0;
+        settings.camera.zoom_distance_range = (500.0, 50.0);
+        settings.camera.pan_max_speed = -1.0;
+        settings.camera.zoom_angle_range = (f32::NAN, 1.0);
+
+        assert_eq!(settings.validate().len(), 5);
+        assert_eq!(settings.graphics.msaa_samples, 4);
+        assert_eq!(settings.window.width, MIN_RESOLUTION.0);
+        assert_eq!(settings.camera.zoom_distance_range, (50.0, 500.0));
+        assert_eq!(settings.camera.pan_max_speed, 0.0);
+        assert_eq!(settings.camera.zoom_angle_range, CameraSettings::default().zoom_angle_range);
+    }
+}
@@ -0,0 +1,170 @@
+//! Settlements of the world - cities, towns and villages - loaded from a gazetteer data file
+//! (`assets/map/world.settlements`) and placed on the map.
+use bevy::asset::{AssetLoader, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::prelude::shape::Cube;
+use bevy::reflect::TypeUuid;
+use bevy::utils::BoxedFuture;
+use serde::Deserialize;
+use crate::loading::progress::LoadingProgress;
+use crate::map::LatLong;
+use crate::map::terrain::SnapToTerrain;
+use crate::{AppState, RomeAssets, STATE_STAGE};
+
+pub struct SettlementsPlugin;
+
+impl Plugin for SettlementsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_asset::<Gazetteer>()
+            .init_asset_loader::<GazetteerLoader>()
+            .on_state_enter(STATE_STAGE, AppState::InGame, spawn_settlements.system());
+    }
+}
+
+/// Every settlement in the world, loaded from a `.settlements` file
+#[derive(Deserialize, TypeUuid, Debug)]
+#[uuid = "4e2f9a61-8c0d-4b7e-a3d5-6f1b2c9e0a47"]
+pub struct Gazetteer {
+    pub settlements: Vec<Settlement>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Settlement {
+    /// Modern English name
+    pub name: String,
+    /// Name in Latin, as shown in game
+    pub latin_name: String,
+    pub location: LatLong,
+    pub population: u32,
+    pub kind: SettlementKind,
+}
+
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SettlementKind {
+    Village,
+    Town,
+    City,
+    Capital,
+}
+
+impl SettlementKind {
+    /// Size of the settlement's marker, in world units
+    fn marker_size(self) -> f32 {
+        match self {
+            SettlementKind::Village => 1.0,
+            SettlementKind::Town => 2.0,
+            SettlementKind::City => 3.0,
+            SettlementKind::Capital => 5.0,
+        }
+    }
+}
+
+impl Gazetteer {
+    /// Settlement with the given English or Latin name, ignoring case
+    pub fn by_name(&self, name: &str) -> Option<&Settlement> {
+        self.settlements
+            .iter()
+            .find(|s| s.name.eq_ignore_ascii_case(name) || s.latin_name.eq_ignore_ascii_case(name))
+    }
+
+    /// Settlement closest to the given location, measured on the map
+    pub fn nearest(&self, location: LatLong) -> Option<&Settlement> {
+        let position = location.to_tile_coord().to_world_space();
+
+        self.settlements.iter().min_by(|a, b| {
+            let a = a.location.to_tile_coord().to_world_space().distance_squared(position);
+            let b = b.location.to_tile_coord().to_world_space().distance_squared(position);
+            a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+        })
+    }
+}
+
+fn spawn_settlements(
+    commands: &mut Commands,
+    assets: Res<RomeAssets>,
+    gazetteers: Res<Assets<Gazetteer>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let gazetteer = match gazetteers.get(&assets.gazetteer) {
+        Some(gazetteer) => gazetteer,
+        None => return,
+    };
+
+    // Every marker is the same cube, scaled to the settlement's size
+    let mesh = meshes.add(Mesh::from(Cube::new(1.0)));
+    let material = materials.add(StandardMaterial {
+        albedo: Color::BEIGE,
+        ..Default::default()
+    });
+
+    for settlement in &gazetteer.settlements {
+        let size = settlement.kind.marker_size();
+
+        commands
+            .spawn(PbrBundle {
+                mesh: mesh.clone(),
+                material: material.clone(),
+                transform: Transform {
+                    translation: settlement.location.to_tile_coord().to_world_space_0y(),
+                    scale: Vec3::splat(size),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .with(settlement.clone())
+            // Sat on the ground rather than half buried
+            .with(SnapToTerrain { offset: size / 2.0 });
+    }
+}
+
+pub struct GazetteerLoader {
+    progress: LoadingProgress,
+}
+
+impl FromResources for GazetteerLoader {
+    fn from_resources(resources: &Resources) -> Self {
+        let progress = resources.get::<LoadingProgress>().expect("LoadRomeAssets must be added first");
+        GazetteerLoader { progress: (*progress).clone() }
+    }
+}
+
+impl AssetLoader for GazetteerLoader {
+    fn load<'a>(&'a self, bytes: &'a [u8], ctx: &'a mut LoadContext) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let gazetteer: Gazetteer = self.progress.report(ctx, ron::de::from_bytes(bytes).map_err(anyhow::Error::from))?;
+            ctx.set_default_asset(LoadedAsset::new(gazetteer));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["settlements"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gazetteer() -> Gazetteer {
+        ron::de::from_str(include_str!("../assets/map/world.settlements")).unwrap()
+    }
+
+    #[test]
+    fn lookup_by_name() {
+        let gazetteer = gazetteer();
+
+        assert_eq!(gazetteer.by_name("rome").unwrap().latin_name, "Roma");
+        assert_eq!(gazetteer.by_name("Roma").unwrap().name, "Rome");
+        assert!(gazetteer.by_name("Atlantis").is_none());
+    }
+
+    #[test]
+    fn lookup_by_nearest() {
+        let gazetteer = gazetteer();
+        let ostia = LatLong { latitude: 41.75, longitude: 12.29 };
+
+        assert_eq!(gazetteer.nearest(ostia).unwrap().name, "Rome");
+    }
+}